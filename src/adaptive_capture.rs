@@ -0,0 +1,128 @@
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_video as gst_video;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+// While the screen is idle one unchanged frame still goes out this often,
+// which is also the idle frame rate
+const IDLE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Keyframes forced while idle, so viewers joining a static screen get a picture
+const IDLE_KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Makes the desktop capture of a pipeline content-adaptive: damage based
+/// grabbing, unchanged frames dropped and periodic keyframes while idle.
+/// Call before the pipeline starts.
+pub fn enable(pipeline: &gst::Pipeline) {
+    let Some(source) = pipeline.iterate_recurse().into_iter().flatten().find(|element| {
+        element
+            .factory()
+            .is_some_and(|factory| matches!(factory.name().as_str(), "ximagesrc" | "pipewiresrc"))
+    }) else {
+        debug!("No desktop capture source, adaptive frame rate not applied");
+        return;
+    };
+
+    match source.factory().map(|factory| factory.name()).as_deref() {
+        // XDamage: only the damaged regions are grabbed from the X server
+        Some("ximagesrc") => source.set_property("use-damage", true),
+        // The compositor only sends damaged frames; the keepalive resends the
+        // last one so the idle refresh below still has frames to work with
+        Some(_) => source.set_property("keepalive-time", IDLE_REFRESH_INTERVAL.as_millis() as i32),
+        None => {}
+    }
+
+    let Some(src_pad) = source.static_pad("src") else {
+        return;
+    };
+    let pipeline_weak = pipeline.downgrade();
+    let filter = Mutex::new(IdleFrameFilter::new());
+    src_pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(buffer) = info.buffer() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        match filter.lock().unwrap().check(pad, buffer) {
+            FrameAction::Drop => gst::PadProbeReturn::Drop,
+            FrameAction::Forward => gst::PadProbeReturn::Ok,
+            FrameAction::ForwardKeyframe => {
+                if let Some(sink) = pipeline_weak.upgrade().and_then(|pipeline| pipeline.by_name("videosink")) {
+                    // Upstream events sent to a sink travel up to the encoder
+                    sink.send_event(gst_video::UpstreamForceKeyUnitEvent::builder().all_headers(true).build());
+                }
+                gst::PadProbeReturn::Ok
+            }
+        }
+    });
+    info!("Adaptive frame rate enabled on {}", source.name());
+}
+
+enum FrameAction {
+    Drop,
+    Forward,
+    ForwardKeyframe,
+}
+
+struct IdleFrameFilter {
+    last_hash: Option<u64>,
+    last_buffer: Instant,
+    last_forwarded: Instant,
+    last_keyframe: Instant,
+}
+
+impl IdleFrameFilter {
+    fn new() -> Self {
+        Self {
+            last_hash: None,
+            last_buffer: Instant::now(),
+            last_forwarded: Instant::now(),
+            last_keyframe: Instant::now(),
+        }
+    }
+
+    fn check(&mut self, pad: &gst::Pad, buffer: &gst::BufferRef) -> FrameAction {
+        let now = Instant::now();
+        let since_previous = now.duration_since(self.last_buffer);
+        self.last_buffer = now;
+
+        // Mapping GPU buffers would read them back; PipeWire only sends those on
+        // damage, so a buffer after a long gap is the keepalive resending the last one
+        let on_gpu = pad
+            .current_caps()
+            .and_then(|caps| caps.features(0).map(|features| features.contains("memory:DMABuf")))
+            .unwrap_or(false);
+        let changed = if on_gpu {
+            since_previous < IDLE_REFRESH_INTERVAL / 2
+        } else {
+            let hash = buffer.map_readable().ok().map(|map| frame_hash(map.as_slice()));
+            hash.is_none() || std::mem::replace(&mut self.last_hash, hash) != hash
+        };
+
+        if changed {
+            self.last_forwarded = now;
+            return FrameAction::Forward;
+        }
+        if now.duration_since(self.last_forwarded) < IDLE_REFRESH_INTERVAL {
+            return FrameAction::Drop;
+        }
+        self.last_forwarded = now;
+        if now.duration_since(self.last_keyframe) >= IDLE_KEYFRAME_INTERVAL {
+            self.last_keyframe = now;
+            return FrameAction::ForwardKeyframe;
+        }
+        FrameAction::Forward
+    }
+}
+
+// FNV-1a over 64-bit words, fast enough to run on every captured frame
+fn frame_hash(data: &[u8]) -> u64 {
+    const PRIME: u64 = 0x100000001b3;
+    let mut chunks = data.chunks_exact(8);
+    let mut hash = chunks.by_ref().fold(0xcbf29ce484222325u64, |hash, word| {
+        (hash ^ u64::from_ne_bytes(word.try_into().unwrap())).wrapping_mul(PRIME)
+    });
+    for byte in chunks.remainder() {
+        hash = (hash ^ *byte as u64).wrapping_mul(PRIME);
+    }
+    hash
+}
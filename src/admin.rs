@@ -0,0 +1,176 @@
+use axum::{
+    Json, Router,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, header, request::Parts},
+    routing::{delete, get, post},
+};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::Serialize;
+use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    audio_detection::AudioDevice,
+    config::{AppState, MonitorInfo, PipelineConfig, SignalMessage},
+    encoder_probe::EncoderCapability,
+    media::{HardwareInfo, detect_hardware_capabilities},
+    server::{detect_audio_devices, detect_monitors},
+    whip::ingest_monitors,
+};
+
+type ApiError = (StatusCode, String);
+
+// Admin API, merged into the main router by run_server. Every handler takes
+// an `AdminAuth`, so requests need `Authorization: Bearer <token>`.
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/admin/peers", get(list_peers))
+        .route("/api/admin/peers/{id}", delete(kick_peer))
+        .route("/api/admin/hardware", get(hardware))
+        .route("/api/admin/monitors", get(monitors))
+        .route("/api/admin/audio-devices", get(audio_devices))
+        .route("/api/admin/redetect", post(redetect))
+}
+
+/// Proof that the request carries the configured admin token
+pub struct AdminAuth;
+
+impl FromRequestParts<Arc<AppState>> for AdminAuth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let Some(token) = &state.settings.admin_token else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Admin API disabled, set DESKTOP_STREAMER_ADMIN_TOKEN".to_string(),
+            ));
+        };
+        let provided = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if constant_time_eq(provided.trim().as_bytes(), token.0.as_bytes()) => Ok(AdminAuth),
+            _ => Err((StatusCode::UNAUTHORIZED, "Invalid or missing admin token".to_string())),
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct PeerSummary {
+    id: String,
+    config: PipelineConfig,
+    pipeline_variant: String,
+    connection_state: String,
+    remote_addr: Option<SocketAddr>,
+    uptime_secs: u64,
+    recording: bool,
+}
+
+async fn list_peers(_: AdminAuth, State(state): State<Arc<AppState>>) -> Json<Vec<PeerSummary>> {
+    let peers: Vec<_> = state.peers.lock().await.iter().map(|(id, peer)| (*id, peer.clone())).collect();
+    let mut summaries = Vec::with_capacity(peers.len());
+    for (id, peer) in peers {
+        summaries.push(PeerSummary {
+            id: id.to_string(),
+            config: peer.config.clone(),
+            pipeline_variant: peer.pipeline_variant.clone(),
+            connection_state: peer.peer_connection.connection_state().to_string(),
+            remote_addr: peer.remote_addr,
+            uptime_secs: peer.connected_at.elapsed().as_secs(),
+            recording: peer.recording.lock().await.is_some(),
+        });
+    }
+    summaries.sort_by_key(|summary| std::cmp::Reverse(summary.uptime_secs));
+    Json(summaries)
+}
+
+async fn kick_peer(
+    _: AdminAuth,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let peer_id = Uuid::parse_str(&id).map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid peer id '{}'", id)))?;
+    let peer = state
+        .peers
+        .lock()
+        .await
+        .remove(&peer_id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Peer {} not found", peer_id)))?;
+
+    info!(%peer_id, "Peer disconnected by admin");
+    let _ = peer.signal_sender.send(SignalMessage::Error {
+        message: "Transmissão encerrada pelo administrador".to_string(),
+    });
+    peer.close(&state).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn hardware(_: AdminAuth, State(state): State<Arc<AppState>>) -> Json<HardwareInfo> {
+    Json((*state.current_hw_info()).clone())
+}
+
+async fn monitors(_: AdminAuth, State(state): State<Arc<AppState>>) -> Json<Vec<MonitorInfo>> {
+    Json(all_monitors(&state).await)
+}
+
+async fn audio_devices(_: AdminAuth) -> Json<Vec<AudioDevice>> {
+    Json(detect_audio_devices().await)
+}
+
+#[derive(Serialize)]
+struct Detection {
+    hardware: HardwareInfo,
+    monitors: Vec<MonitorInfo>,
+    audio_devices: Vec<AudioDevice>,
+}
+
+// Monitors and audio devices are listed live; hardware detection is cached
+// in AppState and only refreshed here (e.g. after installing a driver),
+// re-running the encoder probe
+async fn redetect(_: AdminAuth, State(state): State<Arc<AppState>>) -> Json<Detection> {
+    let in_use = encoders_in_use(&state).await;
+    let hardware = tokio::task::spawn_blocking(move || detect_hardware_capabilities(true, &in_use))
+        .await
+        .unwrap_or_else(|_| (*state.current_hw_info()).clone());
+    info!("Hardware re-detected: {:?}", hardware);
+    *state.hw_info.write().unwrap() = Arc::new(hardware.clone());
+
+    Json(Detection {
+        hardware,
+        monitors: all_monitors(&state).await,
+        audio_devices: detect_audio_devices().await,
+    })
+}
+
+// Encoders running in a viewer or broadcast pipeline: probing them again could
+// fail just because the device is busy
+async fn encoders_in_use(state: &AppState) -> Vec<EncoderCapability> {
+    let mut pipelines: Vec<gst::Pipeline> = state.peers.lock().await.values().map(|peer| peer.pipeline.clone()).collect();
+    pipelines.extend(state.broadcasts.lock().await.values().map(|broadcast| broadcast.pipeline.clone()));
+    let running: HashSet<String> = pipelines
+        .iter()
+        .flat_map(|pipeline| pipeline.iterate_recurse().into_iter().flatten())
+        .filter_map(|element| element.factory().map(|factory| factory.name().to_string()))
+        .collect();
+    state
+        .current_hw_info()
+        .encoders
+        .iter()
+        .filter(|encoder| running.contains(&encoder.element))
+        .cloned()
+        .collect()
+}
+
+async fn all_monitors(state: &AppState) -> Vec<MonitorInfo> {
+    let mut monitors = detect_monitors(&state.session_type, state.has_portal).await;
+    monitors.extend(ingest_monitors(state).await);
+    monitors
+}
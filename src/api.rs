@@ -0,0 +1,230 @@
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{AppState, PeerState, SignalMessage},
+    media::VideoSource,
+    push::{PushInfo, PushTarget, start_push},
+    recording::{RecordingInfo, RecordingOptions, start_peer_recording, stop_peer_recording},
+    snapshot::{SnapshotFormat, capture_snapshot},
+    stats::PeerStats,
+    virtual_camera::VirtualCameraStatus,
+};
+
+type ApiError = (StatusCode, String);
+
+// HTTP control API, merged into the main router by run_server
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/api/peers/{id}/recording",
+            get(recording_status).post(start_recording).delete(stop_recording),
+        )
+        .route("/api/peers/{id}/stats", get(peer_stats))
+        .route("/api/snapshot", get(snapshot))
+        .route("/api/push", get(list_pushes).post(create_push))
+        .route("/api/push/{id}", get(push_status).delete(delete_push))
+        .route(
+            "/api/virtual-camera",
+            get(virtual_camera_status).post(start_virtual_camera).delete(stop_virtual_camera),
+        )
+}
+
+async fn find_peer(state: &AppState, id: &str) -> Result<Arc<PeerState>, ApiError> {
+    let peer_id = Uuid::parse_str(id).map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid peer id '{}'", id)))?;
+    state
+        .peers
+        .lock()
+        .await
+        .get(&peer_id)
+        .cloned()
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Peer {} not found", peer_id)))
+}
+
+// Latest polled statistics, the same ones pushed to the client as `stats` messages
+async fn peer_stats(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<PeerStats>, ApiError> {
+    let peer = find_peer(&state, &id).await?;
+    Ok(Json(peer.stats.snapshot()))
+}
+
+async fn recording_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Option<RecordingInfo>>, ApiError> {
+    let peer = find_peer(&state, &id).await?;
+    let recording = peer.recording.lock().await.as_ref().map(|r| r.info.clone());
+    Ok(Json(recording))
+}
+
+// Body is optional: an empty request records with the server defaults
+async fn start_recording(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    body: Bytes,
+) -> Result<Json<RecordingInfo>, ApiError> {
+    let options: RecordingOptions = if body.is_empty() {
+        RecordingOptions::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid recording options: {}", e)))?
+    };
+
+    let peer = find_peer(&state, &id).await?;
+    if peer.recording.lock().await.is_some() {
+        return Err((StatusCode::CONFLICT, "A recording is already running for this peer".to_string()));
+    }
+    let recording = start_peer_recording(&peer, &options, &state.settings).await.map_err(|e| {
+        warn!("Failed to start recording for peer {}: {}", id, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    info!("Recording {} started over HTTP for peer {}", recording.id, id);
+
+    let _ = peer.signal_sender.send(SignalMessage::RecordingStatus { recording: Some(recording.clone()) });
+    Ok(Json(recording))
+}
+
+async fn stop_recording(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<RecordingInfo>, ApiError> {
+    let peer = find_peer(&state, &id).await?;
+    let recording = stop_peer_recording(&peer)
+        .await
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "No recording is running for this peer".to_string()))?;
+
+    let _ = peer.signal_sender.send(SignalMessage::RecordingStatus { recording: None });
+    Ok(Json(recording))
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    source: String,
+    #[serde(default)]
+    format: SnapshotFormat,
+    width: Option<u32>,
+}
+
+async fn snapshot(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    if let Some(width) = query.width
+        && !(16..=7680).contains(&width)
+    {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid width {}", width)));
+    }
+    if !VideoSource::is_known(&query.source, &state.session_type, state.has_portal) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown source '{}'", query.source)));
+    }
+
+    let image = capture_snapshot(&state, &query.source, query.format, query.width)
+        .await
+        .map_err(|e| {
+            warn!("Snapshot of {} failed: {}", query.source, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, query.format.content_type()),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        image,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct PushRequest {
+    source: String,
+    target: PushTarget,
+}
+
+async fn list_pushes(State(state): State<Arc<AppState>>) -> Json<Vec<PushInfo>> {
+    Json(state.pushes.lock().await.values().map(|push| push.info()).collect())
+}
+
+async fn create_push(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PushRequest>,
+) -> Result<(StatusCode, Json<PushInfo>), ApiError> {
+    let push = start_push(&state, &request.source, request.target)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let info = push.info();
+    state.pushes.lock().await.insert(info.id.clone(), push);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn push_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<PushInfo>, ApiError> {
+    state
+        .pushes
+        .lock()
+        .await
+        .get(&id)
+        .map(|push| Json(push.info()))
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Push {} not found", id)))
+}
+
+async fn delete_push(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<PushInfo>, ApiError> {
+    let push = state
+        .pushes
+        .lock()
+        .await
+        .remove(&id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Push {} not found", id)))?;
+    Ok(Json(push.stop().await))
+}
+
+fn camera_unavailable() -> ApiError {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Virtual camera is disabled or v4l2loopback is unavailable".to_string(),
+    )
+}
+
+async fn virtual_camera_status(State(state): State<Arc<AppState>>) -> Result<Json<VirtualCameraStatus>, ApiError> {
+    let camera = state.virtual_camera.lock().await;
+    camera.as_ref().map(|camera| Json(camera.status())).ok_or_else(camera_unavailable)
+}
+
+#[derive(Debug, Deserialize)]
+struct VirtualCameraRequest {
+    source: String,
+}
+
+async fn start_virtual_camera(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<VirtualCameraRequest>,
+) -> Result<Json<VirtualCameraStatus>, ApiError> {
+    let mut camera = state.virtual_camera.lock().await;
+    let camera = camera.as_mut().ok_or_else(camera_unavailable)?;
+    camera.start_feed(&state, &request.source).await.map_err(|e| {
+        warn!("Failed to feed virtual camera with {}: {}", request.source, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    Ok(Json(camera.status()))
+}
+
+async fn stop_virtual_camera(State(state): State<Arc<AppState>>) -> Result<Json<VirtualCameraStatus>, ApiError> {
+    let mut camera = state.virtual_camera.lock().await;
+    let camera = camera.as_mut().ok_or_else(camera_unavailable)?;
+    camera.stop_feed(&state).await;
+    Ok(Json(camera.status()))
+}
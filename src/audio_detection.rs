@@ -1,6 +1,6 @@
 use tracing::info;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct AudioDevice {
     pub name: String,
     pub description: String,
@@ -157,3 +157,93 @@ pub fn get_device_info(device_name: &str) -> Option<AudioDevice> {
     
     None
 }
+
+/// Stream de reprodução ativo (sink-input do PulseAudio / nó do PipeWire via pipewire-pulse)
+#[derive(Debug, Clone)]
+pub struct PlaybackStream {
+    pub index: u32,
+    pub sink: Option<u32>,
+    pub owner_module: Option<u32>,
+    pub app_name: String,
+    pub pid: Option<u32>,
+    pub binary: Option<String>,
+    pub media_name: Option<String>,
+}
+
+/// Lista os streams de reprodução das aplicações em execução
+pub fn list_playback_streams() -> Vec<PlaybackStream> {
+    let output = std::process::Command::new("pactl")
+        .args(["list", "sink-inputs"])
+        .output();
+
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+
+    let own_pid = std::process::id();
+    parse_sink_inputs(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        // Ignorar os nossos próprios streams (loopbacks, reprodução do microfone do cliente)
+        .filter(|stream| stream.pid != Some(own_pid))
+        .collect()
+}
+
+fn parse_sink_inputs(output: &str) -> Vec<PlaybackStream> {
+    let mut streams = Vec::new();
+    let mut current: Option<PlaybackStream> = None;
+
+    for line in output.lines() {
+        let trimmed = line.trim();
+
+        if let Some(index) = trimmed.strip_prefix("Sink Input #") {
+            if let Some(stream) = current.take() {
+                streams.push(stream);
+            }
+            current = index.trim().parse::<u32>().ok().map(|index| PlaybackStream {
+                index,
+                sink: None,
+                owner_module: None,
+                app_name: String::new(),
+                pid: None,
+                binary: None,
+                media_name: None,
+            });
+            continue;
+        }
+
+        let Some(stream) = current.as_mut() else {
+            continue;
+        };
+
+        if let Some(sink) = trimmed.strip_prefix("Sink:") {
+            stream.sink = sink.trim().parse().ok();
+        } else if let Some(module) = trimmed.strip_prefix("Owner Module:") {
+            stream.owner_module = module.trim().parse().ok();
+        } else if let Some((key, value)) = trimmed.split_once(" = ") {
+            let value = value.trim().trim_matches('"').to_string();
+            match key.trim() {
+                "application.name" => stream.app_name = value,
+                "application.process.id" => stream.pid = value.parse().ok(),
+                "application.process.binary" => stream.binary = Some(value),
+                "media.name" => stream.media_name = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(stream) = current {
+        streams.push(stream);
+    }
+
+    for stream in &mut streams {
+        if stream.app_name.is_empty() {
+            stream.app_name = stream
+                .binary
+                .clone()
+                .unwrap_or_else(|| format!("Stream {}", stream.index));
+        }
+    }
+
+    streams
+}
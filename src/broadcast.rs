@@ -0,0 +1,259 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OwnedMutexGuard, mpsc};
+use tracing::{info, warn};
+
+use crate::config::{AppState, PipLayout, PipelineConfig, SignalMessage};
+use crate::media::{PipelineFactory, TeeBranch, VideoSource, drop_until_keyframe, set_pip_layout, watch_pipeline_bus};
+use crate::webrtc_handler::get_wayland_pipewire_node_id;
+
+// Pipeline encoding a source for non-WebRTC outputs (RTSP, HLS, ...), shared
+// by all of their clients and stopped when the last one leaves
+pub struct Broadcast {
+    pub pipeline: gst::Pipeline,
+    pub variant: String,
+    users: usize,
+}
+
+// A reference on a pipeline encoding `source`: either a broadcast pipeline
+// or the pipeline of a WebRTC viewer of the same source
+pub struct SourceLease {
+    pub source: String,
+    pub pipeline: gst::Pipeline,
+    broadcast: bool,
+}
+
+impl SourceLease {
+    /// Gives the reference back, stopping the broadcast pipeline when unused.
+    pub async fn release(self, state: &AppState) {
+        if !self.broadcast {
+            return;
+        }
+        let mut broadcasts = state.broadcasts.lock().await;
+        let Some(broadcast) = broadcasts.get_mut(&self.source) else {
+            return;
+        };
+        broadcast.users = broadcast.users.saturating_sub(1);
+        if broadcast.users == 0
+            && let Some(broadcast) = broadcasts.remove(&self.source)
+        {
+            info!("Stopping broadcast pipeline for {}", self.source);
+            let _ = broadcast.pipeline.set_state(gst::State::Null);
+        }
+    }
+}
+
+/// Per-key locks taken while starting something slow (e.g. waiting on the
+/// portal dialog), so starts of one key are serialized without holding the
+/// shared maps and blocking every other key.
+#[derive(Default)]
+pub struct StartLocks(std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>);
+
+impl StartLocks {
+    pub async fn lock(&self, key: &str) -> OwnedMutexGuard<()> {
+        let lock = self.0.lock().unwrap().entry(key.to_string()).or_default().clone();
+        lock.lock_owned().await
+    }
+}
+
+/// Stops every broadcast pipeline regardless of its clients, on shutdown.
+pub async fn stop_all_broadcasts(state: &AppState) {
+    for (source, broadcast) in state.broadcasts.lock().await.drain() {
+        info!("Stopping broadcast pipeline for {}", source);
+        let _ = broadcast.pipeline.set_state(gst::State::Null);
+    }
+}
+
+/// A running pipeline already encoding `source`, if any
+pub async fn find_running_pipeline(state: &AppState, source: &str) -> Option<gst::Pipeline> {
+    if let Some(broadcast) = state.broadcasts.lock().await.get(source) {
+        return Some(broadcast.pipeline.clone());
+    }
+    find_peer_pipeline(state, source).await
+}
+
+/// Takes a reference on a pipeline encoding `source`, reusing a running
+/// broadcast or WebRTC pipeline and starting a broadcast otherwise.
+pub async fn acquire_source(state: &Arc<AppState>, source: &str) -> Result<SourceLease> {
+    // Concurrent clients of the same source wait here instead of starting it twice;
+    // the broadcasts map itself is only locked briefly
+    let _starting = state.broadcast_starts.lock(source).await;
+    if let Some(lease) = lease_running_broadcast(state, source).await {
+        return Ok(lease);
+    }
+
+    // Share the encoder of a WebRTC viewer of the same source
+    if let Some(pipeline) = find_peer_pipeline(state, source).await {
+        info!("Sharing the WebRTC pipeline of {}", source);
+        return Ok(SourceLease {
+            source: source.to_string(),
+            pipeline,
+            broadcast: false,
+        });
+    }
+
+    let (pipeline, variant) = start_broadcast_pipeline(state, source).await?;
+    state.broadcasts.lock().await.insert(
+        source.to_string(),
+        Broadcast {
+            pipeline: pipeline.clone(),
+            variant,
+            users: 1,
+        },
+    );
+    Ok(SourceLease {
+        source: source.to_string(),
+        pipeline,
+        broadcast: true,
+    })
+}
+
+async fn lease_running_broadcast(state: &AppState, source: &str) -> Option<SourceLease> {
+    let mut broadcasts = state.broadcasts.lock().await;
+    let broadcast = broadcasts.get_mut(source)?;
+    broadcast.users += 1;
+    Some(SourceLease {
+        source: source.to_string(),
+        pipeline: broadcast.pipeline.clone(),
+        broadcast: true,
+    })
+}
+
+async fn find_peer_pipeline(state: &AppState, source: &str) -> Option<gst::Pipeline> {
+    state
+        .peers
+        .lock()
+        .await
+        .values()
+        .filter(|peer| peer.config.source_type == source)
+        .map(|peer| peer.pipeline.clone())
+        .find(|pipeline| pipeline.current_state() == gst::State::Playing)
+}
+
+// Creates the pipeline with the same hardware fallback chain as WebRTC viewers
+async fn start_broadcast_pipeline(state: &Arc<AppState>, source: &str) -> Result<(gst::Pipeline, String)> {
+    let pipewire_node_id = if VideoSource::uses_portal(source) && state.session_type == "wayland" && state.has_portal {
+        Some(get_wayland_pipewire_node_id().await?)
+    } else {
+        None
+    };
+
+    let mut config = PipelineConfig {
+        source_type: source.to_string(),
+        enable_audio: true,
+        ..Default::default()
+    };
+    let mut factory = PipelineFactory::new().with_pipeline_name(format!("broadcast-{}", source));
+    let mut result = factory
+        .create_pipeline(&config, &state.current_hw_info(), pipewire_node_id, &state.session_type, state.has_portal, state)
+        .await;
+    if let Err(e) = &result {
+        // System audio capture is optional for broadcasts
+        warn!("Broadcast of {} with audio failed ({}), retrying without audio", source, e);
+        config.enable_audio = false;
+        result = factory
+            .create_pipeline(&config, &state.current_hw_info(), pipewire_node_id, &state.session_type, state.has_portal, state)
+            .await;
+    }
+    // The WebRTC tracks have no peer; their writers just discard the packets
+    let (pipeline, _tracks, variant) = result.map_err(|e| anyhow!("Failed to start broadcast of {}: {}", source, e))?;
+    info!("Started broadcast pipeline for {}: {}", source, variant);
+
+    // No client to report to, bus errors are only logged
+    let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<SignalMessage>();
+    watch_pipeline_bus(&pipeline, signal_tx);
+    let source = source.to_string();
+    tokio::spawn(async move {
+        while let Some(msg) = signal_rx.recv().await {
+            if let SignalMessage::Error { message } = msg {
+                warn!("Broadcast pipeline of {} error: {}", source, message);
+            }
+        }
+    });
+
+    Ok((pipeline, variant))
+}
+
+// Raw video of a source feeding a consumer (snapshot, MJPEG, virtual camera)
+pub enum RawVideoTap {
+    // Decoding branch on the `videotee` of a pipeline already encoding the source
+    Branch(TeeBranch),
+    // Capture pipeline of its own
+    Pipeline(gst::Pipeline),
+}
+
+impl RawVideoTap {
+    pub async fn stop(self) {
+        match self {
+            Self::Branch(branch) => branch.detach().await,
+            Self::Pipeline(pipeline) => {
+                let _ = pipeline.set_state(gst::State::Null);
+            }
+        }
+    }
+}
+
+/// Feeds raw video of `source` into `consumer` (a launch fragment taking
+/// video/x-raw). A running pipeline of the source is tapped so capture
+/// devices are not opened twice; otherwise the source is captured directly.
+/// `configure` gets the bin holding the consumer before any data flows.
+pub async fn start_raw_video(
+    state: &AppState,
+    source: &str,
+    consumer: &str,
+    configure: impl FnOnce(&gst::Bin) -> Result<()>,
+) -> Result<RawVideoTap> {
+    if let Some(pipeline) = find_running_pipeline(state, source).await {
+        info!("Tapping the running pipeline of {} for raw video", source);
+        return Ok(RawVideoTap::Branch(attach_raw_branch(&pipeline, consumer, configure)?));
+    }
+    Ok(RawVideoTap::Pipeline(start_raw_pipeline(state, source, consumer, configure)?))
+}
+
+/// Decodes the `videotee` of a pipeline encoding a source into `consumer`.
+pub fn attach_raw_branch(
+    pipeline: &gst::Pipeline,
+    consumer: &str,
+    configure: impl FnOnce(&gst::Bin) -> Result<()>,
+) -> Result<TeeBranch> {
+    let bin = gst::parse::bin_from_description(
+        &format!("queue name=rawqueue leaky=downstream max-size-buffers=30 ! decodebin ! {}", consumer),
+        true,
+    )?;
+    let queue_src = bin
+        .by_name("rawqueue")
+        .and_then(|queue| queue.static_pad("src"))
+        .ok_or_else(|| anyhow!("Raw video bin has no queue"))?;
+    drop_until_keyframe(&queue_src);
+    configure(&bin)?;
+    TeeBranch::attach(pipeline, "videotee", bin)
+}
+
+/// Captures `source` straight into `consumer` with a pipeline of its own.
+pub fn start_raw_pipeline(
+    state: &AppState,
+    source: &str,
+    consumer: &str,
+    configure: impl FnOnce(&gst::Bin) -> Result<()>,
+) -> Result<gst::Pipeline> {
+    let video_source = VideoSource::select(source, None, &state.session_type, state.has_portal)
+        .ok_or_else(|| anyhow!("Source '{}' can only be captured while it is being streamed", source))?;
+    let pipeline_str = format!("{} ! {}", video_source.build_raw_str(), consumer);
+    info!("Capturing raw video of {} with pipeline: {}", source, pipeline_str);
+    let pipeline = gst::parse::launch(&pipeline_str)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Raw video pipeline is not a pipeline"))?;
+    if pipeline.by_name("pipmix").is_some() {
+        set_pip_layout(&pipeline, &PipLayout::default())?;
+    }
+    configure(pipeline.upcast_ref())?;
+
+    if let Err(e) = pipeline.set_state(gst::State::Playing) {
+        let _ = pipeline.set_state(gst::State::Null);
+        return Err(anyhow!("Failed to start raw video pipeline: {:?}", e));
+    }
+    Ok(pipeline)
+}
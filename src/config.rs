@@ -1,14 +1,22 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures::stream::SplitSink;
 use gstreamer as gst;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use gstreamer::prelude::*;
+use std::{collections::HashMap, env, fmt, net::SocketAddr, path::PathBuf, sync::{Arc, RwLock, Weak}, time::Instant};
+use tokio::sync::{mpsc, Mutex};
 use uuid::Uuid;
 use webrtc::peer_connection::RTCPeerConnection;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
+use crate::broadcast::{Broadcast, StartLocks};
+use crate::hls::HlsSession;
 use crate::media::HardwareInfo;
-use crate::virtual_audio::VirtualAudioDevice;
+use crate::mjpeg::MjpegProducer;
+use crate::push::PushSession;
+use crate::stats::{PeerStats, StatsTracker};
+use crate::recording::{Recording, RecordingFormat, RecordingInfo, default_recordings_dir, stop_peer_recording};
+use crate::whip::IngestSession;
+use crate::virtual_audio::{ApplicationAudioRoute, SystemAudioRouting, VirtualAudioDevice};
+use crate::virtual_camera::VirtualCamera;
 
 // Unique identifier for each peer connection
 pub type PeerId = Uuid;
@@ -16,24 +24,168 @@ pub type PeerId = Uuid;
 // Global state shared across all connections and tasks
 pub struct AppState {
     pub peers: Mutex<HashMap<PeerId, Arc<PeerState>>>,
-    pub hw_info: Arc<HardwareInfo>,
+    // Signaling channel of every open WebSocket, streaming or not, for
+    // server-wide notifications such as source list changes
+    pub signal_senders: Mutex<HashMap<PeerId, mpsc::UnboundedSender<SignalMessage>>>,
+    // Replaced when an operator triggers re-detection
+    pub hw_info: RwLock<Arc<HardwareInfo>>,
     pub session_type: String,
     pub has_portal: bool,
     pub virtual_audio: Mutex<Option<VirtualAudioDevice>>,
+    pub virtual_camera: Mutex<Option<VirtualCamera>>,
+    pub settings: ServerSettings,
+    // Streams published over WHIP, by name
+    pub ingests: Mutex<HashMap<String, Arc<IngestSession>>>,
+    // Pipelines encoding a source for RTSP/HLS clients, by source id
+    pub broadcasts: Mutex<HashMap<String, Broadcast>>,
+    // Held per source while a broadcast starts
+    pub broadcast_starts: StartLocks,
+    // Running HLS outputs, by source id ("<source>/ll" for LL-HLS)
+    pub hls: Mutex<HashMap<String, HlsSession>>,
+    // Held per HLS output while it starts
+    pub hls_starts: StartLocks,
+    // MJPEG encoders shared by the /mjpeg clients with the same parameters
+    pub mjpeg: Mutex<HashMap<String, Weak<MjpegProducer>>>,
+    // Held per MJPEG encoder while it starts
+    pub mjpeg_starts: StartLocks,
+    // Pushes to external RTMP/SRT ingests, by push id
+    pub pushes: Mutex<HashMap<String, PushSession>>,
+}
+
+impl AppState {
+    pub fn current_hw_info(&self) -> Arc<HardwareInfo> {
+        self.hw_info.read().unwrap().clone()
+    }
+}
+
+// Server-wide settings, read from DESKTOP_STREAMER_* environment variables
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    // DESKTOP_STREAMER_AUDIO_ROUTING: off | move-streams | loopback
+    pub system_audio_routing: SystemAudioRouting,
+    // DESKTOP_STREAMER_RECORDINGS_DIR
+    pub recordings_dir: PathBuf,
+    // DESKTOP_STREAMER_RECORDING_MAX_SIZE_MB / _MAX_DURATION_SECS, 0 = no rotation
+    pub recording_max_size_mb: u64,
+    pub recording_max_duration_secs: u64,
+    // DESKTOP_STREAMER_RTSP_PORT, RTSP server disabled when unset
+    pub rtsp_port: Option<u16>,
+    // DESKTOP_STREAMER_HLS_SEGMENT_SECS / _IDLE_SECS
+    pub hls_segment_secs: u64,
+    pub hls_idle_secs: u64,
+    // DESKTOP_STREAMER_MJPEG_FPS / _QUALITY, defaults of /mjpeg streams
+    pub mjpeg_fps: u32,
+    pub mjpeg_quality: u32,
+    // DESKTOP_STREAMER_VIRTUAL_CAMERA: create a v4l2loopback camera at startup
+    pub virtual_camera: bool,
+    // DESKTOP_STREAMER_ADMIN_TOKEN, admin API disabled when unset
+    pub admin_token: Option<Secret>,
+}
+
+// Setting kept out of the logs
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
+impl ServerSettings {
+    pub fn from_env() -> Self {
+        let system_audio_routing = match env::var("DESKTOP_STREAMER_AUDIO_ROUTING") {
+            Ok(value) => SystemAudioRouting::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown DESKTOP_STREAMER_AUDIO_ROUTING '{}', routing disabled", value);
+                SystemAudioRouting::Off
+            }),
+            Err(_) => SystemAudioRouting::Off,
+        };
+
+        let recordings_dir = env::var("DESKTOP_STREAMER_RECORDINGS_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| default_recordings_dir());
+
+        Self {
+            system_audio_routing,
+            recordings_dir,
+            recording_max_size_mb: env_u64("DESKTOP_STREAMER_RECORDING_MAX_SIZE_MB", 0),
+            recording_max_duration_secs: env_u64("DESKTOP_STREAMER_RECORDING_MAX_DURATION_SECS", 0),
+            rtsp_port: env_port("DESKTOP_STREAMER_RTSP_PORT"),
+            hls_segment_secs: env_u64("DESKTOP_STREAMER_HLS_SEGMENT_SECS", 2).max(1),
+            hls_idle_secs: env_u64("DESKTOP_STREAMER_HLS_IDLE_SECS", 30),
+            mjpeg_fps: env_u64("DESKTOP_STREAMER_MJPEG_FPS", 10).clamp(1, 60) as u32,
+            mjpeg_quality: env_u64("DESKTOP_STREAMER_MJPEG_QUALITY", 80).min(100) as u32,
+            virtual_camera: env_flag("DESKTOP_STREAMER_VIRTUAL_CAMERA"),
+            admin_token: env::var("DESKTOP_STREAMER_ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty())
+                .map(Secret),
+        }
+    }
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!("Invalid {} '{}', using {}", name, value, default);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
+
+fn env_port(name: &str) -> Option<u16> {
+    let value = env::var(name).ok()?;
+    value.trim().parse().map_err(|_| warn!("Invalid {} '{}', ignoring", name, value)).ok()
 }
 
 // State specific to a single connected peer
 pub struct PeerState {
     pub peer_connection: Arc<RTCPeerConnection>,
     pub pipeline: gst::Pipeline,
+    // Pipeline configuration that started, e.g. "VAAPI H.264"
+    pub pipeline_variant: String,
+    pub config: PipelineConfig,
     // Channel to send signaling messages back to the client's WebSocket
-    pub ws_sender: Mutex<Option<SplitSink<WebSocket, Message>>>,
+    pub signal_sender: mpsc::UnboundedSender<SignalMessage>,
+    // Per-application capture sink, restored when the peer goes away
+    pub app_audio_route: Mutex<Option<ApplicationAudioRoute>>,
+    // Whether this peer holds a reference on the automatic system audio routing
+    pub routes_system_audio: bool,
+    pub recording: Mutex<Option<Recording>>,
+    pub stats: Arc<StatsTracker>,
+    // Client address of the signaling (WebSocket or WHEP) request
+    pub remote_addr: Option<SocketAddr>,
+    pub connected_at: Instant,
+}
+
+impl PeerState {
+    /// Finalizes any recording, stops the media pipeline, closes the peer
+    /// connection and restores any audio routing changed on behalf of this peer.
+    pub async fn close(&self, state: &AppState) {
+        stop_peer_recording(self).await;
+        let _ = self.pipeline.set_state(gst::State::Null);
+        let _ = self.peer_connection.close().await;
+        if let Some(mut route) = self.app_audio_route.lock().await.take() {
+            route.restore();
+        }
+        if self.routes_system_audio
+            && let Some(virtual_device) = state.virtual_audio.lock().await.as_mut()
+        {
+            virtual_device.release_system_audio();
+        }
+    }
 }
 
 // Configuration for creating a new media pipeline
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PipelineConfig {
-    pub source_type: String, // e.g., "x11-0", "wayland-portal", "camera-0", "test"
+    pub source_type: String, // e.g., "x11-0", "wayland-portal", "camera-0", "whip-<name>", "test"
     pub audio_source: Option<String>, // e.g., "microphone", "system", "application"
     pub enable_audio: bool,
     pub enable_microphone_input: bool, // Para receber áudio do cliente
@@ -42,6 +194,150 @@ pub struct PipelineConfig {
     pub resolution: (u32, u32),
     pub framerate: u32,
     pub use_hardware_encoding: bool,
+    // Sink-input indices of the applications whose audio should be streamed
+    #[serde(default)]
+    pub audio_applications: Vec<u32>,
+    // Sources mixed into the single Opus track; takes precedence over audio_source
+    #[serde(default)]
+    pub audio_sources: Vec<AudioSourceConfig>,
+    #[serde(default)]
+    pub audio_processing: AudioProcessingConfig,
+    // Video codecs the client can decode ("H264", "H265", "AV1", "VP8"); variants
+    // encoding anything else are skipped. Filled from the SDP offer when empty.
+    #[serde(default)]
+    pub video_codecs: Vec<String>,
+    // Damage-based desktop capture that skips unchanged frames and drops to
+    // about 1 fps while the screen is idle
+    #[serde(default)]
+    pub adaptive_framerate: bool,
+    // Capture without the pointer and send it over the "cursor" data channel
+    // for the client to draw (X11 sources)
+    #[serde(default)]
+    pub cursor_metadata: bool,
+    // Camera placement of "pip-<screen>+<camera>" sources, changeable live
+    #[serde(default)]
+    pub pip_layout: PipLayout,
+}
+
+// One input of the server-side audio mixer
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AudioSourceConfig {
+    pub device: String, // same ids as audio_source, or "applications" for the per-app capture
+    #[serde(default = "default_audio_gain")]
+    pub gain: f64,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+fn default_audio_gain() -> f64 {
+    1.0
+}
+
+// Processing applied to the (mixed) audio before Opus encoding
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AudioProcessingConfig {
+    pub gain_db: f64,
+    pub limiter: bool,
+    pub limiter_threshold: f64, // linear amplitude, 0.0 - 1.0
+    pub loudness_normalization: bool, // EBU R128, needs rsaudioloudnorm
+    pub target_lufs: f64,
+    pub level_interval_ms: u64, // 0 disables level reports
+    // WebRTC audio processing (webrtcdsp) on microphone paths
+    pub echo_cancellation: bool,
+    pub noise_suppression: bool,
+    pub auto_gain_control: bool,
+}
+
+impl AudioProcessingConfig {
+    pub fn has_voice_processing(&self) -> bool {
+        self.echo_cancellation || self.noise_suppression || self.auto_gain_control
+    }
+}
+
+impl Default for AudioProcessingConfig {
+    fn default() -> Self {
+        Self {
+            gain_db: 0.0,
+            limiter: true,
+            limiter_threshold: 0.89, // ~ -1 dBFS
+            loudness_normalization: false,
+            target_lufs: -16.0,
+            level_interval_ms: 100,
+            echo_cancellation: false,
+            noise_suppression: false,
+            auto_gain_control: false,
+        }
+    }
+}
+
+// Camera overlay of a picture-in-picture source
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PipLayout {
+    pub position: PipPosition,
+    pub size: f64, // camera width as a fraction of the screen width, 0.05 - 1.0
+    pub margin: u32, // pixels between the camera and the screen edges
+    pub border: u32, // white border around the camera, in pixels
+    pub opacity: f64, // 0.0 - 1.0
+}
+
+impl Default for PipLayout {
+    fn default() -> Self {
+        Self {
+            position: PipPosition::BottomRight,
+            size: 0.25,
+            margin: 24,
+            border: 4,
+            opacity: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PipPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl PipelineConfig {
+    /// Whether the client accepts `codec` (an SDP encoding name); no list accepts all
+    pub fn accepts_video_codec(&self, codec: &str) -> bool {
+        self.video_codecs.is_empty() || self.video_codecs.iter().any(|c| c.eq_ignore_ascii_case(codec))
+    }
+
+    /// Restricts the video codecs to the ones offered in `sdp`, unless the client chose them
+    pub fn negotiate_video_codecs(&mut self, sdp: &str) {
+        if !self.video_codecs.is_empty() {
+            return;
+        }
+        let mut in_video = false;
+        for line in sdp.lines() {
+            if line.starts_with("m=") {
+                in_video = line.starts_with("m=video");
+            } else if in_video
+                && let Some(rtpmap) = line.strip_prefix("a=rtpmap:")
+                && let Some(name) = rtpmap.split_whitespace().nth(1).and_then(|c| c.split('/').next())
+                && !self.video_codecs.iter().any(|c| c.eq_ignore_ascii_case(name))
+            {
+                self.video_codecs.push(name.to_ascii_uppercase());
+            }
+        }
+    }
+
+    /// Whether any audio source captures the virtual output sink
+    pub fn uses_virtual_output(&self) -> bool {
+        const VIRTUAL: &str = "desktop_streamer_virtual";
+        self.enable_audio
+            && if self.audio_sources.is_empty() {
+                self.audio_source.as_deref() == Some(VIRTUAL)
+            } else {
+                self.audio_sources.iter().any(|s| s.device == VIRTUAL)
+            }
+    }
 }
 
 impl Default for PipelineConfig {
@@ -56,6 +352,13 @@ impl Default for PipelineConfig {
             resolution: (1920, 1080),
             framerate: 30,
             use_hardware_encoding: true,
+            audio_applications: Vec::new(),
+            audio_sources: Vec::new(),
+            audio_processing: AudioProcessingConfig::default(),
+            video_codecs: Vec::new(),
+            adaptive_framerate: false,
+            cursor_metadata: false,
+            pip_layout: PipLayout::default(),
         }
     }
 }
@@ -70,8 +373,24 @@ pub enum SignalMessage {
     Error { message: String },
     Monitors { monitors: Vec<MonitorInfo> },
     AudioDevices { devices: Vec<AudioDeviceInfo> },
+    ApplicationStreams { streams: Vec<ApplicationStreamInfo> },
+    ListApplicationStreams,
+    SetAudioGain { index: usize, gain: f64, muted: bool },
+    AudioLevel { source: usize, peak: Vec<f64>, rms: Vec<f64> }, // dBFS per channel
+    StartRecording {
+        #[serde(default)]
+        format: RecordingFormat,
+        #[serde(default)]
+        max_size_mb: Option<u64>,
+        #[serde(default)]
+        max_duration_secs: Option<u64>,
+    },
+    StopRecording,
+    RecordingStatus { recording: Option<RecordingInfo> },
     PortalRequest { request_id: String },
     PortalResponse { request_id: String, node_id: Option<u32> },
+    Stats { stats: PeerStats },
+    SetPipLayout { layout: PipLayout },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -81,7 +400,7 @@ pub struct MonitorInfo {
     pub primary: bool,
     pub resolution: String,
     #[serde(rename = "type")]
-    pub source_type: String, // "screen" or "camera"
+    pub source_type: String, // "screen", "camera" or "remote"
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -91,3 +410,12 @@ pub struct AudioDeviceInfo {
     pub device_type: String, // "microphone", "system", "application"
     pub description: String,
 }
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApplicationStreamInfo {
+    pub id: u32, // sink-input index
+    pub app_name: String,
+    pub pid: Option<u32>,
+    pub binary: Option<String>,
+    pub media_name: Option<String>,
+}
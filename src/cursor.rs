@@ -0,0 +1,140 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xfixes::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::ConnectionExt as _;
+
+/// Label of the data channel the client opens to receive cursor positions,
+/// unordered and without retransmissions since only the latest one matters
+pub const CURSOR_CHANNEL: &str = "cursor";
+/// Label of the reliable data channel carrying cursor shapes
+pub const CURSOR_SHAPE_CHANNEL: &str = "cursor-shape";
+
+// Pointer polling period, about 120 Hz; only changes are sent
+const POLL_INTERVAL: Duration = Duration::from_millis(8);
+
+/// Stops the capture sources of a pipeline from drawing the pointer into the
+/// frames. Call before the pipeline starts.
+pub fn hide_pointer(pipeline: &gst::Pipeline) {
+    for element in pipeline.iterate_recurse().into_iter().flatten() {
+        if element.factory().is_some_and(|factory| factory.name() == "ximagesrc") {
+            element.set_property("show-pointer", false);
+        }
+    }
+}
+
+/// The cursor channels of a peer as they open
+#[derive(Default)]
+pub struct CursorChannels {
+    positions: Option<Arc<RTCDataChannel>>,
+    shapes: Option<Arc<RTCDataChannel>>,
+}
+
+impl CursorChannels {
+    /// Records an opened channel, returning (positions, shapes) once both are open
+    pub fn opened(&mut self, channel: Arc<RTCDataChannel>) -> Option<(Arc<RTCDataChannel>, Arc<RTCDataChannel>)> {
+        match channel.label() {
+            CURSOR_CHANNEL => self.positions = Some(channel),
+            CURSOR_SHAPE_CHANNEL => self.shapes = Some(channel),
+            _ => return None,
+        }
+        Some((self.positions.clone()?, self.shapes.clone()?))
+    }
+}
+
+/// Streams the pointer of an X11 screen until either channel closes. Positions
+/// go out on `positions` as JSON text, `{"seq":7,"x":0.5,"y":0.25}`, normalized
+/// to the screen size (they may arrive out of order, older `seq`s are stale);
+/// shapes on `shapes` as binary messages with an 8 byte header (width, height,
+/// hotspot x, hotspot y as little-endian u16) followed by RGBA pixels.
+pub fn spawn_x11_cursor_stream(screen_num: i32, positions: Arc<RTCDataChannel>, shapes: Arc<RTCDataChannel>) {
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        match stream_x11_cursor(screen_num, &positions, &shapes, &handle) {
+            Ok(()) => debug!("Cursor stream of screen {} finished", screen_num),
+            Err(e) => warn!("Cursor stream of screen {} stopped: {}", screen_num, e),
+        }
+    });
+}
+
+fn stream_x11_cursor(
+    screen_num: i32,
+    positions: &RTCDataChannel,
+    shapes: &RTCDataChannel,
+    handle: &tokio::runtime::Handle,
+) -> Result<()> {
+    // Same display as the ximagesrc capture
+    let (conn, _) = x11rb::connect(Some(":0"))?;
+    let screen = conn
+        .setup()
+        .roots
+        .get(screen_num.max(0) as usize)
+        .ok_or_else(|| anyhow!("X11 screen {} not found", screen_num))?;
+    let (root, screen_width, screen_height) = (
+        screen.root,
+        screen.width_in_pixels as f64,
+        screen.height_in_pixels as f64,
+    );
+    conn.xfixes_query_version(5, 0)?.reply()?;
+    conn.xfixes_select_cursor_input(root, xfixes::CursorNotifyMask::DISPLAY_CURSOR)?;
+    conn.flush()?;
+    info!("Streaming cursor of X11 screen {} as metadata", screen_num);
+
+    let mut last_position = None;
+    let mut seq: u64 = 0;
+    let mut shape_changed = true;
+    while positions.ready_state() == RTCDataChannelState::Open && shapes.ready_state() == RTCDataChannelState::Open {
+        while let Some(event) = conn.poll_for_event()? {
+            if let Event::XfixesCursorNotify(_) = event {
+                shape_changed = true;
+            }
+        }
+        if shape_changed {
+            shape_changed = false;
+            let image = conn.xfixes_get_cursor_image()?.reply()?;
+            let message = shape_message(&image);
+            handle.block_on(shapes.send(&message.into()))?;
+        }
+
+        let pointer = conn.query_pointer(root)?.reply()?;
+        let position = (pointer.root_x, pointer.root_y);
+        if pointer.same_screen && last_position != Some(position) {
+            last_position = Some(position);
+            seq += 1;
+            let message = serde_json::json!({
+                "seq": seq,
+                "x": position.0 as f64 / screen_width,
+                "y": position.1 as f64 / screen_height,
+            });
+            handle.block_on(positions.send_text(message.to_string()))?;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+// XFixes cursors are premultiplied ARGB; canvases take straight RGBA
+fn shape_message(image: &xfixes::GetCursorImageReply) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + image.cursor_image.len() * 4);
+    for value in [image.width, image.height, image.xhot, image.yhot] {
+        message.extend_from_slice(&value.to_le_bytes());
+    }
+    for pixel in &image.cursor_image {
+        let alpha = (pixel >> 24) & 0xff;
+        let unpremultiply = |channel: u32| (channel * 255).checked_div(alpha).unwrap_or(0).min(255) as u8;
+        message.extend_from_slice(&[
+            unpremultiply((pixel >> 16) & 0xff),
+            unpremultiply((pixel >> 8) & 0xff),
+            unpremultiply(pixel & 0xff),
+            alpha as u8,
+        ]);
+    }
+    message
+}
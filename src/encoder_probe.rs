@@ -0,0 +1,253 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+// Encoders probed at startup: (element, codec). Frames are fed from system
+// memory, which every encoder accepts; GPU uploads are checked by the variants.
+const CANDIDATES: &[(&str, &str)] = &[
+    ("nvh264enc", "h264"),
+    ("nvh265enc", "h265"),
+    ("nvav1enc", "av1"),
+    ("vah264enc", "h264"),
+    ("vah265enc", "h265"),
+    ("vaav1enc", "av1"),
+    ("qsvh264enc", "h264"),
+    ("vaapih264enc", "h264"),
+    ("vaapivp8enc", "vp8"),
+    ("v4l2h264enc", "h264"),
+    ("x264enc", "h264"),
+    ("vp8enc", "vp8"),
+];
+
+const PROBE_FRAMES: u32 = 5;
+// Size of the test frames, the only resolution actually encoded
+const PROBE_RESOLUTION: (u32, u32) = (640, 480);
+// Bumped when the cached fields change meaning, so old caches are probed again
+const CACHE_VERSION: u32 = 2;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of encoding a few test frames with one encoder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncoderCapability {
+    pub element: String,
+    pub codec: String,
+    pub working: bool,
+    // Negotiated output profile, e.g. "constrained-baseline"
+    pub profile: Option<String>,
+    // Resolution the test frames were encoded at, the only one verified
+    #[serde(default)]
+    pub verified_resolution: Option<(u32, u32)>,
+    // Largest frame the encoder sink pad advertises, not verified by encoding;
+    // None when the caps leave it open (software encoders)
+    pub max_resolution: Option<(u32, u32)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// On-disk cache, reused while the drivers and GStreamer plugins stay the same
+#[derive(Serialize, Deserialize)]
+struct ProbeCache {
+    key: String,
+    encoders: Vec<EncoderCapability>,
+}
+
+/// Probes every installed candidate encoder, reusing the cached results when
+/// the driver versions did not change (unless `refresh` is set). Encoders in
+/// `in_use` are running in a pipeline and keep their previous result.
+pub fn probe_encoders(refresh: bool, in_use: &[EncoderCapability]) -> Vec<EncoderCapability> {
+    let key = driver_key();
+    let path = cache_path();
+    if !refresh
+        && let Some(cache) = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<ProbeCache>(&data).ok())
+            .filter(|cache| cache.key == key)
+    {
+        info!("Using cached encoder probe from {}", path.display());
+        return cache.encoders;
+    }
+
+    let started = Instant::now();
+    let encoders: Vec<_> = CANDIDATES
+        .iter()
+        .filter(|(element, _)| gst::ElementFactory::find(element).is_some())
+        .map(|(element, codec)| match in_use.iter().find(|encoder| encoder.element == *element) {
+            Some(previous) => {
+                info!("  {} ({}): in use, keeping the previous result", element, codec);
+                previous.clone()
+            }
+            None => probe_encoder(element, codec),
+        })
+        .collect();
+    info!("Probed {} encoders in {:?}", encoders.len(), started.elapsed());
+
+    let cache = ProbeCache { key, encoders };
+    // With streams running a GPU may refuse extra sessions; such failures are
+    // not cached so the next start probes them again
+    if !in_use.is_empty() && cache.encoders.iter().any(|encoder| !encoder.working) {
+        warn!("Some encoders failed while others were in use, not caching the probe");
+        return cache.encoders;
+    }
+    let result = std::fs::create_dir_all(path.parent().unwrap_or(&path))
+        .and_then(|_| std::fs::write(&path, serde_json::to_vec_pretty(&cache).unwrap_or_default()));
+    if let Err(e) = result {
+        warn!("Failed to write encoder probe cache {}: {}", path.display(), e);
+    }
+    cache.encoders
+}
+
+fn probe_encoder(element: &str, codec: &str) -> EncoderCapability {
+    let mut capability = EncoderCapability {
+        element: element.to_string(),
+        codec: codec.to_string(),
+        working: false,
+        profile: None,
+        verified_resolution: None,
+        max_resolution: None,
+        error: None,
+    };
+    match run_probe(element) {
+        Ok((profile, max_resolution)) => {
+            capability.working = true;
+            capability.profile = profile;
+            capability.verified_resolution = Some(PROBE_RESOLUTION);
+            capability.max_resolution = max_resolution;
+        }
+        Err(e) => {
+            debug!("Encoder {} failed the probe: {}", element, e);
+            capability.error = Some(e.to_string());
+        }
+    }
+    info!(
+        "  {} ({}): {}",
+        element,
+        codec,
+        if capability.working { "working" } else { "not working" }
+    );
+    capability
+}
+
+// (profile, advertised max resolution) of an encoder that passed the probe
+type ProbeOutcome = (Option<String>, Option<(u32, u32)>);
+
+fn run_probe(element: &str) -> Result<ProbeOutcome> {
+    let pipeline_str = format!(
+        "videotestsrc num-buffers={} ! video/x-raw,width={},height={},framerate=30/1 ! \
+         videoconvert ! {} name=encoder ! fakesink sync=false",
+        PROBE_FRAMES, PROBE_RESOLUTION.0, PROBE_RESOLUTION.1, element
+    );
+    let pipeline = gst::parse::launch(&pipeline_str)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("Not a pipeline"))?;
+    let encoder = pipeline.by_name("encoder").ok_or_else(|| anyhow!("Encoder not found"))?;
+
+    let result = (|| {
+        pipeline.set_state(gst::State::Playing)?;
+        let bus = pipeline.bus().ok_or_else(|| anyhow!("Pipeline without bus"))?;
+        let timeout = gst::ClockTime::from_nseconds(PROBE_TIMEOUT.as_nanos() as u64);
+        match bus.timed_pop_filtered(timeout, &[gst::MessageType::Eos, gst::MessageType::Error]) {
+            Some(msg) => match msg.view() {
+                gst::MessageView::Error(err) => Err(anyhow!("{}", err.error())),
+                _ => Ok(()),
+            },
+            None => Err(anyhow!("Timed out encoding test frames")),
+        }?;
+
+        let profile = encoder
+            .static_pad("src")
+            .and_then(|pad| pad.current_caps())
+            .and_then(|caps| caps.structure(0)?.get::<String>("profile").ok());
+        let max_resolution = encoder
+            .static_pad("sink")
+            .and_then(|pad| max_resolution(&pad.query_caps(None)));
+        Ok((profile, max_resolution))
+    })();
+    let _ = pipeline.set_state(gst::State::Null);
+    result
+}
+
+// Templates without a real limit advertise G_MAXINT, which is no information
+fn max_resolution(caps: &gst::Caps) -> Option<(u32, u32)> {
+    let max = |field: &str| {
+        caps.iter()
+            .filter_map(|s| {
+                s.get::<gst::IntRange<i32>>(field)
+                    .map(|range| range.max())
+                    .or_else(|_| s.get::<i32>(field))
+                    .ok()
+            })
+            .max()
+            .filter(|value| *value < i32::MAX)
+            .map(|value| value as u32)
+    };
+    Some((max("width")?, max("height")?))
+}
+
+// Anything that changes which encoders work: GStreamer and the plugins providing
+// the encoders, kernel (in-tree GPU drivers), the NVIDIA kernel driver and the
+// userspace VA and NVIDIA libraries
+fn driver_key() -> String {
+    let read = |path: &str| std::fs::read_to_string(path).map(|s| s.trim().to_string()).unwrap_or_default();
+    let installed: Vec<_> = CANDIDATES
+        .iter()
+        .filter_map(|(element, _)| {
+            let factory = gst::ElementFactory::find(element)?;
+            let plugin = factory.plugin().map(|plugin| plugin.version().to_string()).unwrap_or_default();
+            Some(format!("{}@{}", element, plugin))
+        })
+        .collect();
+    let va_dirs = std::env::var("LIBVA_DRIVERS_PATH")
+        .map(|dirs| dirs.split(':').map(str::to_string).collect())
+        .unwrap_or_else(|_| LIBRARY_DIRS.iter().map(|dir| format!("{}/dri", dir)).collect::<Vec<_>>());
+    let nvidia_dirs: Vec<_> = LIBRARY_DIRS.iter().map(|dir| dir.to_string()).collect();
+    format!(
+        "v{};gst={};kernel={};nvidia={};libva={};encoders={};va_drivers={};nvidia_libs={}",
+        CACHE_VERSION,
+        gst::version_string(),
+        read("/proc/sys/kernel/osrelease"),
+        read("/sys/module/nvidia/version"),
+        std::env::var("LIBVA_DRIVER_NAME").unwrap_or_default(),
+        installed.join(","),
+        library_versions(&va_dirs, |name| name.ends_with("_drv_video.so")).join(","),
+        library_versions(&nvidia_dirs, |name| name.starts_with("libnvidia-encode.so.") || name.starts_with("libcuda.so.")).join(",")
+    )
+}
+
+// Where distributions install the VA drivers (under dri/) and the NVIDIA libraries
+const LIBRARY_DIRS: &[&str] = &["/usr/lib/x86_64-linux-gnu", "/usr/lib64", "/usr/lib", "/usr/local/lib"];
+
+// "<file>@<mtime>" of the matching libraries; NVIDIA ones carry their version in
+// the file name, VA drivers only change on disk when updated
+fn library_versions(dirs: &[String], matches: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut versions: Vec<_> = dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+        .filter(|entry| entry.file_name().to_str().is_some_and(&matches))
+        .map(|entry| {
+            let modified = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|age| age.as_secs())
+                .unwrap_or_default();
+            format!("{}@{}", entry.file_name().to_string_lossy(), modified)
+        })
+        .collect();
+    versions.sort();
+    versions
+}
+
+fn cache_path() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("desktop_streamer")
+        .join("encoders.json")
+}
@@ -0,0 +1,241 @@
+use anyhow::{Result, anyhow};
+use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse, routing::get};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::AppState;
+use crate::media::PipelineFactory;
+use crate::server::check_portal_availability;
+
+// The dry run starts real encoders, readiness probes reuse its result for a while
+const DRY_RUN_CACHE: Duration = Duration::from_secs(60);
+const DRY_RUN_TIMEOUT: Duration = Duration::from_secs(3);
+const AUDIO_SERVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Pipeline variants tried by the dry run; viewers get the first one that works,
+// so a later one working already means a degraded server
+const DRY_RUN_VARIANTS: usize = 3;
+
+// Variant that encoded a frame, or why none did
+type DryRunResult = Result<String, String>;
+
+static DRY_RUN: Lazy<Mutex<Option<(Instant, DryRunResult)>>> = Lazy::new(Default::default);
+
+// Health routes, merged into the main router by run_server
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+// Liveness: answering at all means the process and its runtime are up
+async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") }))
+}
+
+async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let readiness = check_readiness(&state).await;
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness))
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    // Failed optional checks are reported without making the server unready
+    pub required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub gstreamer: Check,
+    pub video_pipeline: Check,
+    pub audio_server: Check,
+    pub portal: Check,
+}
+
+impl Readiness {
+    fn failed_checks(&self) -> Vec<&'static str> {
+        [
+            ("gstreamer", &self.gstreamer),
+            ("video_pipeline", &self.video_pipeline),
+            ("audio_server", &self.audio_server),
+            ("portal", &self.portal),
+        ]
+        .into_iter()
+        .filter(|(_, check)| !check.ok)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+pub async fn check_readiness(state: &AppState) -> Readiness {
+    let gstreamer = Check {
+        ok: gst::INITIALIZED.load(Ordering::SeqCst),
+        required: true,
+        detail: Some(gst::version_string().to_string()),
+    };
+
+    let video_pipeline = match cached_dry_run(state).await {
+        Ok(encoder) => Check { ok: true, required: true, detail: Some(encoder) },
+        Err(e) => Check { ok: false, required: true, detail: Some(e) },
+    };
+
+    let audio_server = match audio_server_reachable().await {
+        Ok(server) => Check { ok: true, required: true, detail: Some(server) },
+        Err(e) => Check { ok: false, required: true, detail: Some(e.to_string()) },
+    };
+
+    // Only Wayland capture goes through the portal
+    let portal_available = check_portal_availability().await;
+    let portal = Check {
+        ok: portal_available,
+        required: false,
+        detail: Some(format!("{} session", state.session_type)),
+    };
+
+    let ready = [&gstreamer, &video_pipeline, &audio_server].iter().all(|check| check.ok);
+    Readiness {
+        ready,
+        gstreamer,
+        video_pipeline,
+        audio_server,
+        portal,
+    }
+}
+
+async fn cached_dry_run(state: &AppState) -> DryRunResult {
+    if let Some((at, result)) = DRY_RUN.lock().unwrap().as_ref()
+        && at.elapsed() < DRY_RUN_CACHE
+    {
+        return result.clone();
+    }
+    let variants = PipelineFactory::new().dry_run_configurations(&state.current_hw_info(), &state.session_type, state);
+    let result = tokio::task::spawn_blocking(move || dry_run_video_pipeline(variants))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+    *DRY_RUN.lock().unwrap() = Some((Instant::now(), result.clone()));
+    result
+}
+
+/// Starts the first pipeline variants a viewer would get, capture and encoder
+/// included, until one encodes a frame, returning its name.
+fn dry_run_video_pipeline(variants: Vec<(String, String)>) -> Result<String> {
+    for (desc, pipeline_str) in variants.into_iter().take(DRY_RUN_VARIANTS) {
+        let Ok(pipeline) = gst::parse::launch(&pipeline_str) else {
+            debug!("Readiness dry run could not build {}", desc);
+            continue;
+        };
+        let encoded_frame = pipeline
+            .downcast_ref::<gst::Bin>()
+            .and_then(|bin| bin.by_name("videosink"))
+            .and_then(|sink| sink.downcast::<AppSink>().ok())
+            .is_some_and(|sink| {
+                pipeline.set_state(gst::State::Playing).is_ok()
+                    && sink
+                        .try_pull_sample(gst::ClockTime::from_nseconds(DRY_RUN_TIMEOUT.as_nanos() as u64))
+                        .is_some()
+            });
+        let _ = pipeline.set_state(gst::State::Null);
+        if encoded_frame {
+            return Ok(desc);
+        }
+        debug!("Readiness dry run with {} produced no frame", desc);
+    }
+    Err(anyhow!("None of the first {} video pipeline variants encoded a frame", DRY_RUN_VARIANTS))
+}
+
+async fn audio_server_reachable() -> Result<String> {
+    let output = tokio::time::timeout(
+        AUDIO_SERVER_TIMEOUT,
+        tokio::process::Command::new("pactl").arg("info").output(),
+    )
+    .await
+    .map_err(|_| anyhow!("pactl info timed out"))??;
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let info = String::from_utf8_lossy(&output.stdout);
+    Ok(info
+        .lines()
+        .find_map(|line| line.strip_prefix("Server Name:"))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|| "pactl".to_string()))
+}
+
+/// Sends a state change to systemd ($NOTIFY_SOCKET); a no-op outside systemd.
+pub fn sd_notify(message: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        // Abstract socket names start with '@'
+        if let Some(name) = path.strip_prefix('@') {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(message.as_bytes(), &addr)
+        } else {
+            socket.send_to(message.as_bytes(), path.as_ref())
+        }
+    });
+    if let Err(e) = result {
+        warn!("Failed to notify systemd ({}): {}", message.replace('\n', " "), e);
+    }
+}
+
+/// Reports readiness to systemd and, with `WatchdogSec=`, pings the watchdog
+/// for as long as the shared state stays responsive.
+pub async fn run_systemd_notifier(state: Arc<AppState>) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+
+    // Degraded servers still report ready, the failed checks go in the status line
+    let readiness = check_readiness(&state).await;
+    let failed = readiness.failed_checks();
+    let status = if failed.is_empty() {
+        "Streaming ready".to_string()
+    } else {
+        format!("Degraded: {} failed", failed.join(", "))
+    };
+    info!("Notifying systemd: {}", status);
+    sd_notify(&format!("READY=1\nSTATUS={}", status));
+
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    info!("systemd watchdog enabled, pinging every {:?}", interval);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        // A deadlocked peer map would leave the server unable to accept new peers
+        match tokio::time::timeout(Duration::from_secs(1), state.peers.lock()).await {
+            Ok(_) => sd_notify("WATCHDOG=1"),
+            Err(_) => warn!("Peer state unresponsive, skipping watchdog ping"),
+        }
+    }
+}
+
+// Half of WATCHDOG_USEC, as recommended by sd_watchdog_enabled(3)
+fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok())
+        && pid != std::process::id()
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
@@ -0,0 +1,344 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    Router,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::Deserialize;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::broadcast::{SourceLease, acquire_source};
+use crate::config::AppState;
+use crate::ll_hls::{self, LlOutput};
+use crate::media::{TeeBranch, VideoSource, drop_until_keyframe, find_aac_encoder, tee_media_type};
+use crate::recording::sanitize_file_name;
+
+// How often idle sessions are looked for
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// Time allowed for the first playlist to be written after a session starts
+const PLAYLIST_WAIT_TIMEOUT: Duration = Duration::from_secs(20);
+// Segments kept in the playlist
+const PLAYLIST_LENGTH: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HlsMode {
+    // MPEG-TS segments with H.264 + AAC, playable everywhere
+    Standard,
+    // LL-HLS: 1 s CMAF segments split into 200 ms parts served from memory,
+    // with preload hints and blocking playlist reload
+    LowLatency,
+}
+
+impl HlsMode {
+    // Low latency files are prefixed with "ll" so both modes share /hls/<source>/
+    fn for_file(file: &str) -> Self {
+        if file.starts_with("ll") { Self::LowLatency } else { Self::Standard }
+    }
+
+    // The playlist players request, which starts the output
+    fn playlist(self) -> &'static str {
+        match self {
+            Self::Standard => "index.m3u8",
+            Self::LowLatency => "ll.m3u8",
+        }
+    }
+
+    fn key(self, source: &str) -> String {
+        match self {
+            Self::Standard => source.to_string(),
+            Self::LowLatency => format!("{}/ll", source),
+        }
+    }
+}
+
+// Where a session's playlists and segments are read from
+#[derive(Clone)]
+enum HlsOutput {
+    // Written by hlssink2 into a temporary directory
+    Files(PathBuf),
+    Parts(Arc<LlOutput>),
+}
+
+// A source being segmented into a playlist, kept alive by playlist and segment requests
+pub struct HlsSession {
+    mode: HlsMode,
+    output: HlsOutput,
+    lease: SourceLease,
+    branch: TeeBranch,
+    last_access: Instant,
+}
+
+impl HlsSession {
+    async fn stop(self, state: &AppState) {
+        info!("Stopping {:?} HLS output of {}", self.mode, self.lease.source);
+        self.branch.detach().await;
+        self.lease.release(state).await;
+        if let HlsOutput::Files(dir) = &self.output {
+            let _ = tokio::fs::remove_dir_all(dir).await;
+        }
+    }
+}
+
+// Blocking playlist reload parameters of LL-HLS
+#[derive(Deserialize)]
+struct HlsQuery {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<usize>,
+}
+
+// HLS routes, merged into the main router by run_server
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/hls/{source}/{file}", get(serve_file))
+}
+
+async fn serve_file(
+    State(state): State<Arc<AppState>>,
+    Path((source, file)): Path<(String, String)>,
+    Query(query): Query<HlsQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if file.starts_with('.') || file.contains(['/', '\\']) {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid file name '{}'", file)));
+    }
+    if !VideoSource::is_known(&source, &state.session_type, state.has_portal) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown source '{}'", source)));
+    }
+    let mode = HlsMode::for_file(&file);
+    let is_playlist = file.ends_with(".m3u8");
+
+    // Only a playlist request may start the output; segments just keep it alive
+    let key = mode.key(&source);
+    let output = match touch_session(&state, &key).await {
+        Some(output) => output,
+        None if file != mode.playlist() => {
+            return Err((StatusCode::NOT_FOUND, format!("No HLS output running for {}", source)));
+        }
+        None => {
+            // Starting can wait on a portal dialog, so only this output is held
+            // meanwhile; concurrent requests for it wait here and find it running
+            let _starting = state.hls_starts.lock(&key).await;
+            match touch_session(&state, &key).await {
+                Some(output) => output,
+                None => {
+                    let session = start_session(&state, &source, mode).await.map_err(|e| {
+                        warn!("Failed to start HLS output of {}: {}", source, e);
+                        (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+                    })?;
+                    let output = session.output.clone();
+                    state.hls.lock().await.insert(key, session);
+                    output
+                }
+            }
+        }
+    };
+
+    let body = match output {
+        HlsOutput::Files(dir) => {
+            let path = dir.join(&file);
+            if is_playlist {
+                wait_for_file(&path).await?;
+            }
+            tokio::fs::read(&path)
+                .await
+                .map_err(|_| (StatusCode::NOT_FOUND, format!("{} not found", file)))?
+        }
+        HlsOutput::Parts(output) => output.serve(&file, query.msn, query.part).await?,
+    };
+
+    let content_type = match file.rsplit('.').next() {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("ts") => "video/mp2t",
+        Some("m4s") => "video/iso.segment",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    };
+    // Playlists change with every segment, segments never change
+    let cache_control = if is_playlist { "no-cache" } else { "max-age=60" };
+    Ok((
+        [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, cache_control)],
+        body,
+    ))
+}
+
+// Marks a running output as requested and returns where its files are
+async fn touch_session(state: &AppState, key: &str) -> Option<HlsOutput> {
+    let mut sessions = state.hls.lock().await;
+    let session = sessions.get_mut(key)?;
+    session.last_access = Instant::now();
+    Some(session.output.clone())
+}
+
+// The first playlist only appears once a whole segment has been written
+async fn wait_for_file(path: &std::path::Path) -> Result<(), (StatusCode, String)> {
+    let deadline = Instant::now() + PLAYLIST_WAIT_TIMEOUT;
+    while !tokio::fs::try_exists(path).await.unwrap_or(false) {
+        if Instant::now() > deadline {
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "HLS playlist not ready yet".to_string()));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    Ok(())
+}
+
+async fn start_session(state: &Arc<AppState>, source: &str, mode: HlsMode) -> Result<HlsSession> {
+    let lease = acquire_source(state, source).await?;
+    match attach_segmenter(state, &lease, mode) {
+        Ok((output, branch)) => {
+            info!("Started {:?} HLS output of {}", mode, source);
+            Ok(HlsSession {
+                mode,
+                output,
+                lease,
+                branch,
+                last_access: Instant::now(),
+            })
+        }
+        Err(e) => {
+            lease.release(state).await;
+            Err(e)
+        }
+    }
+}
+
+fn attach_segmenter(state: &AppState, lease: &SourceLease, mode: HlsMode) -> Result<(HlsOutput, TeeBranch)> {
+    let media_type = tee_media_type(&lease.pipeline, "videotee")
+        .ok_or_else(|| anyhow!("Source {} has no video stream", lease.source))?;
+    // Segments can only be cut at keyframes
+    let keyframe_secs = match mode {
+        HlsMode::Standard => state.settings.hls_segment_secs,
+        HlsMode::LowLatency => 1,
+    };
+    // HLS players only take H.264/H.265, other encoders are transcoded
+    let (video_chain, transcoded) = match media_type.as_str() {
+        "video/x-h264" => ("h264parse config-interval=-1".to_string(), false),
+        "video/x-h265" => ("h265parse config-interval=-1".to_string(), false),
+        _ => (
+            format!(
+                "decodebin ! videoconvert ! x264enc tune=zerolatency speed-preset=ultrafast key-int-max={} ! h264parse",
+                keyframe_secs * 30
+            ),
+            true,
+        ),
+    };
+
+    let dir = hls_dir(&lease.source);
+    let file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let target_duration = state.settings.hls_segment_secs;
+
+    let video_queue = "queue name=hlsvideo max-size-buffers=0 max-size-bytes=0 max-size-time=3000000000";
+    let mut description = match mode {
+        HlsMode::Standard => format!(
+            "hlssink2 name=hlssink location=\"{}\" playlist-location=\"{}\" \
+             target-duration={} playlist-length={} max-files={} send-keyframe-requests=true \
+             {} ! {} ! hlssink.video",
+            file("segment%05d.ts"),
+            file(mode.playlist()),
+            target_duration,
+            PLAYLIST_LENGTH,
+            PLAYLIST_LENGTH + 2,
+            video_queue,
+            video_chain
+        ),
+        HlsMode::LowLatency => {
+            if !ll_hls::is_supported() {
+                return Err(anyhow!("LL-HLS requires cmafmux (gst-plugins-rs)"));
+            }
+            format!("{} ! {} ! {}", video_queue, video_chain, ll_hls::sink_str("video"))
+        }
+    };
+
+    // Opus is not playable from HLS, the audio is re-encoded to AAC
+    let aac_encoder = find_aac_encoder();
+    let with_audio = tee_media_type(&lease.pipeline, "audiotee").is_some() && aac_encoder.is_some();
+    if let Some(aac_encoder) = aac_encoder.filter(|_| with_audio) {
+        // CMAF segments carry a single track, so audio gets its own rendition
+        let audio_sink = match mode {
+            HlsMode::Standard => "hlssink.audio".to_string(),
+            HlsMode::LowLatency => ll_hls::sink_str("audio"),
+        };
+        description.push_str(&format!(
+            " queue name=hlsaudio max-size-buffers=0 max-size-bytes=0 max-size-time=3000000000 ! \
+             opusdec ! audioconvert ! audioresample ! {} ! aacparse ! {}",
+            aac_encoder, audio_sink
+        ));
+    }
+    let bin = gst::parse::bin_from_description_with_name(
+        &description,
+        false,
+        &format!("hls-{}", sanitize_file_name(&mode.key(&lease.source))),
+    )?;
+    let mut links = vec![("videotee", "hlsvideo")];
+    if with_audio {
+        links.push(("audiotee", "hlsaudio"));
+    }
+    for (_, queue_name) in &links {
+        let queue = bin
+            .by_name(queue_name)
+            .ok_or_else(|| anyhow!("HLS bin has no {} queue", queue_name))?;
+        let queue_sink = queue.static_pad("sink").ok_or_else(|| anyhow!("queue has no sink pad"))?;
+        bin.add_pad(&gst::GhostPad::builder_with_target(&queue_sink)?.name(*queue_name).build())?;
+        if *queue_name == "hlsvideo" && transcoded {
+            let queue_src = queue.static_pad("src").ok_or_else(|| anyhow!("queue has no src pad"))?;
+            drop_until_keyframe(&queue_src);
+        }
+    }
+
+    let output = match mode {
+        HlsMode::Standard => {
+            std::fs::create_dir_all(&dir)?;
+            HlsOutput::Files(dir)
+        }
+        HlsMode::LowLatency => HlsOutput::Parts(Arc::new(LlOutput::attach(&bin, with_audio)?)),
+    };
+    let branch = TeeBranch::attach_pads(&lease.pipeline, bin, &links)?;
+    Ok((output, branch))
+}
+
+fn hls_dir(source: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("desktop_streamer-hls-{}", std::process::id()))
+        .join(sanitize_file_name(source))
+}
+
+/// Stops HLS outputs nobody has requested for the idle timeout, or whose
+/// source pipeline went away (a WebRTC viewer they were sharing left).
+pub async fn run_idle_reaper(state: Arc<AppState>) {
+    let idle_timeout = Duration::from_secs(state.settings.hls_idle_secs);
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let expired: Vec<HlsSession> = {
+            let mut sessions = state.hls.lock().await;
+            let keys: Vec<String> = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.last_access.elapsed() > idle_timeout
+                        || session.lease.pipeline.current_state() != gst::State::Playing
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter().filter_map(|key| sessions.remove(key)).collect()
+        };
+        for session in expired {
+            session.stop(&state).await;
+        }
+    }
+}
+
+/// Stops every HLS output, on shutdown.
+pub async fn stop_all_sessions(state: &AppState) {
+    let sessions: Vec<HlsSession> = state.hls.lock().await.drain().map(|(_, session)| session).collect();
+    for session in sessions {
+        session.stop(state).await;
+    }
+}
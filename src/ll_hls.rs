@@ -0,0 +1,459 @@
+use axum::http::StatusCode;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use gstreamer_video as gst_video;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::warn;
+
+// Duration of each partial segment, the chunk duration of cmafmux
+const PART_DURATION: Duration = Duration::from_millis(200);
+// Advertised upper bound of part durations, with room for audio frames
+// straddling a chunk boundary
+const PART_TARGET: f64 = 0.25;
+// Segments are cut at the first independent part after this long
+const SEGMENT_TARGET: Duration = Duration::from_secs(1);
+// Complete segments kept (and listed) per rendition
+const RETAINED_SEGMENTS: usize = 10;
+// Recent complete segments whose parts are still listed
+const PART_SEGMENTS: usize = 2;
+// Time allowed for the first segment after the output starts
+const FIRST_SEGMENT_TIMEOUT: Duration = Duration::from_secs(20);
+// Longest a blocking playlist reload or preload hinted part is held, three
+// target durations as the spec recommends
+const BLOCKING_TIMEOUT: Duration = Duration::from_secs(6);
+
+type HlsError = (StatusCode, String);
+
+/// Muxer and sink producing the parts of one rendition, named `ll<rendition>sink`
+/// so `attach` can find it.
+pub fn sink_str(rendition: &str) -> String {
+    format!(
+        "cmafmux fragment-duration={} chunk-duration={} ! appsink name=ll{}sink sync=false buffer-list=true",
+        SEGMENT_TARGET.as_nanos(),
+        PART_DURATION.as_nanos(),
+        rendition
+    )
+}
+
+/// Whether the muxer LL-HLS needs is installed
+pub fn is_supported() -> bool {
+    gst::ElementFactory::find("cmafmux").is_some()
+}
+
+/// Video and optional audio renditions of one LL-HLS output, served from
+/// memory: `ll.m3u8` (multivariant), `ll-<rendition>.m3u8`,
+/// `ll-<rendition>-init.mp4`, `ll-<rendition>-<msn>.m4s` and
+/// `ll-<rendition>-<msn>.<part>.m4s`.
+pub struct LlOutput {
+    video: Arc<LlRendition>,
+    audio: Option<Arc<LlRendition>>,
+}
+
+impl LlOutput {
+    /// Feeds the renditions from the appsinks made by `sink_str` in `bin`.
+    pub fn attach(bin: &gst::Bin, with_audio: bool) -> anyhow::Result<Self> {
+        let output = Self {
+            video: Arc::new(LlRendition::new("video")),
+            audio: with_audio.then(|| Arc::new(LlRendition::new("audio"))),
+        };
+        for rendition in std::iter::once(&output.video).chain(&output.audio) {
+            let sink = bin
+                .by_name(&format!("ll{}sink", rendition.name))
+                .and_then(|sink| sink.downcast::<AppSink>().ok())
+                .ok_or_else(|| anyhow::anyhow!("LL-HLS bin has no {} appsink", rendition.name))?;
+            feed_rendition(&sink, rendition.clone());
+        }
+        Ok(output)
+    }
+
+    /// Answers a request for one of the output files. `msn`/`part` are the
+    /// `_HLS_msn`/`_HLS_part` parameters of blocking playlist reloads.
+    pub async fn serve(&self, file: &str, msn: Option<u64>, part: Option<usize>) -> Result<Vec<u8>, HlsError> {
+        let not_found = || (StatusCode::NOT_FOUND, format!("{} not found", file));
+        let name = file.strip_prefix("ll").ok_or_else(not_found)?;
+        if name == ".m3u8" {
+            return Ok(self.multivariant_playlist().into_bytes());
+        }
+        let (rendition, rest) = name
+            .strip_prefix('-')
+            .and_then(|name| name.split_once(['-', '.']))
+            .ok_or_else(not_found)?;
+        let rendition = std::iter::once(&self.video)
+            .chain(&self.audio)
+            .find(|candidate| candidate.name == rendition)
+            .ok_or_else(not_found)?;
+        if rest == "m3u8" {
+            return rendition.playlist(msn, part).await.map(String::into_bytes);
+        }
+        rendition.file(rest).await.ok_or_else(not_found)
+    }
+
+    fn multivariant_playlist(&self) -> String {
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+        if self.audio.is_some() {
+            playlist.push_str(
+                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"audio\",DEFAULT=YES,AUTOSELECT=YES,URI=\"ll-audio.m3u8\"\n\
+                 #EXT-X-STREAM-INF:BANDWIDTH=5000000,AUDIO=\"audio\"\n",
+            );
+        } else {
+            playlist.push_str("#EXT-X-STREAM-INF:BANDWIDTH=5000000\n");
+        }
+        playlist.push_str("ll-video.m3u8\n");
+        playlist
+    }
+}
+
+// cmafmux pushes one buffer list per chunk, the first one prefixed by the init segment
+fn feed_rendition(sink: &AppSink, rendition: Arc<LlRendition>) {
+    sink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let mut data = Vec::new();
+                let mut append = |buffer: &gst::BufferRef| {
+                    if let Ok(map) = buffer.map_readable() {
+                        data.extend_from_slice(map.as_slice());
+                    }
+                };
+                match (sample.buffer_list(), sample.buffer()) {
+                    (Some(list), _) => list.iter().for_each(&mut append),
+                    (None, Some(buffer)) => append(buffer),
+                    (None, None) => return Err(gst::FlowError::Error),
+                }
+                if rendition.push(&data) {
+                    // Upstream events sent to a sink travel up to the encoder
+                    sink.send_event(gst_video::UpstreamForceKeyUnitEvent::builder().all_headers(true).build());
+                }
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+}
+
+struct Part {
+    duration: f64,
+    independent: bool,
+    data: Vec<u8>,
+}
+
+struct Segment {
+    msn: u64,
+    parts: Vec<Part>,
+    keyframe_requested: bool,
+}
+
+impl Segment {
+    fn new(msn: u64) -> Self {
+        Self { msn, parts: Vec::new(), keyframe_requested: false }
+    }
+
+    fn duration(&self) -> f64 {
+        self.parts.iter().map(|part| part.duration).sum()
+    }
+}
+
+#[derive(Default)]
+struct RenditionState {
+    init: Option<Vec<u8>>,
+    track: Option<TrackDefaults>,
+    segments: VecDeque<Segment>,
+    // Segment being filled; None until the first independent part
+    current: Option<Segment>,
+    // Longest segment so far, the target duration may only grow
+    longest: f64,
+}
+
+struct LlRendition {
+    name: &'static str,
+    state: Mutex<RenditionState>,
+    // (msn of the segment being filled, parts it has), the newest part available
+    position: watch::Sender<(u64, usize)>,
+}
+
+impl LlRendition {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: Mutex::default(),
+            position: watch::Sender::new((0, 0)),
+        }
+    }
+
+    /// Adds a chunk from the muxer, returning whether a keyframe should be
+    /// requested to close the current segment.
+    fn push(&self, data: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (init, media) = split_init(data);
+        if let Some(init) = init {
+            state.track = track_defaults(init);
+            state.init = Some(init.to_vec());
+        }
+        let Some(track) = state.track.filter(|_| !media.is_empty()) else {
+            return false;
+        };
+        let Some((ticks, independent)) = fragment_info(media, &track) else {
+            warn!("Unparsable LL-HLS {} chunk dropped", self.name);
+            return false;
+        };
+        let part = Part {
+            duration: ticks as f64 / track.timescale.max(1) as f64,
+            independent,
+            data: media.to_vec(),
+        };
+
+        let state = &mut *state;
+        match state.current.take() {
+            // Playback has to start on an independent part
+            None if !part.independent => return true,
+            None => state.current = Some(Segment::new(0)),
+            Some(current) if part.independent && current.duration() >= SEGMENT_TARGET.as_secs_f64() => {
+                state.longest = state.longest.max(current.duration());
+                state.current = Some(Segment::new(current.msn + 1));
+                state.segments.push_back(current);
+                if state.segments.len() > RETAINED_SEGMENTS {
+                    state.segments.pop_front();
+                }
+            }
+            current => state.current = current,
+        }
+        let current = state.current.as_mut().expect("segment was just started");
+        current.parts.push(part);
+        self.position.send_replace((current.msn, current.parts.len()));
+
+        let overdue = !independent && current.duration() >= SEGMENT_TARGET.as_secs_f64();
+        overdue && !std::mem::replace(&mut current.keyframe_requested, true)
+    }
+
+    // Blocks until `wanted` (see `position`) is reached, or a timeout
+    async fn wait_for(&self, wanted: (u64, usize), timeout: Duration) -> Result<(), HlsError> {
+        let mut position = self.position.subscribe();
+        match tokio::time::timeout(timeout, position.wait_for(|position| *position >= wanted)).await {
+            Ok(Ok(_)) => Ok(()),
+            _ => Err((StatusCode::SERVICE_UNAVAILABLE, "LL-HLS part not ready yet".to_string())),
+        }
+    }
+
+    async fn playlist(&self, msn: Option<u64>, part: Option<usize>) -> Result<String, HlsError> {
+        let (wanted, timeout) = match (msn, part) {
+            (Some(msn), Some(part)) => ((msn, part + 1), BLOCKING_TIMEOUT),
+            (Some(msn), None) => ((msn + 1, 0), BLOCKING_TIMEOUT),
+            (None, Some(_)) => return Err((StatusCode::BAD_REQUEST, "_HLS_part requires _HLS_msn".to_string())),
+            // Players start once a whole segment is listed
+            (None, None) => ((1, 0), FIRST_SEGMENT_TIMEOUT),
+        };
+        // Requests this far ahead could never be answered in time
+        if msn.is_some_and(|msn| msn > self.position.borrow().0 + 2) {
+            return Err((StatusCode::BAD_REQUEST, "_HLS_msn too far in the future".to_string()));
+        }
+        self.wait_for(wanted, timeout).await?;
+        Ok(self.render_playlist())
+    }
+
+    fn render_playlist(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let prefix = format!("ll-{}", self.name);
+        let first_msn = state
+            .segments
+            .front()
+            .or(state.current.as_ref())
+            .map(|segment| segment.msn)
+            .unwrap_or_default();
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n\
+             #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}\n\
+             #EXT-X-PART-INF:PART-TARGET={:.3}\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXT-X-MAP:URI=\"{}-init.mp4\"\n",
+            state.longest.round().max(2.0),
+            PART_TARGET * 3.0,
+            PART_TARGET,
+            first_msn,
+            prefix
+        );
+        let list_parts = |playlist: &mut String, segment: &Segment| {
+            for (index, part) in segment.parts.iter().enumerate() {
+                playlist.push_str(&format!(
+                    "#EXT-X-PART:DURATION={:.3},URI=\"{}-{}.{}.m4s\"{}\n",
+                    part.duration,
+                    prefix,
+                    segment.msn,
+                    index,
+                    if part.independent { ",INDEPENDENT=YES" } else { "" }
+                ));
+            }
+        };
+        let parts_from = state.segments.len().saturating_sub(PART_SEGMENTS);
+        for (index, segment) in state.segments.iter().enumerate() {
+            if index >= parts_from {
+                list_parts(&mut playlist, segment);
+            }
+            playlist.push_str(&format!("#EXTINF:{:.3},\n{}-{}.m4s\n", segment.duration(), prefix, segment.msn));
+        }
+        if let Some(current) = &state.current {
+            list_parts(&mut playlist, current);
+            playlist.push_str(&format!(
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}-{}.{}.m4s\"\n",
+                prefix,
+                current.msn,
+                current.parts.len()
+            ));
+        }
+        playlist
+    }
+
+    // `init.mp4`, `<msn>.m4s` or `<msn>.<part>.m4s`; parts not written yet
+    // (preload hints) are waited for
+    async fn file(&self, name: &str) -> Option<Vec<u8>> {
+        if name == "init.mp4" {
+            return self.state.lock().unwrap().init.clone();
+        }
+        let (msn, part) = match name.strip_suffix(".m4s")?.split_once('.') {
+            Some((msn, part)) => (msn.parse::<u64>().ok()?, Some(part.parse::<usize>().ok()?)),
+            None => (name.strip_suffix(".m4s")?.parse::<u64>().ok()?, None),
+        };
+        if let Some(part) = part
+            && msn <= self.position.borrow().0 + 1
+        {
+            self.wait_for((msn, part + 1), BLOCKING_TIMEOUT).await.ok()?;
+        }
+
+        let state = self.state.lock().unwrap();
+        let segment = state
+            .segments
+            .iter()
+            .chain(state.current.as_ref())
+            .find(|segment| segment.msn == msn)?;
+        match part {
+            Some(part) => segment.parts.get(part).map(|part| part.data.clone()),
+            // Whole segments are only served once complete
+            None if state.current.as_ref().is_some_and(|current| current.msn == msn) => None,
+            None => Some(segment.parts.iter().flat_map(|part| part.data.iter().copied()).collect()),
+        }
+    }
+}
+
+// ISO BMFF parsing, just enough to time parts and find their independent ones
+
+#[derive(Clone, Copy)]
+struct TrackDefaults {
+    timescale: u32,
+    // trex defaults, used when the fragments do not override them
+    sample_duration: u32,
+    sample_flags: u32,
+}
+
+// Boxes of a buffer as (type, payload)
+struct Boxes<'a>(&'a [u8]);
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.0;
+        let kind: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (header, size) = match read_u32(data, 0)? {
+            0 => (8, data.len()),
+            1 => (16, u64::from_be_bytes(data.get(8..16)?.try_into().ok()?) as usize),
+            size => (8, size as usize),
+        };
+        if size < header || size > data.len() {
+            self.0 = &[];
+            return None;
+        }
+        self.0 = &data[size..];
+        Some((kind, &data[header..size]))
+    }
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    Boxes(data).find(|(found, _)| found == kind).map(|(_, payload)| payload)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// (init segment, media) of a chunk; the init segment is whatever precedes the first fragment
+fn split_init(data: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    let mut offset = 0;
+    let mut rest = Boxes(data);
+    while let Some((kind, _)) = rest.next() {
+        if matches!(&kind, b"moof" | b"styp" | b"sidx" | b"prft") {
+            break;
+        }
+        offset = data.len() - rest.0.len();
+    }
+    let (init, media) = data.split_at(offset);
+    (find_box(init, b"moov").map(|_| init), media)
+}
+
+fn track_defaults(init: &[u8]) -> Option<TrackDefaults> {
+    let moov = find_box(init, b"moov")?;
+    let mdhd = find_box(find_box(find_box(moov, b"trak")?, b"mdia")?, b"mdhd")?;
+    let timescale = if mdhd.first() == Some(&1) { read_u32(mdhd, 20)? } else { read_u32(mdhd, 12)? };
+    let trex = find_box(moov, b"mvex").and_then(|mvex| find_box(mvex, b"trex"));
+    Some(TrackDefaults {
+        timescale,
+        sample_duration: trex.and_then(|trex| read_u32(trex, 12)).unwrap_or_default(),
+        sample_flags: trex.and_then(|trex| read_u32(trex, 20)).unwrap_or_default(),
+    })
+}
+
+// (duration in timescale units, whether the first sample is a sync sample) of the fragments in `media`
+fn fragment_info(media: &[u8], track: &TrackDefaults) -> Option<(u64, bool)> {
+    const NON_SYNC_SAMPLE: u32 = 0x1_0000;
+    let mut duration = 0u64;
+    let mut first_flags = None;
+    for traf in Boxes(media)
+        .filter(|(kind, _)| kind == b"moof")
+        .flat_map(|(_, moof)| Boxes(moof).filter(|(kind, _)| kind == b"traf"))
+        .map(|(_, traf)| traf)
+    {
+        let tfhd = find_box(traf, b"tfhd")?;
+        let tfhd_flags = read_u32(tfhd, 0)? & 0xff_ffff;
+        // Skip version/flags, track id, base data offset and sample description index
+        let mut offset = 8 + if tfhd_flags & 0x01 != 0 { 8 } else { 0 } + if tfhd_flags & 0x02 != 0 { 4 } else { 0 };
+        let mut default_duration = track.sample_duration;
+        if tfhd_flags & 0x08 != 0 {
+            default_duration = read_u32(tfhd, offset)?;
+            offset += 4;
+        }
+        if tfhd_flags & 0x10 != 0 {
+            offset += 4;
+        }
+        let default_flags = if tfhd_flags & 0x20 != 0 { read_u32(tfhd, offset)? } else { track.sample_flags };
+
+        for trun in Boxes(traf).filter(|(kind, _)| kind == b"trun").map(|(_, trun)| trun) {
+            let trun_flags = read_u32(trun, 0)? & 0xff_ffff;
+            let sample_count = read_u32(trun, 4)? as usize;
+            let mut offset = 8 + if trun_flags & 0x01 != 0 { 4 } else { 0 };
+            let first_sample_flags = if trun_flags & 0x04 != 0 {
+                offset += 4;
+                Some(read_u32(trun, offset - 4)?)
+            } else {
+                None
+            };
+            let fields = [0x100, 0x200, 0x400, 0x800].iter().filter(|field| trun_flags & **field != 0).count();
+            for sample in 0..sample_count {
+                let mut field = offset + sample * fields * 4;
+                let sample_duration = if trun_flags & 0x100 != 0 {
+                    field += 4;
+                    read_u32(trun, field - 4)?
+                } else {
+                    default_duration
+                };
+                if trun_flags & 0x200 != 0 {
+                    field += 4;
+                }
+                let sample_flags = if trun_flags & 0x400 != 0 { read_u32(trun, field)? } else { default_flags };
+                duration += sample_duration as u64;
+                if first_flags.is_none() {
+                    first_flags = Some(first_sample_flags.filter(|_| sample == 0).unwrap_or(sample_flags));
+                }
+            }
+        }
+    }
+    Some((duration, first_flags? & NON_SYNC_SAMPLE == 0))
+}
@@ -1,21 +1,41 @@
 use anyhow::Result;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, env};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, env, time::Duration};
 use tokio::sync::Mutex;
 use tokio::signal;
 use tracing::{info, warn, level_filters::LevelFilter};
 use tracing_subscriber::EnvFilter;
 
+mod adaptive_capture;
 mod config;
+mod cursor;
+mod encoder_probe;
 mod media;
 mod server;
 mod webrtc_handler;
 mod virtual_audio;
+mod virtual_camera;
 mod audio_detection;
+mod recording;
+mod snapshot;
+mod whep;
+mod whip;
+mod admin;
+mod api;
+mod broadcast;
+mod health;
+mod hls;
+mod ll_hls;
+mod metrics;
+mod mjpeg;
+mod push;
+mod rtsp;
+mod stats;
 
-use config::AppState;
+use config::{AppState, ServerSettings};
 use media::detect_hardware_capabilities;
 use server::{run_server, check_portal_availability};
 use virtual_audio::VirtualAudioDevice;
+use virtual_camera::VirtualCamera;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -35,8 +55,11 @@ async fn main() -> Result<()> {
     
     info!("Session type: {}, Portal available: {}", session_type, has_portal);
 
+    let settings = ServerSettings::from_env();
+    info!("Server settings: {:?}", settings);
+
     // 4. Detect hardware capabilities at startup
-    let hw_info = detect_hardware_capabilities();
+    let hw_info = detect_hardware_capabilities(false, &[]);
     info!("Detected Hardware Capabilities: {:?}", hw_info);
 
     // 4. Clean up any orphan virtual audio devices first
@@ -46,19 +69,48 @@ async fn main() -> Result<()> {
 
     // 5. Initialize virtual audio device
     let mut virtual_audio = VirtualAudioDevice::new();
+    virtual_audio.routing_mode = settings.system_audio_routing;
     if let Err(e) = virtual_audio.create_virtual_devices() {
         warn!("Failed to create virtual audio devices: {}", e);
     } else {
         info!("Virtual audio devices created successfully");
     }
 
+    // Optional v4l2loopback camera, same lifecycle as the audio devices
+    if let Err(e) = VirtualCamera::remove_all_orphan_devices() {
+        warn!("Failed to clean up orphan virtual cameras: {}", e);
+    }
+    let virtual_camera = if settings.virtual_camera {
+        let mut camera = VirtualCamera::new();
+        match camera.create_virtual_device() {
+            Ok(()) => Some(camera),
+            Err(e) => {
+                warn!("Failed to create virtual camera: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     // 6. Create the shared application state
     let app_state = Arc::new(AppState {
         peers: Mutex::new(HashMap::new()),
-        hw_info: Arc::new(hw_info),
+        signal_senders: Mutex::new(HashMap::new()),
+        hw_info: std::sync::RwLock::new(Arc::new(hw_info)),
         session_type,
         has_portal,
         virtual_audio: Mutex::new(Some(virtual_audio)),
+        virtual_camera: Mutex::new(virtual_camera),
+        settings,
+        ingests: Mutex::new(HashMap::new()),
+        broadcasts: Mutex::new(HashMap::new()),
+        broadcast_starts: Default::default(),
+        hls: Mutex::new(HashMap::new()),
+        hls_starts: Default::default(),
+        mjpeg: Mutex::new(HashMap::new()),
+        mjpeg_starts: Default::default(),
+        pushes: Mutex::new(HashMap::new()),
     });
 
     // Clone app_state for signal handler
@@ -101,6 +153,18 @@ async fn main() -> Result<()> {
         }
     });
 
+    if let Some(port) = app_state.settings.rtsp_port {
+        let rtsp_state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = rtsp::run_rtsp_server(rtsp_state, port).await {
+                warn!("RTSP server stopped: {}", e);
+            }
+        });
+    }
+
+    tokio::spawn(hls::run_idle_reaper(app_state.clone()));
+    tokio::spawn(virtual_camera::run_feed_monitor(app_state.clone()));
+
     // 7. Start the Axum server
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
     info!("🚀 High-Performance Desktop Streamer Enhanced v2.0 starting on http://0.0.0.0:3000");
@@ -111,16 +175,42 @@ async fn main() -> Result<()> {
 }
 
 async fn cleanup_virtual_devices(app_state: &Arc<AppState>) {
+    health::sd_notify("STOPPING=1");
+    // Close peers first so per-peer audio routing is restored before the devices go away
+    let peers: Vec<_> = app_state.peers.lock().await.drain().map(|(_, peer)| peer).collect();
+    for peer in peers {
+        peer.close(app_state).await;
+    }
+    push::stop_all_pushes(app_state).await;
+    hls::stop_all_sessions(app_state).await;
+    broadcast::stop_all_broadcasts(app_state).await;
+
+    match tokio::time::timeout(Duration::from_secs(2), app_state.virtual_camera.lock()).await {
+        Ok(mut camera_guard) => {
+            if let Some(mut camera) = camera_guard.take() {
+                camera.stop_feed(app_state).await;
+                if let Err(e) = camera.remove_virtual_device() {
+                    warn!("Failed to cleanup virtual camera: {}", e);
+                }
+            }
+        }
+        Err(_) => warn!("Could not acquire lock to cleanup virtual camera"),
+    }
+
     info!("Cleaning up virtual audio devices...");
-    if let Ok(mut virtual_audio_guard) = app_state.virtual_audio.try_lock() {
-        if let Some(virtual_device) = virtual_audio_guard.take() {
-            if let Err(e) = virtual_device.remove_virtual_devices() {
-                warn!("Failed to cleanup virtual audio devices: {}", e);
-            } else {
-                info!("Virtual audio devices cleaned up successfully");
+    match tokio::time::timeout(Duration::from_secs(2), app_state.virtual_audio.lock()).await {
+        Ok(mut virtual_audio_guard) => {
+            if let Some(mut virtual_device) = virtual_audio_guard.take() {
+                if let Err(e) = virtual_device.remove_virtual_devices() {
+                    warn!("Failed to cleanup virtual audio devices: {}", e);
+                } else {
+                    info!("Virtual audio devices cleaned up successfully");
+                }
             }
         }
-    } else {
-        warn!("Could not acquire lock to cleanup virtual audio devices");
+        Err(_) => {
+            // The state file lets the next start reclaim the modules
+            warn!("Could not acquire lock to cleanup virtual audio devices");
+        }
     }
 }
@@ -3,79 +3,473 @@ use bytes::Bytes;
 use gstreamer as gst;
 use gstreamer::prelude::*;
 use gstreamer_app::AppSink;
+use gstreamer_video as gst_video;
 use once_cell::sync::Lazy;
+use futures::StreamExt;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tracing::{info, warn};
+use tracing::{debug, error, info, warn};
 use webrtc::{
     rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
     track::track_local::{TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
 };
 
 use crate::config::AppState;
-use crate::config::PipelineConfig;
+use crate::encoder_probe::{EncoderCapability, probe_encoders};
+use crate::config::{AudioProcessingConfig, PipLayout, PipPosition, PipelineConfig, SignalMessage};
+use crate::whip::WHIP_SOURCE_PREFIX;
 
 static NUM_CPUS: Lazy<String> = Lazy::new(|| num_cpus::get().to_string());
 
-#[derive(Debug, Clone)]
+// Upper bound of the `volume` element
+const MAX_AUDIO_GAIN: f64 = 10.0;
+
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct HardwareInfo {
     pub has_nvenc: bool,
     pub has_nvh264enc_basic: bool, // nvh264enc without nvvidconv
     pub has_vaapi: bool,
     pub has_v4l2h264enc: bool,
     pub has_intel_gpu: bool,
+    // Candidate encoders and whether they actually encoded test frames
+    pub encoders: Vec<EncoderCapability>,
+}
+
+impl HardwareInfo {
+    // Encoders that are installed but failed the probe are treated as missing
+    fn encoder_works(&self, element: &str) -> bool {
+        self.encoders.iter().any(|encoder| encoder.element == element && encoder.working)
+    }
 }
 
-pub fn detect_hardware_capabilities() -> HardwareInfo {
+/// Detects hardware encoders, probing them with test frames (probe results are
+/// cached on disk, `refresh_probe` forces a new probe). Encoders in `in_use`
+/// are running and keep their previous result.
+pub fn detect_hardware_capabilities(refresh_probe: bool, in_use: &[EncoderCapability]) -> HardwareInfo {
     info!("Detecting hardware acceleration capabilities...");
 
+    let encoders = probe_encoders(refresh_probe, in_use);
+    let mut hw_info = HardwareInfo {
+        has_nvenc: false,
+        has_nvh264enc_basic: false,
+        has_vaapi: false,
+        has_v4l2h264enc: false,
+        has_intel_gpu: has_intel_render_node(),
+        encoders,
+    };
+
     // Check for individual NVIDIA elements
-    let has_nvh264enc = gst::ElementFactory::find("nvh264enc").is_some();
+    let has_nvh264enc = hw_info.encoder_works("nvh264enc");
     let has_nvvidconv = gst::ElementFactory::find("nvvidconv").is_some();
-    let has_nvenc = has_nvh264enc && has_nvvidconv;
-    
-    // Check for VAAPI elements
-    let has_vaapi = gst::ElementFactory::find("vaapivp8enc").is_some()
-        || gst::ElementFactory::find("vaapih264enc").is_some();
-    let has_v4l2h264enc = gst::ElementFactory::find("v4l2h264enc").is_some();
-    let has_intel_gpu = std::path::Path::new("/dev/dri/renderD128").exists();
+    hw_info.has_nvenc = has_nvh264enc && has_nvvidconv;
+    hw_info.has_nvh264enc_basic = has_nvh264enc;
+
+    // VAAPI variants encode VP8
+    hw_info.has_vaapi = hw_info.encoder_works("vaapivp8enc");
+    hw_info.has_v4l2h264enc = hw_info.encoder_works("v4l2h264enc");
 
     info!("Hardware detection results:");
     info!("  NVIDIA NVENC elements:");
     info!("    nvh264enc: {}", has_nvh264enc);
     info!("    nvvidconv: {}", has_nvvidconv);
-    info!("    Complete NVENC support: {}", has_nvenc);
+    info!("    Complete NVENC support: {}", hw_info.has_nvenc);
     
     // Additional diagnostic information
     if has_nvh264enc && !has_nvvidconv {
         warn!("NVIDIA GPU detected but nvvidconv is missing. This usually means gstreamer-plugins-bad with NVIDIA support is not installed.");
         info!("Try installing: gstreamer1.0-plugins-bad or gst-plugins-bad (depending on your distribution)");
+    } else if gst::ElementFactory::find("nvh264enc").is_some() && !has_nvh264enc {
+        warn!("nvh264enc is installed but failed to encode test frames (driver or device missing?)");
     } else if !has_nvh264enc {
         info!("No NVIDIA hardware encoding support detected. This is normal if you don't have an NVIDIA GPU or the drivers aren't installed.");
     }
     
-    info!("  VAAPI: {}", has_vaapi);
-    info!("  V4L2 H264: {}", has_v4l2h264enc);
-    info!("  Intel GPU: {}", has_intel_gpu);
+    info!("  VAAPI: {}", hw_info.has_vaapi);
+    info!("  V4L2 H264: {}", hw_info.has_v4l2h264enc);
+    info!("  Intel GPU: {}", hw_info.has_intel_gpu);
+
+    hw_info
+}
+
+// Any DRM render node driven by an Intel GPU (PCI vendor 0x8086), as QSV needs
+fn has_intel_render_node() -> bool {
+    std::fs::read_dir("/sys/class/drm")
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok()).any(|entry| {
+                entry.file_name().to_string_lossy().starts_with("renderD")
+                    && std::fs::read_to_string(entry.path().join("device/vendor"))
+                        .is_ok_and(|vendor| vendor.trim() == "0x8086")
+            })
+        })
+        .unwrap_or(false)
+}
+
+// Video codec of a pipeline variant, which sets the codec of the WebRTC track
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoCodec {
+    Vp8,
+    H264,
+    H265,
+    Av1,
+}
+
+impl VideoCodec {
+    fn mime_type(self) -> &'static str {
+        match self {
+            VideoCodec::Vp8 => "video/VP8",
+            VideoCodec::H264 => "video/H264",
+            VideoCodec::H265 => "video/H265",
+            VideoCodec::Av1 => "video/AV1",
+        }
+    }
+
+    // Encoding name in SDP rtpmap lines
+    fn sdp_name(self) -> &'static str {
+        self.mime_type().trim_start_matches("video/")
+    }
+
+    fn payloader(self) -> &'static str {
+        match self {
+            VideoCodec::Vp8 => "rtpvp8pay pt=96 mtu=1200",
+            VideoCodec::H264 => "rtph264pay pt=96 mtu=1200",
+            VideoCodec::H265 => "rtph265pay pt=96 mtu=1200",
+            VideoCodec::Av1 => "rtpav1pay pt=96 mtu=1200",
+        }
+    }
+}
+
+// Hardware encoders of the current GStreamer plugins (va, nvcodec, qsv, v4l2codecs),
+// each with its own upload and low-latency tuning. H.264 comes first since every
+// client decodes it; H.265 and AV1 are used when the client only accepts those.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HwEncoder {
+    NvH264,
+    VaH264,
+    QsvH264,
+    V4l2H264,
+    NvH265,
+    VaH265,
+    NvAv1,
+    VaAv1,
+}
+
+impl HwEncoder {
+    const ALL: [HwEncoder; 8] = [
+        HwEncoder::NvH264,
+        HwEncoder::VaH264,
+        HwEncoder::QsvH264,
+        HwEncoder::V4l2H264,
+        HwEncoder::NvH265,
+        HwEncoder::VaH265,
+        HwEncoder::NvAv1,
+        HwEncoder::VaAv1,
+    ];
+
+    fn element(self) -> &'static str {
+        match self {
+            HwEncoder::NvH264 => "nvh264enc",
+            HwEncoder::VaH264 => "vah264enc",
+            HwEncoder::QsvH264 => "qsvh264enc",
+            HwEncoder::V4l2H264 => "v4l2h264enc",
+            HwEncoder::NvH265 => "nvh265enc",
+            HwEncoder::VaH265 => "vah265enc",
+            HwEncoder::NvAv1 => "nvav1enc",
+            HwEncoder::VaAv1 => "vaav1enc",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            HwEncoder::NvH264 => "NVIDIA NVENC H.264 (CUDA)",
+            HwEncoder::VaH264 => "VA H.264",
+            HwEncoder::QsvH264 => "Intel QSV H.264",
+            HwEncoder::V4l2H264 => "V4L2 M2M H.264",
+            HwEncoder::NvH265 => "NVIDIA NVENC H.265 (CUDA)",
+            HwEncoder::VaH265 => "VA H.265",
+            HwEncoder::NvAv1 => "NVIDIA NVENC AV1 (CUDA)",
+            HwEncoder::VaAv1 => "VA AV1",
+        }
+    }
+
+    fn codec(self) -> VideoCodec {
+        match self {
+            HwEncoder::NvH264 | HwEncoder::VaH264 | HwEncoder::QsvH264 | HwEncoder::V4l2H264 => VideoCodec::H264,
+            HwEncoder::NvH265 | HwEncoder::VaH265 => VideoCodec::H265,
+            HwEncoder::NvAv1 | HwEncoder::VaAv1 => VideoCodec::Av1,
+        }
+    }
+
+    // Elements besides the encoder that the variant needs
+    fn required_elements(self) -> &'static [&'static str] {
+        match self {
+            HwEncoder::NvH264 | HwEncoder::NvH265 => &["cudaupload", "cudaconvert"],
+            HwEncoder::NvAv1 => &["cudaupload", "cudaconvert", "av1parse", "rtpav1pay"],
+            HwEncoder::VaH264 | HwEncoder::VaH265 => &["vapostproc"],
+            HwEncoder::VaAv1 => &["vapostproc", "av1parse", "rtpav1pay"],
+            HwEncoder::QsvH264 | HwEncoder::V4l2H264 => &[],
+        }
+    }
+
+    fn is_available(self, hw_info: &HardwareInfo) -> bool {
+        let usable = match self {
+            HwEncoder::QsvH264 => hw_info.has_intel_gpu,
+            HwEncoder::V4l2H264 => hw_info.has_v4l2h264enc,
+            _ => true,
+        };
+        usable
+            && hw_info.encoder_works(self.element())
+            && self
+                .required_elements()
+                .iter()
+                .all(|element| gst::ElementFactory::find(element).is_some())
+    }
+
+    // GPU import of DMA-BUF frames, only for encoders that keep them on the GPU
+    fn supports_dmabuf(self) -> bool {
+        matches!(
+            self,
+            HwEncoder::NvH264 | HwEncoder::NvH265 | HwEncoder::NvAv1 | HwEncoder::VaH264 | HwEncoder::VaH265 | HwEncoder::VaAv1
+        )
+    }
+
+    /// Upload, encoder and parser, from raw video to the encoded stream
+    fn build_encode_str(self, bitrate_kbps: u32, capture: CapturePath) -> String {
+        format!("{} ! {}", self.upload_str(capture), self.encoder_str(bitrate_kbps))
+    }
+
+    fn upload_str(self, capture: CapturePath) -> &'static str {
+        match (self, capture) {
+            // vapostproc imports DMA-BUF and system memory alike
+            (HwEncoder::VaH264 | HwEncoder::VaH265 | HwEncoder::VaAv1, _) => {
+                "vapostproc ! 'video/x-raw(memory:VAMemory),format=NV12'"
+            }
+            // CUDA has no DMA-BUF import, frames go through a GL texture instead
+            (HwEncoder::NvH264 | HwEncoder::NvH265 | HwEncoder::NvAv1, CapturePath::DmaBuf) => {
+                "glupload ! glcolorconvert ! 'video/x-raw(memory:GLMemory),format=NV12' ! \
+                 cudaupload ! 'video/x-raw(memory:CUDAMemory),format=NV12'"
+            }
+            (HwEncoder::NvH264 | HwEncoder::NvH265 | HwEncoder::NvAv1, CapturePath::SystemMemory) => {
+                "cudaupload ! cudaconvert ! 'video/x-raw(memory:CUDAMemory),format=NV12'"
+            }
+            (HwEncoder::QsvH264 | HwEncoder::V4l2H264, _) => "videoconvert ! video/x-raw,format=NV12",
+        }
+    }
+
+    fn encoder_str(self, bitrate_kbps: u32) -> String {
+        match self {
+            HwEncoder::NvH264 | HwEncoder::NvH265 => format!(
+                "{} preset=low-latency-hq rc-mode=cbr zerolatency=true bframes=0 gop-size=60 bitrate={} ! {}",
+                self.element(),
+                bitrate_kbps,
+                self.parser()
+            ),
+            HwEncoder::NvAv1 => format!(
+                "nvav1enc preset=p1 tune=ultra-low-latency rc-mode=cbr gop-size=60 bitrate={} ! av1parse",
+                bitrate_kbps
+            ),
+            HwEncoder::VaH264 | HwEncoder::VaH265 => format!(
+                "{} rate-control=cbr bitrate={} key-int-max=60 b-frames=0 target-usage=7 ! {}",
+                self.element(),
+                bitrate_kbps,
+                self.parser()
+            ),
+            HwEncoder::VaAv1 => format!(
+                "vaav1enc rate-control=cbr bitrate={} key-int-max=60 target-usage=7 ! av1parse",
+                bitrate_kbps
+            ),
+            HwEncoder::QsvH264 => format!(
+                "qsvh264enc rate-control=cbr bitrate={} gop-size=60 b-frames=0 target-usage=7 ! {}",
+                bitrate_kbps,
+                self.parser()
+            ),
+            // Stateful M2M encoders (e.g. Raspberry Pi) take their tuning as V4L2 controls
+            HwEncoder::V4l2H264 => format!(
+                "v4l2h264enc extra-controls=\"controls,video_bitrate={},h264_i_frame_period=60,repeat_sequence_header=1\" ! \
+                 'video/x-h264,level=(string)4' ! {}",
+                bitrate_kbps * 1000,
+                self.parser()
+            ),
+        }
+    }
+
+    fn parser(self) -> &'static str {
+        match self.codec() {
+            VideoCodec::H265 => "h265parse config-interval=-1",
+            VideoCodec::Av1 => "av1parse",
+            _ => "h264parse config-interval=-1",
+        }
+    }
+}
+
+// Suffix of the variant names whose frames stay on the GPU from capture to encoder
+const DMABUF_VARIANT_SUFFIX: &str = " (DMA-BUF)";
+// Caps requested from pipewiresrc by the zero-copy variants
+const DMABUF_CAPS: &str = "'video/x-raw(memory:DMABuf)'";
+// How long a zero-copy variant may take to negotiate its first frame
+const DMABUF_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How captured frames reach the encoder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CapturePath {
+    // DMA-BUF/DRM-modifier buffers imported by VA or CUDA, no CPU copy
+    DmaBuf,
+    // Downloaded to system memory and uploaded again by the encoder
+    SystemMemory,
+}
+
+impl CapturePath {
+    pub fn of_variant(variant: &str) -> Self {
+        if variant.ends_with(DMABUF_VARIANT_SUFFIX) {
+            CapturePath::DmaBuf
+        } else {
+            CapturePath::SystemMemory
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CapturePath::DmaBuf => "dmabuf",
+            CapturePath::SystemMemory => "system-memory",
+        }
+    }
+}
+
+/// Prefix of the picture-in-picture source ids, e.g. "pip-x11-0+camera-0"
+pub const PIP_SOURCE_PREFIX: &str = "pip-";
+/// Source id of the test pattern, also streamed to WebRTC viewers asking for an unknown source
+pub const TEST_SOURCE: &str = "test";
+// Output size of picture-in-picture sources, the screen is scaled to fit
+const PIP_CANVAS: (i32, i32) = (1920, 1080);
+
+// Capture source selected from a `source_type` id
+#[derive(Debug, Clone, PartialEq)]
+pub enum VideoSource {
+    WaylandPortal(u32), // PipeWire node id
+    Camera(String),     // /dev/videoN number
+    X11(i32),           // screen number
+    Remote(String),     // inter-pipeline channel of a WHIP stream
+    // Camera composited over a screen, placed by `set_pip_layout`
+    Composite { screen: Box<VideoSource>, camera: String },
+    Test,
+}
+
+impl VideoSource {
+    /// Maps a source id to its capture source. Returns None for unknown ids
+    /// and for a portal source that has no PipeWire node yet.
+    pub fn select(
+        source_type: &str,
+        pipewire_node_id: Option<u32>,
+        session_type: &str,
+        has_portal: bool,
+    ) -> Option<Self> {
+        match source_type {
+            "wayland-portal" if session_type == "wayland" && has_portal => {
+                pipewire_node_id.map(VideoSource::WaylandPortal)
+            }
+            s if s.starts_with("camera-") => Some(VideoSource::Camera(
+                s.strip_prefix("camera-").unwrap_or("0").to_string(),
+            )),
+            s if s.starts_with("x11-") => Some(VideoSource::X11(
+                s.strip_prefix("x11-")
+                    .and_then(|n| n.parse::<i32>().ok())
+                    .unwrap_or(0),
+            )),
+            s if s.starts_with(WHIP_SOURCE_PREFIX) => Some(VideoSource::Remote(s.to_string())),
+            s if let Some((screen, camera)) = s.strip_prefix(PIP_SOURCE_PREFIX).and_then(|s| s.split_once('+'))
+                && !screen.starts_with(PIP_SOURCE_PREFIX)
+                && camera.starts_with("camera-") =>
+            {
+                Some(VideoSource::Composite {
+                    screen: Box::new(Self::select(screen, pipewire_node_id, session_type, has_portal)?),
+                    camera: camera.strip_prefix("camera-").unwrap_or("0").to_string(),
+                })
+            }
+            TEST_SOURCE => Some(VideoSource::Test),
+            _ => None,
+        }
+    }
+
+    /// Whether a source id names something this server can capture; the
+    /// PipeWire node of portal sources is only obtained once capturing starts
+    pub fn is_known(source_type: &str, session_type: &str, has_portal: bool) -> bool {
+        Self::select(source_type, Some(0), session_type, has_portal).is_some()
+    }
 
-    HardwareInfo {
-        has_nvenc,
-        has_nvh264enc_basic: has_nvh264enc,
-        has_vaapi,
-        has_v4l2h264enc,
-        has_intel_gpu,
+    /// Whether a source id captures through the screencast portal
+    pub fn uses_portal(source_type: &str) -> bool {
+        source_type == "wayland-portal"
+            || source_type
+                .strip_prefix(PIP_SOURCE_PREFIX)
+                .is_some_and(|s| s.starts_with("wayland-portal+"))
+    }
+
+    /// Prefix of the pipeline variant names
+    fn label(&self) -> &'static str {
+        match self {
+            VideoSource::WaylandPortal(_) => "Wayland Portal",
+            VideoSource::Camera(_) => "Camera",
+            VideoSource::X11(_) => "X11",
+            VideoSource::Remote(_) => "WHIP Stream",
+            VideoSource::Composite { .. } => "Picture-in-Picture",
+            VideoSource::Test => "Test Pattern",
+        }
+    }
+
+    /// Raw video capture part of the pipeline, as used by the encoder builders
+    pub fn build_raw_str(&self) -> String {
+        match self {
+            VideoSource::WaylandPortal(node_id) => {
+                format!("pipewiresrc path={} do-timestamp=true", node_id)
+            }
+            VideoSource::Camera(device_num) => format!(
+                "v4l2src device=/dev/video{} ! video/x-raw,width=1280,height=720,framerate=30/1",
+                device_num
+            ),
+            VideoSource::X11(screen_num) => format!(
+                "ximagesrc display-name=:0 screen-num={} show-pointer=true use-damage=false ! \
+                 video/x-raw,framerate=30/1",
+                screen_num
+            ),
+            VideoSource::Remote(channel) => {
+                format!("intervideosrc channel={} ! video/x-raw,framerate=30/1", channel)
+            }
+            // Layout elements start empty, set_pip_layout fills them before PLAYING
+            VideoSource::Composite { screen, camera } => format!(
+                "{} ! videoconvert ! videoscale ! video/x-raw,width={w},height={h} ! \
+                 queue max-size-buffers=2 leaky=downstream ! pipmix.sink_0 \
+                 {} ! videoconvert ! videoscale ! capsfilter name=pipcaps ! \
+                 videobox name=pipborder fill=white ! queue max-size-buffers=2 leaky=downstream ! pipmix.sink_1 \
+                 compositor name=pipmix background=black sink_0::zorder=0 sink_1::zorder=1 ! \
+                 video/x-raw,width={w},height={h},framerate=30/1",
+                screen.build_raw_str(),
+                VideoSource::Camera(camera.clone()).build_raw_str(),
+                w = PIP_CANVAS.0,
+                h = PIP_CANVAS.1
+            ),
+            VideoSource::Test => {
+                "videotestsrc pattern=smpte ! video/x-raw,width=1920,height=1080,framerate=30/1".to_string()
+            }
+        }
     }
 }
 
 pub struct PipelineFactory {
     // Agora sem dispositivo virtual local - será usado o compartilhado no AppState
+    // Name of the created pipelines (`peer-<id>`, `broadcast-<source>`), labelling
+    // their appsink metrics
+    pipeline_name: Option<String>,
 }
 
 impl PipelineFactory {
     pub fn new() -> Self {
-        Self {
-            // Sem campos locais
-        }
+        Self { pipeline_name: None }
+    }
+
+    pub fn with_pipeline_name(mut self, name: impl Into<String>) -> Self {
+        self.pipeline_name = Some(name.into());
+        self
     }
 
     pub async fn create_pipeline(
@@ -86,21 +480,10 @@ impl PipelineFactory {
         session_type: &str,
         has_portal: bool,
         app_state: &AppState,
-    ) -> Result<(gst::Pipeline, Vec<Arc<TrackLocalStaticRTP>>)> {
-        // Create WebRTC tracks
-        let (video_track, video_sender) =
-            Self::create_webrtc_track("video", "desktop-video", "video/VP8")?;
-        let mut tracks = vec![video_track];
-
-        // Create audio track if enabled
-        let audio_sender = if config.enable_audio {
-            let (audio_track, audio_sender) =
-                Self::create_webrtc_track("audio", "desktop-audio", "audio/opus")?;
-            tracks.push(audio_track);
-            Some(audio_sender)
-        } else {
-            None
-        };
+    ) -> Result<(gst::Pipeline, Vec<Arc<TrackLocalStaticRTP>>, String)> {
+        // Tracks are created once a variant started, the video one with its codec
+        let (video_sender, mut video_receiver) = mpsc::unbounded_channel::<Bytes>();
+        let audio_channel = config.enable_audio.then(mpsc::unbounded_channel::<Bytes>);
 
         // Try different pipeline configurations with fallback
         let pipeline_configurations = self.get_pipeline_configurations(
@@ -112,29 +495,48 @@ impl PipelineFactory {
             app_state,
         );
 
-        for (desc, pipeline_str) in pipeline_configurations {
+        for (desc, codec, pipeline_str) in pipeline_configurations {
             info!("Attempting to create pipeline: {}", desc);
             info!("Pipeline string: {}", pipeline_str);
 
             match self.try_create_pipeline(
                 &pipeline_str,
+                config,
                 video_sender.clone(),
-                audio_sender.clone(),
+                audio_channel.as_ref().map(|(sender, _)| sender.clone()),
             ) {
                 Ok(pipeline) => {
                     info!("Successfully created pipeline: {}", desc);
-                    return Ok((pipeline, tracks));
+                    // Drop samples a failed variant may have produced in another codec
+                    while video_receiver.try_recv().is_ok() {}
+                    let mut tracks = vec![Self::create_webrtc_track(
+                        "video",
+                        "desktop-video",
+                        codec.mime_type(),
+                        video_receiver,
+                    )];
+                    if let Some((_, audio_receiver)) = audio_channel {
+                        tracks.push(Self::create_webrtc_track(
+                            "audio",
+                            "desktop-audio",
+                            "audio/opus",
+                            audio_receiver,
+                        ));
+                    }
+                    return Ok((pipeline, tracks, desc));
                 }
                 Err(e) => {
                     warn!(
                         "Failed to create pipeline '{}': {}. Trying fallback...",
                         desc, e
                     );
+                    crate::metrics::record_pipeline_failure(pipeline_failure_reason(&e));
                     continue;
                 }
             }
         }
 
+        crate::metrics::record_pipeline_failure("no_working_variant");
         Err(anyhow!("All pipeline configurations failed"))
     }
 
@@ -146,63 +548,95 @@ impl PipelineFactory {
         session_type: &str,
         has_portal: bool,
         app_state: &AppState,
-    ) -> Vec<(String, String)> {
+    ) -> Vec<(String, VideoCodec, String)> {
         let mut configurations = Vec::new();
+        let video_source = VideoSource::select(&config.source_type, pipewire_node_id, session_type, has_portal);
 
-        match config.source_type.as_str() {
-            "wayland-portal" if session_type == "wayland" && has_portal => {
-                if let Some(node_id) = pipewire_node_id {
-                    // Try hardware acceleration first, then software
-                    if config.use_hardware_encoding && hw_info.has_nvenc {
-                        configurations.push((
-                            "Wayland Portal + NVIDIA NVENC (Full)".to_string(),
-                            self.build_combined_pipeline_str(
-                                &self.build_wayland_nvenc_pipeline_str(node_id),
-                                &self.build_audio_pipeline_str(config, app_state),
-                            ),
-                        ));
-                    }
-                    // Fallback to basic NVIDIA if nvh264enc is available but nvvidconv is not
-                    if config.use_hardware_encoding && hw_info.has_nvh264enc_basic {
-                        configurations.push((
-                            "Wayland Portal + NVIDIA NVENC (Basic)".to_string(),
-                            self.build_combined_pipeline_str(
-                                &self.build_wayland_nvenc_basic_pipeline_str(node_id),
-                                &self.build_audio_pipeline_str(config, app_state),
-                            ),
-                        ));
-                        // Ultra-minimal fallback for limited nvh264enc implementations
-                        configurations.push((
-                            "Wayland Portal + NVIDIA NVENC (Minimal)".to_string(),
-                            self.build_combined_pipeline_str(
-                                &self.build_wayland_nvenc_minimal_pipeline_str(node_id),
-                                &self.build_audio_pipeline_str(config, app_state),
-                            ),
-                        ));
-                    }
-                    if config.use_hardware_encoding && hw_info.has_vaapi {
-                        configurations.push((
-                            "Wayland Portal + VAAPI".to_string(),
-                            self.build_combined_pipeline_str(
-                                &self.build_wayland_vaapi_pipeline_str(node_id),
-                                &self.build_audio_pipeline_str(config, app_state),
-                            ),
-                        ));
-                    }
+        // Current hardware encoders first, then the legacy variants below
+        if config.use_hardware_encoding
+            && let Some(source) = video_source.as_ref().filter(|source| **source != VideoSource::Test)
+        {
+            for encoder in HwEncoder::ALL.into_iter().filter(|encoder| encoder.is_available(hw_info)) {
+                // Zero-copy from the portal stream first, falling back to system memory
+                if matches!(source, VideoSource::WaylandPortal(_)) && encoder.supports_dmabuf() {
                     configurations.push((
-                        "Wayland Portal + Software VP8".to_string(),
+                        format!("{} + {}{}", source.label(), encoder.label(), DMABUF_VARIANT_SUFFIX),
+                        encoder.codec(),
                         self.build_combined_pipeline_str(
-                            &self.build_wayland_software_pipeline_str(node_id),
+                            &self.build_hw_encoder_pipeline_str(source, encoder, CapturePath::DmaBuf),
                             &self.build_audio_pipeline_str(config, app_state),
                         ),
                     ));
                 }
+                configurations.push((
+                    format!("{} + {}", source.label(), encoder.label()),
+                    encoder.codec(),
+                    self.build_combined_pipeline_str(
+                        &self.build_hw_encoder_pipeline_str(source, encoder, CapturePath::SystemMemory),
+                        &self.build_audio_pipeline_str(config, app_state),
+                    ),
+                ));
             }
-            s if s.starts_with("camera-") => {
-                let device_num = s.strip_prefix("camera-").unwrap_or("0");
+        }
+
+        match video_source {
+            Some(VideoSource::WaylandPortal(node_id)) => {
+                // Try hardware acceleration first, then software
+                if config.use_hardware_encoding && hw_info.has_nvenc {
+                    configurations.push((
+                        "Wayland Portal + NVIDIA NVENC (Full)".to_string(),
+                        VideoCodec::H264,
+                        self.build_combined_pipeline_str(
+                            &self.build_wayland_nvenc_pipeline_str(node_id),
+                            &self.build_audio_pipeline_str(config, app_state),
+                        ),
+                    ));
+                }
+                // Fallback to basic NVIDIA if nvh264enc is available but nvvidconv is not
+                if config.use_hardware_encoding && hw_info.has_nvh264enc_basic {
+                    configurations.push((
+                        "Wayland Portal + NVIDIA NVENC (Basic)".to_string(),
+                        VideoCodec::H264,
+                        self.build_combined_pipeline_str(
+                            &self.build_wayland_nvenc_basic_pipeline_str(node_id),
+                            &self.build_audio_pipeline_str(config, app_state),
+                        ),
+                    ));
+                    // Ultra-minimal fallback for limited nvh264enc implementations
+                    configurations.push((
+                        "Wayland Portal + NVIDIA NVENC (Minimal)".to_string(),
+                        VideoCodec::H264,
+                        self.build_combined_pipeline_str(
+                            &self.build_wayland_nvenc_minimal_pipeline_str(node_id),
+                            &self.build_audio_pipeline_str(config, app_state),
+                        ),
+                    ));
+                }
+                if config.use_hardware_encoding && hw_info.has_vaapi {
+                    configurations.push((
+                        "Wayland Portal + VAAPI".to_string(),
+                        VideoCodec::Vp8,
+                        self.build_combined_pipeline_str(
+                            &self.build_wayland_vaapi_pipeline_str(node_id),
+                            &self.build_audio_pipeline_str(config, app_state),
+                        ),
+                    ));
+                }
+                configurations.push((
+                    "Wayland Portal + Software VP8".to_string(),
+                    VideoCodec::Vp8,
+                    self.build_combined_pipeline_str(
+                        &self.build_wayland_software_pipeline_str(node_id),
+                        &self.build_audio_pipeline_str(config, app_state),
+                    ),
+                ));
+            }
+            Some(VideoSource::Camera(device_num)) => {
+                let device_num = device_num.as_str();
                 if config.use_hardware_encoding && hw_info.has_nvenc {
                     configurations.push((
                         "Camera + NVIDIA NVENC (Full)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_camera_nvenc_pipeline_str(device_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -213,6 +647,7 @@ impl PipelineFactory {
                 if config.use_hardware_encoding && hw_info.has_nvh264enc_basic {
                     configurations.push((
                         "Camera + NVIDIA NVENC (Basic)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_camera_nvenc_basic_pipeline_str(device_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -221,6 +656,7 @@ impl PipelineFactory {
                     // Ultra-minimal fallback for limited nvh264enc implementations
                     configurations.push((
                         "Camera + NVIDIA NVENC (Minimal)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_camera_nvenc_minimal_pipeline_str(device_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -230,6 +666,7 @@ impl PipelineFactory {
                 if config.use_hardware_encoding && hw_info.has_vaapi {
                     configurations.push((
                         "Camera + VAAPI".to_string(),
+                        VideoCodec::Vp8,
                         self.build_combined_pipeline_str(
                             &self.build_camera_vaapi_pipeline_str(device_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -238,21 +675,18 @@ impl PipelineFactory {
                 }
                 configurations.push((
                     "Camera + Software VP8".to_string(),
+                    VideoCodec::Vp8,
                     self.build_combined_pipeline_str(
                         &self.build_camera_software_pipeline_str(device_num),
                         &self.build_audio_pipeline_str(config, app_state),
                     ),
                 ));
             }
-            s if s.starts_with("x11-") => {
-                let screen_num = s
-                    .strip_prefix("x11-")
-                    .and_then(|n| n.parse::<i32>().ok())
-                    .unwrap_or(0);
-
+            Some(VideoSource::X11(screen_num)) => {
                 if config.use_hardware_encoding && hw_info.has_nvenc {
                     configurations.push((
                         "X11 + NVIDIA NVENC (Full)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_x11_nvenc_pipeline_str(screen_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -263,6 +697,7 @@ impl PipelineFactory {
                 if config.use_hardware_encoding && hw_info.has_nvh264enc_basic {
                     configurations.push((
                         "X11 + NVIDIA NVENC (Basic)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_x11_nvenc_basic_pipeline_str(screen_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -271,6 +706,7 @@ impl PipelineFactory {
                     // Ultra-minimal fallback for limited nvh264enc implementations
                     configurations.push((
                         "X11 + NVIDIA NVENC (Minimal)".to_string(),
+                        VideoCodec::H264,
                         self.build_combined_pipeline_str(
                             &self.build_x11_nvenc_minimal_pipeline_str(screen_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -280,6 +716,7 @@ impl PipelineFactory {
                 if config.use_hardware_encoding && hw_info.has_vaapi {
                     configurations.push((
                         "X11 + VAAPI".to_string(),
+                        VideoCodec::Vp8,
                         self.build_combined_pipeline_str(
                             &self.build_x11_vaapi_pipeline_str(screen_num),
                             &self.build_audio_pipeline_str(config, app_state),
@@ -288,29 +725,135 @@ impl PipelineFactory {
                 }
                 configurations.push((
                     "X11 + Software VP8".to_string(),
+                    VideoCodec::Vp8,
                     self.build_combined_pipeline_str(
                         &self.build_x11_software_pipeline_str(screen_num),
                         &self.build_audio_pipeline_str(config, app_state),
                     ),
                 ));
             }
-            _ => {
+            Some(VideoSource::Remote(channel)) => {
+                // Already decoded, re-encoded in software like the test pattern
+                configurations.push((
+                    "WHIP Stream + Software VP8".to_string(),
+                    VideoCodec::Vp8,
+                    self.build_combined_pipeline_str(
+                        &self.build_remote_software_pipeline_str(&channel),
+                        &self.build_audio_pipeline_str(config, app_state),
+                    ),
+                ));
+            }
+            Some(source @ VideoSource::Composite { .. }) => {
+                // Hardware encoders were added above; mixing happens before the single encoder
+                configurations.push((
+                    "Picture-in-Picture + Software VP8".to_string(),
+                    VideoCodec::Vp8,
+                    self.build_combined_pipeline_str(
+                        &self.build_composite_software_pipeline_str(&source),
+                        &self.build_audio_pipeline_str(config, app_state),
+                    ),
+                ));
+            }
+            Some(VideoSource::Test) => {
                 configurations.push((
                     "Test Pattern".to_string(),
+                    VideoCodec::Vp8,
                     self.build_combined_pipeline_str(
                         &self.build_test_pipeline_str(),
                         &self.build_audio_pipeline_str(config, app_state),
                     ),
                 ));
             }
+            // Portal source without a PipeWire node
+            None => {}
         }
 
+        configurations.retain(|(_, codec, _)| config.accepts_video_codec(codec.sdp_name()));
         configurations
     }
 
+    /// Video-only variants for the readiness dry run, in fallback order: those
+    /// of a viewer of the first X11 screen, or on other sessions (portal capture
+    /// needs the user to pick a screen) the same encoders fed a test pattern.
+    pub fn dry_run_configurations(
+        &self,
+        hw_info: &HardwareInfo,
+        session_type: &str,
+        app_state: &AppState,
+    ) -> Vec<(String, String)> {
+        if session_type == "x11" {
+            let config = PipelineConfig::default();
+            return self
+                .get_pipeline_configurations(&config, hw_info, None, session_type, false, app_state)
+                .into_iter()
+                .map(|(desc, _, pipeline_str)| (desc, pipeline_str))
+                .collect();
+        }
+        let source = VideoSource::Test;
+        HwEncoder::ALL
+            .into_iter()
+            .filter(|encoder| encoder.is_available(hw_info))
+            .map(|encoder| {
+                (
+                    format!("{} + {}", source.label(), encoder.label()),
+                    self.build_hw_encoder_pipeline_str(&source, encoder, CapturePath::SystemMemory),
+                )
+            })
+            .chain(std::iter::once((source.label().to_string(), self.build_test_pipeline_str())))
+            .collect()
+    }
+
+    // Raw capture, then upload, encoder and parser of a current hardware encoder
+    fn build_hw_encoder_pipeline_str(&self, source: &VideoSource, encoder: HwEncoder, capture: CapturePath) -> String {
+        // Same bitrates as the legacy variants of each source
+        let bitrate_kbps = match source {
+            VideoSource::Camera(_) => 4000,
+            VideoSource::Remote(_) => 6000,
+            _ => 8000,
+        };
+        // Pinning the memory type makes a failed DMA-BUF negotiation fail the
+        // variant instead of silently falling back to a copy
+        let memory_caps = match (source, capture) {
+            (VideoSource::WaylandPortal(_), CapturePath::DmaBuf) => format!(" ! {}", DMABUF_CAPS),
+            (VideoSource::WaylandPortal(_), CapturePath::SystemMemory) => " ! video/x-raw".to_string(),
+            _ => String::new(),
+        };
+        format!(
+            "{}{} ! {} ! tee name=videotee ! {} ! \
+             appsink name=videosink sync=false drop=true max-buffers=2",
+            source.build_raw_str(),
+            memory_caps,
+            encoder.build_encode_str(bitrate_kbps, capture),
+            encoder.codec().payloader()
+        )
+    }
+
+    // pipewiresrc reaches PLAYING before the portal stream is negotiated, so
+    // zero-copy variants wait for caps on the video sink or a negotiation error
+    fn wait_for_negotiation(pipeline: &gst::Pipeline) -> Result<()> {
+        let sink_pad = pipeline
+            .by_name("videosink")
+            .and_then(|sink| sink.static_pad("sink"))
+            .ok_or_else(|| anyhow!("Video appsink not found"))?;
+        let bus = pipeline.bus().ok_or_else(|| anyhow!("Pipeline without bus"))?;
+        let deadline = Instant::now() + DMABUF_NEGOTIATION_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Some(msg) = bus.timed_pop_filtered(gst::ClockTime::from_mseconds(50), &[gst::MessageType::Error])
+                && let gst::MessageView::Error(err) = msg.view()
+            {
+                return Err(anyhow!("DMA-BUF negotiation failed: {}", err.error()));
+            }
+            if sink_pad.current_caps().is_some() {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("DMA-BUF negotiation timed out"))
+    }
+
     fn try_create_pipeline(
         &self,
         pipeline_str: &str,
+        config: &PipelineConfig,
         video_sender: mpsc::UnboundedSender<Bytes>,
         audio_sender: Option<mpsc::UnboundedSender<Bytes>>,
     ) -> Result<gst::Pipeline> {
@@ -331,6 +874,9 @@ impl PipelineFactory {
             })?
             .downcast::<gst::Pipeline>()
             .expect("Must be a pipeline");
+        if let Some(name) = &self.pipeline_name {
+            pipeline.set_property("name", name);
+        }
 
         // Configure video appsink
         self.setup_appsink("videosink", &pipeline, video_sender)?;
@@ -348,6 +894,16 @@ impl PipelineFactory {
             info!("Audio sender not provided - audio disabled");
         }
 
+        if config.adaptive_framerate {
+            crate::adaptive_capture::enable(&pipeline);
+        }
+        if config.cursor_metadata {
+            crate::cursor::hide_pointer(&pipeline);
+        }
+        if pipeline.by_name("pipmix").is_some() {
+            set_pip_layout(&pipeline, &config.pip_layout)?;
+        }
+
         // Try to set pipeline to playing state
         pipeline
             .set_state(gst::State::Playing)
@@ -360,6 +916,12 @@ impl PipelineFactory {
             Ok(gst::StateChangeSuccess::Success) => {
                 if current_state == gst::State::Playing {
                     info!("Pipeline successfully reached PLAYING state");
+                    if pipeline_str.contains(DMABUF_CAPS)
+                        && let Err(e) = Self::wait_for_negotiation(&pipeline)
+                    {
+                        pipeline.set_state(gst::State::Null)?;
+                        return Err(e);
+                    }
                     Ok(pipeline)
                 } else {
                     pipeline.set_state(gst::State::Null)?;
@@ -390,7 +952,7 @@ impl PipelineFactory {
              video/x-raw,framerate=30/1 ! \
              videoconvert ! nvvidconv ! 'video/x-raw(memory:NVMM)' ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             screen_num
         )
@@ -401,7 +963,7 @@ impl PipelineFactory {
             "pipewiresrc path={} do-timestamp=true ! \
              videoconvert ! nvvidconv ! 'video/x-raw(memory:NVMM)' ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             node_id
         )
@@ -413,7 +975,7 @@ impl PipelineFactory {
              video/x-raw,width=1280,height=720,framerate=30/1 ! \
              videoconvert ! nvvidconv ! 'video/x-raw(memory:NVMM)' ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=4000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             device_num
         )
@@ -426,7 +988,7 @@ impl PipelineFactory {
              video/x-raw,framerate=30/1 ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             screen_num
         )
@@ -437,7 +999,7 @@ impl PipelineFactory {
             "pipewiresrc path={} do-timestamp=true ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             node_id
         )
@@ -449,7 +1011,7 @@ impl PipelineFactory {
              video/x-raw,width=1280,height=720,framerate=30/1 ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc preset=low-latency-hq rc-mode=cbr gop-size=60 bitrate=4000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             device_num
         )
@@ -462,7 +1024,7 @@ impl PipelineFactory {
              video/x-raw,framerate=30/1 ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             screen_num
         )
@@ -473,7 +1035,7 @@ impl PipelineFactory {
             "pipewiresrc path={} do-timestamp=true ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc bitrate=8000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             node_id
         )
@@ -485,7 +1047,7 @@ impl PipelineFactory {
              video/x-raw,width=1280,height=720,framerate=30/1 ! \
              videoconvert ! video/x-raw,format=NV12 ! \
              nvh264enc bitrate=4000 ! \
-             h264parse ! rtph264pay pt=96 mtu=1200 ! \
+             h264parse ! tee name=videotee ! rtph264pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             device_num
         )
@@ -498,7 +1060,7 @@ impl PipelineFactory {
              video/x-raw,framerate=30/1 ! \
              videoconvert ! vaapipostproc ! 'video/x-raw(memory:VASurface)' ! \
              vaapivp8enc rate-control=cbr quality-level=5 bitrate=8000 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             screen_num
         )
@@ -509,7 +1071,7 @@ impl PipelineFactory {
             "pipewiresrc path={} do-timestamp=true ! \
              videoconvert ! vaapipostproc ! 'video/x-raw(memory:VASurface)' ! \
              vaapivp8enc rate-control=cbr quality-level=5 bitrate=8000 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             node_id
         )
@@ -521,7 +1083,7 @@ impl PipelineFactory {
              video/x-raw,width=1280,height=720,framerate=30/1 ! \
              videoconvert ! vaapipostproc ! 'video/x-raw(memory:VASurface)' ! \
              vaapivp8enc rate-control=cbr quality-level=5 bitrate=4000 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             device_num
         )
@@ -536,7 +1098,7 @@ impl PipelineFactory {
              queue max-size-buffers=2 leaky=downstream ! \
              vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
              target-bitrate=8000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             screen_num, *NUM_CPUS
         )
@@ -549,7 +1111,7 @@ impl PipelineFactory {
              queue max-size-buffers=2 leaky=downstream ! \
              vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
              target-bitrate=8000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             node_id, *NUM_CPUS
         )
@@ -563,12 +1125,38 @@ impl PipelineFactory {
              queue max-size-buffers=2 leaky=downstream ! \
              vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
              target-bitrate=4000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             device_num, *NUM_CPUS
         )
     }
 
+    fn build_remote_software_pipeline_str(&self, channel: &str) -> String {
+        format!(
+            "{} ! \
+             videoconvert ! videoscale ! video/x-raw,format=I420 ! \
+             queue max-size-buffers=2 leaky=downstream ! \
+             vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
+             target-bitrate=6000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
+             appsink name=videosink sync=false drop=true max-buffers=2",
+            VideoSource::Remote(channel.to_string()).build_raw_str(), *NUM_CPUS
+        )
+    }
+
+    fn build_composite_software_pipeline_str(&self, source: &VideoSource) -> String {
+        format!(
+            "{} ! \
+             videoconvert ! video/x-raw,format=I420 ! \
+             queue max-size-buffers=2 leaky=downstream ! \
+             vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
+             target-bitrate=8000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
+             appsink name=videosink sync=false drop=true max-buffers=2",
+            source.build_raw_str(), *NUM_CPUS
+        )
+    }
+
     fn build_test_pipeline_str(&self) -> String {
         format!(
             "videotestsrc pattern=smpte ! \
@@ -576,7 +1164,7 @@ impl PipelineFactory {
              videoconvert ! queue max-size-buffers=2 leaky=downstream ! \
              vp8enc deadline=1 cpu-used=8 threads={} error-resilient=1 keyframe-max-dist=60 \
              target-bitrate=8000000 end-usage=1 min-quantizer=4 max-quantizer=56 ! \
-             rtpvp8pay pt=96 mtu=1200 ! \
+             tee name=videotee ! rtpvp8pay pt=96 mtu=1200 ! \
              appsink name=videosink sync=false drop=true max-buffers=2",
             *NUM_CPUS
         )
@@ -585,8 +1173,8 @@ impl PipelineFactory {
     // Audio pipeline builders
     fn build_audio_pipeline_str(&self, config: &PipelineConfig, app_state: &AppState) -> String {
         info!(
-            "Building audio pipeline - enable_audio: {}, audio_source: {:?}",
-            config.enable_audio, config.audio_source
+            "Building audio pipeline - enable_audio: {}, audio_source: {:?}, mixer sources: {}",
+            config.enable_audio, config.audio_source, config.audio_sources.len()
         );
 
         if !config.enable_audio {
@@ -594,90 +1182,176 @@ impl PipelineFactory {
             return String::new();
         }
 
-        let pipeline_str = match config.audio_source.as_deref() {
-            Some(device_id) if device_id == "desktop_streamer_virtual" => {
+        let echo_probe = EchoProbe::new();
+        let mut uses_echo_probe = false;
+
+        let mut pipeline_str = if config.audio_sources.is_empty() {
+            let (device, is_microphone) =
+                self.resolve_audio_device(config.audio_source.as_deref(), app_state);
+            let voice_processing = if is_microphone {
+                build_voice_processing_str(&config.audio_processing, &echo_probe.name)
+            } else {
+                String::new()
+            };
+            uses_echo_probe |= !voice_processing.is_empty();
+            format!(
+                "pulsesrc device={} ! audioconvert ! audioresample ! \
+                 audio/x-raw,rate=48000,channels=2,format=S16LE ! {}{}{}",
+                device,
+                voice_processing,
+                self.build_audio_source_controls_str(0, 1.0, false, config),
+                self.build_audio_encoder_str(config)
+            )
+        } else {
+            let (pipeline, mixer_uses_probe) =
+                self.build_audio_mixer_str(config, app_state, &echo_probe.name);
+            uses_echo_probe |= mixer_uses_probe;
+            pipeline
+        };
+
+        if uses_echo_probe {
+            pipeline_str.push(' ');
+            pipeline_str.push_str(&echo_probe.build_str(&echo_reference_device(app_state)));
+        }
+
+        info!("Final audio pipeline: {}", pipeline_str);
+        pipeline_str
+    }
+
+    fn build_audio_mixer_str(
+        &self,
+        config: &PipelineConfig,
+        app_state: &AppState,
+        echo_probe_name: &str,
+    ) -> (String, bool) {
+        let mut uses_echo_probe = false;
+        let mut pipeline = format!(
+            "audiomixer name=audiomix ! {}",
+            self.build_audio_encoder_str(config)
+        );
+
+        for (index, source) in config.audio_sources.iter().enumerate() {
+            let (device, is_microphone) =
+                self.resolve_audio_device(Some(&source.device), app_state);
+            let voice_processing = if is_microphone {
+                build_voice_processing_str(&config.audio_processing, echo_probe_name)
+            } else {
+                String::new()
+            };
+            uses_echo_probe |= !voice_processing.is_empty();
+            pipeline.push_str(&format!(
+                " pulsesrc device={} ! audioconvert ! audioresample ! \
+                 audio/x-raw,rate=48000,channels=2,format=S16LE ! {}{}\
+                 queue max-size-time=100000000 leaky=downstream ! audiomix.",
+                device,
+                voice_processing,
+                self.build_audio_source_controls_str(index, source.gain, source.muted, config),
+            ));
+        }
+
+        info!("Created audio mixer pipeline with {} sources", config.audio_sources.len());
+        (pipeline, uses_echo_probe)
+    }
+
+    // Per-source gain/mute control and level meter
+    fn build_audio_source_controls_str(
+        &self,
+        index: usize,
+        gain: f64,
+        muted: bool,
+        config: &PipelineConfig,
+    ) -> String {
+        let mut controls = format!(
+            "volume name=audiogain{} volume={} mute={} ! ",
+            index,
+            gain.clamp(0.0, MAX_AUDIO_GAIN),
+            muted
+        );
+        let interval_ms = config.audio_processing.level_interval_ms;
+        if interval_ms > 0 {
+            controls.push_str(&format!(
+                "level name=audiolevel{} interval={} post-messages=true ! ",
+                index,
+                interval_ms * 1_000_000
+            ));
+        }
+        controls
+    }
+
+    // Master gain, loudness normalization and limiter applied before encoding
+    fn build_audio_processing_str(&self, config: &PipelineConfig) -> String {
+        let processing = &config.audio_processing;
+        let mut stage = format!(
+            "audioconvert ! volume volume={} ! ",
+            10f64.powf(processing.gain_db / 20.0).clamp(0.0, MAX_AUDIO_GAIN)
+        );
+
+        if processing.loudness_normalization {
+            if gst::ElementFactory::find("rsaudioloudnorm").is_some() {
+                // audioloudnorm only operates on 192 kHz F64 audio
+                stage.push_str(&format!(
+                    "audioresample ! audio/x-raw,format=F64LE,rate=192000 ! \
+                     rsaudioloudnorm loudness-target={} ! audioconvert ! audioresample ! \
+                     audio/x-raw,rate=48000 ! ",
+                    processing.target_lufs
+                ));
+            } else {
+                warn!("Loudness normalization requested but rsaudioloudnorm is not available (gst-plugins-rs audiofx)");
+            }
+        }
+
+        if processing.limiter {
+            stage.push_str(&format!(
+                "audiodynamic characteristics=soft-knee mode=compressor threshold={} ratio=0.0 ! ",
+                processing.limiter_threshold.clamp(0.0, 1.0)
+            ));
+        }
+
+        stage
+    }
+
+    /// Maps a client audio device id to a `pulsesrc` device and whether it is a microphone
+    fn resolve_audio_device(&self, device_id: Option<&str>, app_state: &AppState) -> (String, bool) {
+        match device_id {
+            Some("desktop_streamer_virtual") => {
                 // Use virtual device for system audio capture
                 if let Ok(virtual_audio_guard) = app_state.virtual_audio.try_lock() {
                     if let Some(virtual_device) = virtual_audio_guard.as_ref() {
-                        let pipeline = format!(
-                            "pulsesrc device={} ! audioconvert ! audioresample ! \
-                             audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                             volume volume=2.0 ! \
-                             opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                             appsink name=audiosink sync=false drop=true max-buffers=2",
-                            virtual_device.get_monitor_source_name(),
-                            config.audio_bitrate
-                        );
-                        info!("Created virtual device audio pipeline");
-                        pipeline
-                    } else {
-                        warn!(
-                            "Virtual device requested but not available, falling back to default"
-                        );
-                        format!(
-                            "pulsesrc device=@DEFAULT_MONITOR@ ! audioconvert ! audioresample ! \
-                             audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                             volume volume=2.0 ! \
-                             opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                             appsink name=audiosink sync=false drop=true max-buffers=2",
-                            config.audio_bitrate
-                        )
+                        info!("Using virtual device for audio capture");
+                        return (virtual_device.get_monitor_source_name(), false);
                     }
+                    warn!("Virtual device requested but not available, falling back to default");
                 } else {
                     warn!("Cannot access virtual device (locked), falling back to default");
-                    format!(
-                        "pulsesrc device=@DEFAULT_MONITOR@ ! audioconvert ! audioresample ! \
-                         audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                         volume volume=2.0 ! \
-                         opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                         appsink name=audiosink sync=false drop=true max-buffers=2",
-                        config.audio_bitrate
-                    )
                 }
+                ("@DEFAULT_MONITOR@".to_string(), false)
             }
             Some(device_id) if device_id.contains(".monitor") => {
                 // System audio (monitor device)
-                let pipeline = format!(
-                    "pulsesrc device={} ! audioconvert ! audioresample ! \
-                     audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                     volume volume=2.0 ! \
-                     opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                     appsink name=audiosink sync=false drop=true max-buffers=2",
-                    device_id, config.audio_bitrate
-                );
-                info!("Created monitor audio pipeline for device: {}", device_id);
-                pipeline
+                info!("Using monitor audio device: {}", device_id);
+                (device_id.to_string(), false)
             }
             Some(device_id) => {
                 // Microphone or other input device
-                let pipeline = format!(
-                    "pulsesrc device={} ! audioconvert ! audioresample ! \
-                     audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                     volume volume=1.5 ! \
-                     opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                     appsink name=audiosink sync=false drop=true max-buffers=2",
-                    device_id, config.audio_bitrate
-                );
-                info!("Created input audio pipeline for device: {}", device_id);
-                pipeline
+                info!("Using input audio device: {}", device_id);
+                (device_id.to_string(), true)
             }
             None => {
                 // Fallback to default system audio
-                let pipeline = format!(
-                    "pulsesrc device=@DEFAULT_MONITOR@ ! audioconvert ! audioresample ! \
-                     audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-                     volume volume=2.0 ! \
-                     opusenc bitrate={} ! rtpopuspay pt=111 ! \
-                     appsink name=audiosink sync=false drop=true max-buffers=2",
-                    config.audio_bitrate
-                );
-                info!("Created default monitor audio pipeline");
-                pipeline
+                info!("Using default monitor audio device");
+                ("@DEFAULT_MONITOR@".to_string(), false)
             }
-        };
+        }
+    }
 
-        info!("Final audio pipeline: {}", pipeline_str);
-        pipeline_str
+    fn build_audio_encoder_str(&self, config: &PipelineConfig) -> String {
+        format!(
+            "{}audioconvert ! audioresample ! audio/x-raw,rate=48000,channels=2,format=S16LE ! \
+             opusenc bitrate={} ! tee name=audiotee ! rtpopuspay pt=111 ! \
+             appsink name=audiosink sync=false drop=true max-buffers=2",
+            self.build_audio_processing_str(config),
+            config.audio_bitrate
+        )
     }
 
     fn build_combined_pipeline_str(&self, video_pipeline: &str, audio_pipeline: &str) -> String {
@@ -701,7 +1375,8 @@ impl PipelineFactory {
         id: &str,
         stream_id: &str,
         mime_type: &str,
-    ) -> Result<(Arc<TrackLocalStaticRTP>, mpsc::UnboundedSender<Bytes>)> {
+        mut receiver: mpsc::UnboundedReceiver<Bytes>,
+    ) -> Arc<TrackLocalStaticRTP> {
         let track = Arc::new(TrackLocalStaticRTP::new(
             RTCRtpCodecCapability {
                 mime_type: mime_type.to_owned(),
@@ -711,7 +1386,6 @@ impl PipelineFactory {
             stream_id.to_owned(),
         ));
 
-        let (sender, mut receiver) = mpsc::unbounded_channel::<Bytes>();
         let track_writer = track.clone();
         let track_id = id.to_owned(); // Convert to owned String
 
@@ -724,7 +1398,7 @@ impl PipelineFactory {
             }
         });
 
-        Ok((track, sender))
+        track
     }
 
     fn setup_appsink(
@@ -747,6 +1421,20 @@ impl PipelineFactory {
         let sample_count = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
         let sample_count_clone = sample_count.clone();
         let name_clone = name.to_string();
+        let counters = crate::metrics::register_appsink(pipeline, name);
+
+        // With drop=true a full appsink silently discards buffers; counting what
+        // reaches its pad against what is pulled gives those drops
+        let arrived = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
+        if let Some(sink_pad) = appsink.static_pad("sink") {
+            let arrived = arrived.clone();
+            sink_pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+                arrived.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                gst::PadProbeReturn::Ok
+            });
+        }
+        let max_buffers = appsink.max_buffers() as u64;
+        let mut counted_drops = 0u64;
 
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
@@ -754,21 +1442,22 @@ impl PipelineFactory {
                     let count =
                         sample_count_clone.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    // Up to max-buffers of the difference may still be queued, so
+                    // drops are only counted beyond that (never overcounted)
+                    let dropped = arrived
+                        .load(std::sync::atomic::Ordering::Relaxed)
+                        .saturating_sub(count + 1 + max_buffers);
+                    if dropped > counted_drops {
+                        counters.dropped.fetch_add(dropped - counted_drops, std::sync::atomic::Ordering::Relaxed);
+                        counted_drops = dropped;
+                    }
                     let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
                     let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
-
-                    // Log every 100th sample for audio to avoid spam, every 30th for video
-                    // let log_interval = if name_clone == "audiosink" { 100 } else { 30 };
-                    // if count % log_interval == 0 {
-                    //     info!(
-                    //         "{} sample #{} received: {} bytes",
-                    //         name_clone,
-                    //         count,
-                    //         map.len()
-                    //     );
-                    // }
+                    counters.frames.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    counters.bytes.fetch_add(map.len() as u64, std::sync::atomic::Ordering::Relaxed);
 
                     if let Err(e) = sender.send(Bytes::copy_from_slice(map.as_slice())) {
+                        counters.dropped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                         warn!("Failed to send {} sample #{}: {}", name_clone, count, e);
                     }
 
@@ -779,3 +1468,348 @@ impl PipelineFactory {
         Ok(())
     }
 }
+
+// Metrics label for a failed pipeline variant, from the errors of try_create_pipeline
+fn pipeline_failure_reason(error: &anyhow::Error) -> &'static str {
+    let message = error.to_string();
+    if message.contains("Missing GStreamer element") {
+        "missing_element"
+    } else if message.contains("Failed to parse pipeline") {
+        "parse"
+    } else if message.contains("appsink") {
+        "appsink"
+    } else if message.contains("state") || message.contains("playing") {
+        "state_change"
+    } else {
+        "other"
+    }
+}
+
+static ECHO_PROBE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// `webrtcechoprobe` feeding the far-end signal to `webrtcdsp` elements.
+/// Probes are looked up by name process-wide, so each one gets a unique name.
+pub struct EchoProbe {
+    pub name: String,
+}
+
+impl EchoProbe {
+    pub fn new() -> Self {
+        Self {
+            name: format!("echoprobe{}", ECHO_PROBE_COUNTER.fetch_add(1, Ordering::Relaxed)),
+        }
+    }
+
+    /// Branch capturing the far-end reference into the probe
+    pub fn build_str(&self, reference_device: &str) -> String {
+        format!(
+            "pulsesrc device={} ! audioconvert ! audioresample ! \
+             audio/x-raw,rate=48000,channels=2,format=S16LE ! \
+             webrtcechoprobe name={} ! fakesink sync=false async=false",
+            reference_device, self.name
+        )
+    }
+}
+
+/// Far-end reference for echo cancellation: the monitor of the virtual output
+/// sink, i.e. what is being sent to the client (falls back to the default monitor)
+pub fn echo_reference_device(app_state: &AppState) -> String {
+    app_state
+        .virtual_audio
+        .try_lock()
+        .ok()
+        .and_then(|guard| guard.as_ref().map(|device| device.get_monitor_source_name()))
+        .unwrap_or_else(|| "@DEFAULT_MONITOR@".to_string())
+}
+
+/// `webrtcdsp` stage for a microphone path; expects S16LE at 48 kHz.
+/// Returns an empty string when no processing is enabled or available.
+pub fn build_voice_processing_str(processing: &AudioProcessingConfig, probe_name: &str) -> String {
+    if !processing.has_voice_processing() {
+        return String::new();
+    }
+    if gst::ElementFactory::find("webrtcdsp").is_none() {
+        warn!("Voice processing requested but webrtcdsp is not available (gst-plugins-bad)");
+        return String::new();
+    }
+
+    format!(
+        "webrtcdsp probe={} echo-cancel={} noise-suppression={} gain-control={} ! audioconvert ! ",
+        probe_name,
+        processing.echo_cancellation,
+        processing.noise_suppression,
+        processing.auto_gain_control
+    )
+}
+
+/// Adjusts gain and mute of one audio mixer input on a running pipeline
+pub fn set_audio_source_gain(
+    pipeline: &gst::Pipeline,
+    index: usize,
+    gain: f64,
+    muted: bool,
+) -> Result<()> {
+    let volume = pipeline
+        .by_name(&format!("audiogain{}", index))
+        .ok_or_else(|| anyhow!("Audio source {} not found in pipeline", index))?;
+    volume.set_property("volume", gain.clamp(0.0, MAX_AUDIO_GAIN));
+    volume.set_property("mute", muted);
+    Ok(())
+}
+
+/// Places the camera of a picture-in-picture pipeline; used before the
+/// pipeline starts and for live layout changes.
+pub fn set_pip_layout(pipeline: &gst::Pipeline, layout: &PipLayout) -> Result<()> {
+    let camera_pad = pipeline
+        .by_name("pipmix")
+        .and_then(|mixer| mixer.static_pad("sink_1"))
+        .ok_or_else(|| anyhow!("Pipeline has no picture-in-picture source"))?;
+    let caps = pipeline
+        .by_name("pipcaps")
+        .ok_or_else(|| anyhow!("Picture-in-picture camera caps not found"))?;
+    let border_box = pipeline
+        .by_name("pipborder")
+        .ok_or_else(|| anyhow!("Picture-in-picture border not found"))?;
+
+    let (canvas_width, canvas_height) = PIP_CANVAS;
+    let border = layout.border.min(64) as i32;
+    let margin = layout.margin.min(256) as i32;
+    // Even sizes keep 4:2:0 chroma aligned; cameras are captured at 16:9
+    let width = (canvas_width as f64 * layout.size.clamp(0.05, 1.0)) as i32 / 2 * 2;
+    let height = width * 9 / 16 / 2 * 2;
+    let (outer_width, outer_height) = (width + 2 * border, height + 2 * border);
+    let left = margin;
+    let right = (canvas_width - outer_width - margin).max(0);
+    let top = margin;
+    let bottom = (canvas_height - outer_height - margin).max(0);
+    let (xpos, ypos) = match layout.position {
+        PipPosition::TopLeft => (left, top),
+        PipPosition::TopRight => (right, top),
+        PipPosition::BottomLeft => (left, bottom),
+        PipPosition::BottomRight => (right, bottom),
+    };
+
+    caps.set_property(
+        "caps",
+        gst::Caps::builder("video/x-raw")
+            .field("width", width)
+            .field("height", height)
+            .build(),
+    );
+    // Negative videobox crops add borders
+    for side in ["top", "bottom", "left", "right"] {
+        border_box.set_property(side, -border);
+    }
+    camera_pad.set_property("xpos", xpos);
+    camera_pad.set_property("ypos", ypos);
+    camera_pad.set_property("alpha", layout.opacity.clamp(0.0, 1.0));
+    Ok(())
+}
+
+// Branch attached at runtime to the `videotee`/`audiotee` elements of a
+// running pipeline; used to tap the already-encoded streams
+pub struct TeeBranch {
+    pipeline: gst::Pipeline,
+    bin: gst::Bin,
+    // (tee, requested tee src pad, bin sink pad)
+    links: Vec<(gst::Element, gst::Pad, gst::Pad)>,
+}
+
+impl TeeBranch {
+    /// Adds `bin` (which must expose a "sink" ghost pad) to the pipeline and
+    /// links it to the tee, asking the encoder for a keyframe.
+    pub fn attach(pipeline: &gst::Pipeline, tee_name: &str, bin: gst::Bin) -> Result<Self> {
+        Self::attach_pads(pipeline, bin, &[(tee_name, "sink")])
+    }
+
+    /// Like `attach`, for a bin fed by several tees: each (tee, bin pad)
+    /// pair links a tee to the bin ghost pad of that name.
+    pub fn attach_pads(pipeline: &gst::Pipeline, bin: gst::Bin, links: &[(&str, &str)]) -> Result<Self> {
+        pipeline.add(&bin)?;
+        let mut branch = Self {
+            pipeline: pipeline.clone(),
+            bin,
+            links: Vec::new(),
+        };
+        if let Err(e) = branch.bin.sync_state_with_parent() {
+            branch.remove();
+            return Err(anyhow!("Failed to start tee branch: {}", e));
+        }
+        for (tee_name, pad_name) in links {
+            if let Err(e) = branch.link(tee_name, pad_name) {
+                branch.remove();
+                return Err(e);
+            }
+        }
+
+        branch.request_keyframe();
+        Ok(branch)
+    }
+
+    fn link(&mut self, tee_name: &str, pad_name: &str) -> Result<()> {
+        let tee = self
+            .pipeline
+            .by_name(tee_name)
+            .ok_or_else(|| anyhow!("Pipeline has no '{}'", tee_name))?;
+        let bin_pad = self
+            .bin
+            .static_pad(pad_name)
+            .ok_or_else(|| anyhow!("Tee branch has no '{}' pad", pad_name))?;
+        let tee_pad = tee
+            .request_pad_simple("src_%u")
+            .ok_or_else(|| anyhow!("Failed to request a pad from '{}'", tee_name))?;
+        self.links.push((tee, tee_pad.clone(), bin_pad.clone()));
+        tee_pad
+            .link(&bin_pad)
+            .map_err(|e| anyhow!("Failed to link tee branch: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Asks the encoder feeding the branch for a keyframe with headers.
+    pub fn request_keyframe(&self) {
+        for (_, tee_pad, _) in &self.links {
+            tee_pad.send_event(gst_video::UpstreamForceKeyUnitEvent::builder().all_headers(true).build());
+        }
+    }
+
+    /// Unlinks the branch once the tees are idle and removes it from the pipeline.
+    pub async fn detach(self) {
+        let mut pending = Vec::new();
+        for (_, tee_pad, bin_pad) in &self.links {
+            let (unlinked_tx, unlinked) = tokio::sync::oneshot::channel();
+            let unlinked_tx = std::sync::Mutex::new(Some(unlinked_tx));
+            let bin_pad = bin_pad.clone();
+            tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+                let _ = pad.unlink(&bin_pad);
+                if let Some(tx) = unlinked_tx.lock().unwrap().take() {
+                    let _ = tx.send(());
+                }
+                gst::PadProbeReturn::Remove
+            });
+            pending.push(unlinked);
+        }
+        // Setting the branch to NULL while a tee still pushes into it would stall the tee
+        let all_unlinked = futures::future::join_all(pending);
+        if tokio::time::timeout(std::time::Duration::from_secs(5), all_unlinked).await.is_err() {
+            warn!("Tee branch was not unlinked in time");
+        }
+        self.remove();
+    }
+
+    fn remove(&self) {
+        let _ = self.bin.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&self.bin);
+        for (tee, tee_pad, _) in &self.links {
+            tee.release_request_pad(tee_pad);
+        }
+    }
+}
+
+/// Drops buffers reaching `pad` until the first keyframe, so a decoder
+/// tapping a running stream never starts mid-GOP and errors out on the
+/// shared bus.
+pub fn drop_until_keyframe(pad: &gst::Pad) {
+    let keyframe_seen = AtomicBool::new(false);
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        let Some(buffer) = info.buffer() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        if !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+            keyframe_seen.store(true, Ordering::SeqCst);
+        }
+        if keyframe_seen.load(Ordering::SeqCst) {
+            gst::PadProbeReturn::Ok
+        } else {
+            gst::PadProbeReturn::Drop
+        }
+    });
+}
+
+/// Media type (e.g. "video/x-h264") flowing through a tee of a running pipeline
+pub fn tee_media_type(pipeline: &gst::Pipeline, tee_name: &str) -> Option<String> {
+    pipeline
+        .by_name(tee_name)?
+        .static_pad("sink")
+        .and_then(|pad| pad.current_caps().or_else(|| pad.allowed_caps()))?
+        .structure(0)
+        .map(|s| s.name().to_string())
+}
+
+/// First available AAC encoder, for outputs that cannot carry Opus
+pub fn find_aac_encoder() -> Option<&'static str> {
+    ["fdkaacenc", "avenc_aac", "voaacenc"]
+        .into_iter()
+        .find(|name| gst::ElementFactory::find(name).is_some())
+}
+
+/// Forwards relevant bus messages of a peer pipeline (audio levels, errors)
+/// to the client until the pipeline is shut down.
+pub fn watch_pipeline_bus(pipeline: &gst::Pipeline, signal_sender: mpsc::UnboundedSender<SignalMessage>) {
+    let Some(bus) = pipeline.bus() else {
+        warn!("Pipeline has no bus, not watching messages");
+        return;
+    };
+    let pipeline_weak = pipeline.downgrade();
+
+    tokio::spawn(async move {
+        let mut messages = bus.stream();
+        while let Some(msg) = messages.next().await {
+            use gst::MessageView;
+
+            let signal = match msg.view() {
+                MessageView::Element(element) => {
+                    element.structure().and_then(|s| audio_level_signal(msg.src(), s))
+                }
+                MessageView::Error(err) => {
+                    error!(
+                        "Pipeline error from {:?}: {} ({:?})",
+                        err.src().map(|s| s.name()),
+                        err.error(),
+                        err.debug()
+                    );
+                    Some(SignalMessage::Error {
+                        message: format!("Erro no pipeline: {}", err.error()),
+                    })
+                }
+                MessageView::StateChanged(state_changed) => {
+                    // The pipeline leaving PAUSED for READY means it is being shut down
+                    let from_pipeline = pipeline_weak
+                        .upgrade()
+                        .is_some_and(|p| msg.src() == Some(p.upcast_ref::<gst::Object>()));
+                    if from_pipeline
+                        && state_changed.old() == gst::State::Paused
+                        && state_changed.current() == gst::State::Ready
+                    {
+                        break;
+                    }
+                    None
+                }
+                MessageView::Eos(_) => break,
+                _ => None,
+            };
+
+            if signal.is_some_and(|signal| signal_sender.send(signal).is_err()) {
+                break;
+            }
+        }
+        debug!("Stopped watching pipeline bus");
+    });
+}
+
+fn audio_level_signal(src: Option<&gst::Object>, s: &gst::StructureRef) -> Option<SignalMessage> {
+    if s.name() != "level" {
+        return None;
+    }
+    let source = src?.name().strip_prefix("audiolevel")?.parse::<usize>().ok()?;
+    let channel_values = |field: &str| -> Vec<f64> {
+        s.get::<gst::glib::ValueArray>(field)
+            .map(|values| values.iter().filter_map(|v| v.get::<f64>().ok()).collect())
+            .unwrap_or_default()
+    };
+
+    Some(SignalMessage::AudioLevel {
+        source,
+        peak: channel_values("peak"),
+        rms: channel_values("rms"),
+    })
+}
@@ -0,0 +1,178 @@
+use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::AppState;
+use crate::stats::StreamStats;
+
+// Sample counters of an appsink feeding WebRTC tracks, kept while its pipeline exists
+#[derive(Default)]
+pub struct AppsinkCounters {
+    pub frames: AtomicU64,
+    pub bytes: AtomicU64,
+    // Samples the appsink discarded because it was full (drop=true), plus
+    // those that could not be handed to the track writer
+    pub dropped: AtomicU64,
+}
+
+// (metric name, help, accessor) of the per-appsink and per-stream series
+type AppsinkMetric = (&'static str, &'static str, fn(&AppsinkCounters) -> &AtomicU64);
+type StreamMetric = (&'static str, &'static str, fn(&StreamStats) -> f64);
+
+// By (pipeline name, appsink name)
+type AppsinkMap = BTreeMap<(String, String), Arc<AppsinkCounters>>;
+
+static APPSINKS: Lazy<Mutex<AppsinkMap>> = Lazy::new(Default::default);
+static PIPELINE_FAILURES: Lazy<Mutex<BTreeMap<&'static str, u64>>> = Lazy::new(Default::default);
+
+/// Fresh counters for the appsink `name` of `pipeline`, removed once the pipeline
+/// is disposed. A fallback variant started under the same pipeline name
+/// replaces the counters of the one that failed.
+pub fn register_appsink(pipeline: &gst::Pipeline, name: &str) -> Arc<AppsinkCounters> {
+    let key = (pipeline.name().to_string(), name.to_string());
+    let counters = Arc::new(AppsinkCounters::default());
+    APPSINKS.lock().unwrap().insert(key.clone(), counters.clone());
+    let registered = Arc::downgrade(&counters);
+    pipeline.add_weak_ref_notify(move || {
+        let mut appsinks = APPSINKS.lock().unwrap();
+        if appsinks.get(&key).is_some_and(|counters| std::ptr::eq(Arc::as_ptr(counters), registered.as_ptr())) {
+            appsinks.remove(&key);
+        }
+    });
+    counters
+}
+
+pub fn record_pipeline_failure(reason: &'static str) {
+    *PIPELINE_FAILURES.lock().unwrap().entry(reason).or_default() += 1;
+}
+
+// Metrics routes, merged into the main router by run_server
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&state).await,
+    )
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub async fn render(state: &AppState) -> String {
+    let mut out = String::new();
+    let peers: Vec<_> = state.peers.lock().await.iter().map(|(id, peer)| (*id, peer.clone())).collect();
+
+    metric_header(&mut out, "desktop_streamer_peers", "gauge", "Connected WebRTC peers");
+    let _ = writeln!(out, "desktop_streamer_peers {}", peers.len());
+    metric_header(&mut out, "desktop_streamer_ingests", "gauge", "Streams published over WHIP");
+    let _ = writeln!(out, "desktop_streamer_ingests {}", state.ingests.lock().await.len());
+
+    let mut variants: BTreeMap<String, u64> = BTreeMap::new();
+    for (_, peer) in &peers {
+        *variants.entry(peer.pipeline_variant.clone()).or_default() += 1;
+    }
+    for broadcast in state.broadcasts.lock().await.values() {
+        *variants.entry(broadcast.variant.clone()).or_default() += 1;
+    }
+    metric_header(&mut out, "desktop_streamer_pipelines", "gauge", "Running encoder pipelines by variant");
+    for (variant, count) in &variants {
+        let _ = writeln!(out, "desktop_streamer_pipelines{{variant=\"{}\"}} {}", escape(variant), count);
+    }
+
+    metric_header(
+        &mut out,
+        "desktop_streamer_pipeline_failures_total",
+        "counter",
+        "Pipeline variants that failed to start, by reason",
+    );
+    for (reason, count) in PIPELINE_FAILURES.lock().unwrap().iter() {
+        let _ = writeln!(out, "desktop_streamer_pipeline_failures_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    let appsinks: Vec<_> = APPSINKS.lock().unwrap().iter().map(|(key, c)| (key.clone(), c.clone())).collect();
+    let appsink_metrics: [AppsinkMetric; 3] = [
+        ("desktop_streamer_appsink_frames_total", "Samples received by appsink", |c| &c.frames),
+        ("desktop_streamer_appsink_bytes_total", "Bytes received by appsink", |c| &c.bytes),
+        ("desktop_streamer_appsink_dropped_samples_total", "Samples dropped before reaching the track", |c| &c.dropped),
+    ];
+    for (metric, help, counter) in appsink_metrics {
+        metric_header(&mut out, metric, "counter", help);
+        for ((pipeline, sink), counters) in &appsinks {
+            let _ = writeln!(
+                out,
+                "{}{{pipeline=\"{}\",sink=\"{}\"}} {}",
+                metric,
+                escape(pipeline),
+                escape(sink),
+                counter(counters).load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    let stats: Vec<_> = peers.iter().map(|(id, peer)| (id.to_string(), peer.stats.snapshot())).collect();
+    let stream_metrics: [StreamMetric; 4] = [
+        ("desktop_streamer_outbound_rtp_bitrate_bps", "Outbound RTP bitrate per peer, from the bytes sent", |s| s.bitrate_bps as f64),
+        ("desktop_streamer_rtcp_packets_lost", "Packets lost reported by RTCP", |s| s.packets_lost as f64),
+        ("desktop_streamer_rtcp_fraction_lost", "Fraction lost in the last RTCP report", |s| s.fraction_lost),
+        ("desktop_streamer_rtcp_jitter_seconds", "Interarrival jitter reported by RTCP", |s| s.jitter_ms / 1000.0),
+    ];
+    for (metric, help, value) in stream_metrics {
+        metric_header(&mut out, metric, "gauge", help);
+        for (peer, stats) in &stats {
+            for (kind, stream) in [("video", &stats.video), ("audio", &stats.audio)] {
+                let _ = writeln!(out, "{}{{peer=\"{}\",kind=\"{}\"}} {}", metric, peer, kind, value(stream));
+            }
+        }
+    }
+    metric_header(&mut out, "desktop_streamer_rtt_seconds", "gauge", "Round trip time per peer");
+    for (peer, stats) in &stats {
+        if let Some(rtt_ms) = stats.rtt_ms {
+            let _ = writeln!(out, "desktop_streamer_rtt_seconds{{peer=\"{}\"}} {}", peer, rtt_ms / 1000.0);
+        }
+    }
+
+    let (sink_up, source_up) = state
+        .virtual_audio
+        .lock()
+        .await
+        .as_ref()
+        .map(|device| (device.sink_module_id.is_some(), device.source_module_id.is_some()))
+        .unwrap_or_default();
+    let (camera_up, camera_feeding) = state
+        .virtual_camera
+        .lock()
+        .await
+        .as_ref()
+        .map(|camera| {
+            let status = camera.status();
+            (status.device.is_some(), status.source.is_some())
+        })
+        .unwrap_or_default();
+    metric_header(&mut out, "desktop_streamer_virtual_device_up", "gauge", "Whether each virtual device exists");
+    for (device, up) in [
+        ("audio_sink", sink_up),
+        ("audio_source", source_up),
+        ("camera", camera_up),
+    ] {
+        let _ = writeln!(out, "desktop_streamer_virtual_device_up{{device=\"{}\"}} {}", device, up as u8);
+    }
+    metric_header(&mut out, "desktop_streamer_virtual_camera_feeding", "gauge", "Whether a source is written to the virtual camera");
+    let _ = writeln!(out, "desktop_streamer_virtual_camera_feeding {}", camera_feeding as u8);
+
+    out
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
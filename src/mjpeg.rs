@@ -0,0 +1,196 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+    routing::get,
+};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::broadcast::{RawVideoTap, start_raw_video};
+use crate::config::AppState;
+use crate::media::VideoSource;
+
+const BOUNDARY: &str = "frame";
+// A stream without frames for this long is considered dead (e.g. its source pipeline stopped)
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+// Frames buffered per client; slower clients skip to the newest ones
+const CLIENT_FRAME_BACKLOG: usize = 2;
+
+#[derive(Deserialize)]
+struct MjpegQuery {
+    fps: Option<u32>,
+    quality: Option<u32>,
+    width: Option<u32>,
+}
+
+// MJPEG routes, merged into the main router by run_server
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/mjpeg/{source}", get(mjpeg_stream))
+}
+
+// JPEG encoder of a source shared by every client asking for the same
+// parameters, so a capture device is opened once; stopped with its last client
+pub struct MjpegProducer {
+    tap: Option<RawVideoTap>,
+    frames: broadcast::Sender<Bytes>,
+}
+
+impl Drop for MjpegProducer {
+    fn drop(&mut self) {
+        if let Some(tap) = self.tap.take() {
+            tokio::spawn(tap.stop());
+        }
+    }
+}
+
+struct MjpegClient {
+    _producer: Arc<MjpegProducer>,
+    frames: broadcast::Receiver<Bytes>,
+}
+
+async fn mjpeg_stream(
+    State(state): State<Arc<AppState>>,
+    Path(source): Path<String>,
+    Query(query): Query<MjpegQuery>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let fps = query.fps.unwrap_or(state.settings.mjpeg_fps);
+    let quality = query.quality.unwrap_or(state.settings.mjpeg_quality);
+    if !(1..=60).contains(&fps) || quality > 100 {
+        return Err((StatusCode::BAD_REQUEST, "fps must be 1-60 and quality 0-100".to_string()));
+    }
+    if let Some(width) = query.width
+        && !(16..=7680).contains(&width)
+    {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid width {}", width)));
+    }
+    if !VideoSource::is_known(&source, &state.session_type, state.has_portal) {
+        return Err((StatusCode::NOT_FOUND, format!("Unknown source '{}'", source)));
+    }
+
+    let producer = join_stream(&state, &source, fps, quality, query.width).await.map_err(|e| {
+        warn!("MJPEG stream of {} failed: {}", source, e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    info!("MJPEG client started on {} ({} fps, quality {})", source, fps, quality);
+    let client = MjpegClient {
+        frames: producer.frames.subscribe(),
+        _producer: producer,
+    };
+
+    // Slow clients skip the frames they fell behind on instead of delaying the others
+    let parts = futures::stream::unfold(client, |mut client| async move {
+        loop {
+            match tokio::time::timeout(FRAME_TIMEOUT, client.frames.recv()).await {
+                Ok(Ok(frame)) => return Some((Ok::<_, std::io::Error>(multipart_frame(&frame)), client)),
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                _ => return None,
+            }
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format!("multipart/x-mixed-replace; boundary={}", BOUNDARY)),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Body::from_stream(parts),
+    ))
+}
+
+fn multipart_frame(jpeg: &[u8]) -> Bytes {
+    let mut part = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    )
+    .into_bytes();
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+// The running producer for these parameters, or a new one
+async fn join_stream(
+    state: &AppState,
+    source: &str,
+    fps: u32,
+    quality: u32,
+    width: Option<u32>,
+) -> Result<Arc<MjpegProducer>> {
+    let key = format!("{}/{}fps/q{}/w{}", source, fps, quality, width.unwrap_or(0));
+    if let Some(producer) = running_producer(state, &key).await {
+        return Ok(producer);
+    }
+    // Clients arriving while the producer starts wait for it instead of opening the source again
+    let _starting = state.mjpeg_starts.lock(&key).await;
+    if let Some(producer) = running_producer(state, &key).await {
+        return Ok(producer);
+    }
+    let producer = Arc::new(start_producer(state, source, fps, quality, width).await?);
+    let mut producers = state.mjpeg.lock().await;
+    producers.retain(|_, producer| producer.strong_count() > 0);
+    producers.insert(key, Arc::downgrade(&producer));
+    Ok(producer)
+}
+
+async fn running_producer(state: &AppState, key: &str) -> Option<Arc<MjpegProducer>> {
+    state.mjpeg.lock().await.get(key).and_then(|producer| producer.upgrade())
+}
+
+async fn start_producer(
+    state: &AppState,
+    source: &str,
+    fps: u32,
+    quality: u32,
+    width: Option<u32>,
+) -> Result<MjpegProducer> {
+    let mut caps = format!("video/x-raw,framerate={}/1", fps);
+    if let Some(width) = width {
+        caps.push_str(&format!(",width={},pixel-aspect-ratio=1/1", width));
+    }
+    let encode_str = format!(
+        "videoconvert ! videoscale ! videorate drop-only=true ! {} ! jpegenc quality={} ! \
+         appsink name=mjpegsink sync=false max-buffers=1 drop=true",
+        caps, quality
+    );
+
+    let (frames, _) = broadcast::channel(CLIENT_FRAME_BACKLOG);
+    let frames_tx = frames.clone();
+    let tap = start_raw_video(state, source, &encode_str, |bin| {
+        forward_frames(&mjpeg_sink(bin)?, frames_tx);
+        Ok(())
+    })
+    .await?;
+    info!("Started MJPEG encoder of {} ({} fps, quality {})", source, fps, quality);
+    Ok(MjpegProducer { tap: Some(tap), frames })
+}
+
+fn mjpeg_sink(bin: &gst::Bin) -> Result<AppSink> {
+    bin.by_name("mjpegsink")
+        .and_then(|e| e.downcast::<AppSink>().ok())
+        .ok_or_else(|| anyhow!("Failed to get MJPEG appsink"))
+}
+
+fn forward_frames(appsink: &AppSink, frames_tx: broadcast::Sender<Bytes>) {
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                // Fails only while no client is subscribed
+                let _ = frames_tx.send(Bytes::copy_from_slice(map.as_slice()));
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+}
@@ -0,0 +1,384 @@
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::{AppSink, AppSrc};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::broadcast::{SourceLease, acquire_source};
+use crate::config::AppState;
+use crate::media::{TeeBranch, find_aac_encoder, tee_media_type};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// An attempt that stayed up this long resets the backoff
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+// How often a push checks that the pipeline it taps is still running
+const PIPELINE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// External ingest a source is pushed to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub enum PushTarget {
+    // rtmp:// or rtmps:// URL including the stream key
+    Rtmp { url: String },
+    Srt {
+        uri: String, // srt://host:port, caller mode
+        #[serde(default)]
+        latency_ms: Option<u32>,
+        // Never reported back through the API
+        #[serde(default, skip_serializing)]
+        passphrase: Option<String>,
+    },
+}
+
+impl PushTarget {
+    fn validate(&self) -> Result<()> {
+        let location = match self {
+            Self::Rtmp { url } => {
+                if !url.starts_with("rtmp://") && !url.starts_with("rtmps://") {
+                    return Err(anyhow!("RTMP url must start with rtmp:// or rtmps://"));
+                }
+                url
+            }
+            Self::Srt { uri, passphrase, .. } => {
+                if !uri.starts_with("srt://") {
+                    return Err(anyhow!("SRT uri must start with srt://"));
+                }
+                if let Some(passphrase) = passphrase
+                    && !(10..=79).contains(&passphrase.len())
+                {
+                    return Err(anyhow!("SRT passphrase must have 10 to 79 characters"));
+                }
+                uri
+            }
+        };
+        if location.contains(['"', ' ']) {
+            return Err(anyhow!("Target location contains invalid characters"));
+        }
+        Ok(())
+    }
+
+    // Muxer and sink of the push pipeline; the sink is named "pushsink"
+    fn mux_and_sink(&self) -> String {
+        match self {
+            Self::Rtmp { url } => format!(
+                "flvmux name=pushmux streamable=true ! rtmp2sink name=pushsink location=\"{}\"",
+                url
+            ),
+            Self::Srt { uri, .. } => format!(
+                "mpegtsmux name=pushmux alignment=7 ! srtsink name=pushsink uri=\"{}\" wait-for-connection=false",
+                uri
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PushStatus {
+    Connecting,
+    Live,
+    Reconnecting,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PushInfo {
+    pub id: String,
+    pub source: String,
+    pub target: PushTarget,
+    pub status: PushStatus,
+    pub started_at: u64, // unix seconds
+    pub reconnects: u32,
+    pub last_error: Option<String>,
+}
+
+// A push kept running (and reconnected) by its own task until stopped
+pub struct PushSession {
+    info: Arc<Mutex<PushInfo>>,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl PushSession {
+    pub fn info(&self) -> PushInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    pub async fn stop(self) -> PushInfo {
+        let _ = self.stop.send(true);
+        if tokio::time::timeout(Duration::from_secs(10), self.task).await.is_err() {
+            warn!("Push {} did not stop in time", self.info.lock().unwrap().id);
+        }
+        self.info.lock().unwrap().clone()
+    }
+}
+
+/// Starts pushing `source` to `target` in the background.
+pub fn start_push(state: &Arc<AppState>, source: &str, target: PushTarget) -> Result<PushSession> {
+    target.validate()?;
+    let info = Arc::new(Mutex::new(PushInfo {
+        id: Uuid::new_v4().simple().to_string(),
+        source: source.to_string(),
+        target,
+        status: PushStatus::Connecting,
+        started_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        reconnects: 0,
+        last_error: None,
+    }));
+    let (stop, stop_rx) = watch::channel(false);
+    let task = tokio::spawn(run_push(state.clone(), info.clone(), stop_rx));
+    Ok(PushSession { info, stop, task })
+}
+
+/// Stops every push, on shutdown.
+pub async fn stop_all_pushes(state: &AppState) {
+    let pushes: Vec<PushSession> = state.pushes.lock().await.drain().map(|(_, push)| push).collect();
+    for push in pushes {
+        push.stop().await;
+    }
+}
+
+fn set_status(info: &Mutex<PushInfo>, status: PushStatus) {
+    info.lock().unwrap().status = status;
+}
+
+// Retries the push with exponential backoff until it is stopped
+async fn run_push(state: Arc<AppState>, info: Arc<Mutex<PushInfo>>, mut stop_rx: watch::Receiver<bool>) {
+    let (id, source, target) = {
+        let info = info.lock().unwrap();
+        (info.id.clone(), info.source.clone(), info.target.clone())
+    };
+    info!("Starting push {} of {} to {:?}", id, source, target);
+
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match push_attempt(&state, &source, &target, &info, &mut stop_rx).await {
+            Ok(()) => break,
+            Err(e) => {
+                warn!("Push {} failed: {}", id, e);
+                let mut info = info.lock().unwrap();
+                info.status = PushStatus::Reconnecting;
+                info.reconnects += 1;
+                info.last_error = Some(e.to_string());
+            }
+        }
+
+        if started.elapsed() > STABLE_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = stop_rx.changed() => break,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        set_status(&info, PushStatus::Connecting);
+    }
+
+    set_status(&info, PushStatus::Stopped);
+    info!("Push {} stopped", id);
+}
+
+// Runs one connection to the target; returns Ok only once the push is stopped
+async fn push_attempt(
+    state: &Arc<AppState>,
+    source: &str,
+    target: &PushTarget,
+    info: &Mutex<PushInfo>,
+    stop_rx: &mut watch::Receiver<bool>,
+) -> Result<()> {
+    let lease = acquire_source(state, source).await?;
+    let result = match PushPipeline::start(&lease, target) {
+        Ok(push) => {
+            let result = push.monitor(&lease, info, stop_rx).await;
+            push.stop().await;
+            result
+        }
+        Err(e) => Err(e),
+    };
+    lease.release(state).await;
+    result
+}
+
+// The sink side runs in a pipeline of its own, fed through appsink/appsrc,
+// so a failing target never posts errors on the shared encoder pipeline
+struct PushPipeline {
+    pipeline: gst::Pipeline,
+    branch: TeeBranch,
+}
+
+impl PushPipeline {
+    fn start(lease: &SourceLease, target: &PushTarget) -> Result<Self> {
+        let video_type = tee_media_type(&lease.pipeline, "videotee")
+            .ok_or_else(|| anyhow!("Source {} has no video stream", lease.source))?;
+        // FLV and most SRT receivers expect H.264 and AAC
+        let video_chain = match video_type.as_str() {
+            "video/x-h264" => "h264parse config-interval=-1".to_string(),
+            _ => "decodebin ! videoconvert ! x264enc tune=zerolatency speed-preset=ultrafast key-int-max=60 ! \
+                  h264parse config-interval=-1"
+                .to_string(),
+        };
+        let aac_encoder = find_aac_encoder();
+        let with_audio = tee_media_type(&lease.pipeline, "audiotee").is_some() && aac_encoder.is_some();
+
+        let appsrc = "format=time is-live=true handle-segment-change=true leaky-type=downstream max-time=2000000000";
+        let mut description = format!(
+            "{} appsrc name=pushvideo {} ! queue ! {} ! pushmux.",
+            target.mux_and_sink(),
+            appsrc,
+            video_chain
+        );
+        if with_audio && let Some(aac_encoder) = aac_encoder {
+            description.push_str(&format!(
+                " appsrc name=pushaudio {} ! queue ! opusdec ! audioconvert ! audioresample ! {} ! aacparse ! pushmux.",
+                appsrc, aac_encoder
+            ));
+        }
+        let pipeline = gst::parse::launch(&description)?
+            .downcast::<gst::Pipeline>()
+            .map_err(|_| anyhow!("Push pipeline is not a pipeline"))?;
+
+        if let PushTarget::Srt { latency_ms, passphrase, .. } = target
+            && let Some(sink) = pipeline.by_name("pushsink")
+        {
+            if let Some(latency_ms) = latency_ms {
+                sink.set_property("latency", *latency_ms as i32);
+            }
+            if let Some(passphrase) = passphrase {
+                sink.set_property("passphrase", passphrase);
+            }
+        }
+
+        // Share clock and base time so the forwarded timestamps stay valid
+        if let Some(clock) = lease.pipeline.clock() {
+            pipeline.use_clock(Some(&clock));
+        }
+        if let Some(base_time) = lease.pipeline.base_time() {
+            pipeline.set_base_time(base_time);
+        }
+        pipeline.set_start_time(gst::ClockTime::NONE);
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|e| anyhow!("Failed to start push pipeline: {:?}", e))?;
+
+        match Self::attach_tap(lease, &pipeline, with_audio) {
+            Ok(branch) => Ok(Self { pipeline, branch }),
+            Err(e) => {
+                let _ = pipeline.set_state(gst::State::Null);
+                Err(e)
+            }
+        }
+    }
+
+    fn attach_tap(lease: &SourceLease, pipeline: &gst::Pipeline, with_audio: bool) -> Result<TeeBranch> {
+        let mut description = "queue name=tapvideo leaky=downstream ! appsink name=tapvideosink sync=false".to_string();
+        let mut links = vec![("videotee", "tapvideo", "pushvideo")];
+        if with_audio {
+            description.push_str(" queue name=tapaudio leaky=downstream ! appsink name=tapaudiosink sync=false");
+            links.push(("audiotee", "tapaudio", "pushaudio"));
+        }
+        let bin = gst::parse::bin_from_description_with_name(&description, false, "push-tap")?;
+
+        for (_, queue_name, appsrc_name) in &links {
+            let queue_sink = bin
+                .by_name(queue_name)
+                .and_then(|queue| queue.static_pad("sink"))
+                .ok_or_else(|| anyhow!("Push tap has no {} queue", queue_name))?;
+            bin.add_pad(&gst::GhostPad::builder_with_target(&queue_sink)?.name(*queue_name).build())?;
+
+            let appsink = bin
+                .by_name(&format!("{}sink", queue_name))
+                .and_then(|e| e.downcast::<AppSink>().ok())
+                .ok_or_else(|| anyhow!("Push tap has no appsink"))?;
+            let appsrc = pipeline
+                .by_name(appsrc_name)
+                .and_then(|e| e.downcast::<AppSrc>().ok())
+                .ok_or_else(|| anyhow!("Push pipeline has no {}", appsrc_name))?;
+            forward_samples(&appsink, appsrc, *queue_name == "tapvideo");
+        }
+
+        let links: Vec<(&str, &str)> = links.iter().map(|(tee, pad, _)| (*tee, *pad)).collect();
+        TeeBranch::attach_pads(&lease.pipeline, bin, &links)
+    }
+
+    async fn monitor(
+        &self,
+        lease: &SourceLease,
+        info: &Mutex<PushInfo>,
+        stop_rx: &mut watch::Receiver<bool>,
+    ) -> Result<()> {
+        let bus = self.pipeline.bus().ok_or_else(|| anyhow!("Push pipeline has no bus"))?;
+        let mut messages = bus.stream();
+        // Live pipelines usually reach PLAYING before the bus is watched
+        if self.pipeline.current_state() == gst::State::Playing {
+            set_status(info, PushStatus::Live);
+        }
+        let mut check = tokio::time::interval(PIPELINE_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = stop_rx.changed() => return Ok(()),
+                msg = messages.next() => {
+                    let Some(msg) = msg else {
+                        return Err(anyhow!("Push pipeline bus closed"));
+                    };
+                    match msg.view() {
+                        gst::MessageView::Error(err) => {
+                            return Err(anyhow!("{} ({:?})", err.error(), err.src().map(|s| s.name())));
+                        }
+                        gst::MessageView::Eos(_) => return Err(anyhow!("Target closed the stream")),
+                        gst::MessageView::StateChanged(changed)
+                            if msg.src() == Some(self.pipeline.upcast_ref::<gst::Object>())
+                                && changed.current() == gst::State::Playing =>
+                        {
+                            set_status(info, PushStatus::Live);
+                        }
+                        _ => {}
+                    }
+                }
+                _ = check.tick() => {
+                    // The shared WebRTC pipeline may go away under us
+                    if lease.pipeline.current_state() != gst::State::Playing {
+                        return Err(anyhow!("Source pipeline of {} stopped", lease.source));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn stop(self) {
+        self.branch.detach().await;
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+// Video is only forwarded from a keyframe on, so the muxer starts on a decodable frame
+fn forward_samples(appsink: &AppSink, appsrc: AppSrc, is_video: bool) {
+    let keyframe_seen = AtomicBool::new(!is_video);
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                if !keyframe_seen.load(Ordering::SeqCst) {
+                    let is_keyframe = sample
+                        .buffer()
+                        .is_some_and(|buffer| !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT));
+                    if !is_keyframe {
+                        return Ok(gst::FlowSuccess::Ok);
+                    }
+                    keyframe_seen.store(true, Ordering::SeqCst);
+                }
+                // Target errors surface on the push pipeline bus, never upstream
+                let _ = appsrc.push_sample(&sample);
+                Ok(gst::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+}
@@ -0,0 +1,314 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_video as gst_video;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::{PeerState, ServerSettings};
+
+// Time allowed for the muxer to write its index/trailer after EOS
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    #[default]
+    Mkv,
+    Webm,
+    Mp4,
+}
+
+impl RecordingFormat {
+    fn muxer(self) -> &'static str {
+        match self {
+            Self::Mkv => "matroskamux",
+            Self::Webm => "webmmux",
+            Self::Mp4 => "mp4mux",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Mkv => "mkv",
+            Self::Webm => "webm",
+            Self::Mp4 => "mp4",
+        }
+    }
+
+    fn supports_video(self, media_type: &str) -> bool {
+        match self {
+            Self::Mkv => true,
+            Self::Webm => matches!(media_type, "video/x-vp8" | "video/x-vp9" | "video/x-av1"),
+            Self::Mp4 => matches!(media_type, "video/x-h264" | "video/x-h265" | "video/x-vp9" | "video/x-av1"),
+        }
+    }
+}
+
+// Options of a single recording; unset limits fall back to the server settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingOptions {
+    pub format: RecordingFormat,
+    pub max_size_mb: Option<u64>, // 0 disables size based rotation
+    pub max_duration_secs: Option<u64>, // 0 disables time based rotation
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingInfo {
+    pub id: String,
+    pub format: RecordingFormat,
+    pub location: String, // file pattern, one file per rotated fragment
+    pub started_at: u64, // unix seconds
+}
+
+// Tee branch muxing the already-encoded streams of a pipeline into files
+pub struct Recording {
+    pub info: RecordingInfo,
+    pipeline: gst::Pipeline,
+    bin: gst::Bin,
+    // (tee, requested tee src pad, recording bin sink pad)
+    branches: Vec<(gst::Element, gst::Pad, gst::Pad)>,
+    stopping: Arc<AtomicBool>,
+    finished: oneshot::Receiver<()>,
+}
+
+impl Recording {
+    /// Attaches a recording branch to the `videotee`/`audiotee` of a running pipeline.
+    pub fn start(
+        pipeline: &gst::Pipeline,
+        name_prefix: &str,
+        options: &RecordingOptions,
+        settings: &ServerSettings,
+    ) -> Result<Self> {
+        let video_tee = pipeline
+            .by_name("videotee")
+            .ok_or_else(|| anyhow!("Pipeline has no encoded video branch to record"))?;
+        let audio_tee = pipeline.by_name("audiotee");
+
+        let video_caps = video_tee
+            .static_pad("sink")
+            .and_then(|pad| pad.current_caps().or_else(|| pad.allowed_caps()))
+            .ok_or_else(|| anyhow!("Video stream has not been negotiated yet"))?;
+        let media_type = video_caps
+            .structure(0)
+            .map(|s| s.name().to_string())
+            .ok_or_else(|| anyhow!("Video stream has empty caps"))?;
+        if !options.format.supports_video(&media_type) {
+            return Err(anyhow!(
+                "{:?} cannot hold {} video, use another format",
+                options.format, media_type
+            ));
+        }
+        let video_parser = match media_type.as_str() {
+            "video/x-h264" => "h264parse config-interval=-1 ! ",
+            "video/x-h265" => "h265parse config-interval=-1 ! ",
+            _ => "",
+        };
+
+        std::fs::create_dir_all(&settings.recordings_dir)?;
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let id = Uuid::new_v4().simple().to_string();
+        let location = settings
+            .recordings_dir
+            .join(format!(
+                "{}-{}-%05d.{}",
+                sanitize_file_name(name_prefix),
+                started_at,
+                options.format.extension()
+            ))
+            .to_string_lossy()
+            .into_owned();
+
+        let max_size_bytes = options.max_size_mb.unwrap_or(settings.recording_max_size_mb) * 1024 * 1024;
+        let max_size_time = options.max_duration_secs.unwrap_or(settings.recording_max_duration_secs) * 1_000_000_000;
+        // Keyframe requests only help time based splitting
+        let keyframe_requests = max_size_bytes == 0 && max_size_time > 0;
+
+        let mut description = format!(
+            "queue name=recvideo max-size-buffers=0 max-size-bytes=0 max-size-time=3000000000 ! {}\
+             splitmuxsink name=recmux location=\"{}\" muxer-factory={} \
+             max-size-bytes={} max-size-time={} send-keyframe-requests={}",
+            video_parser,
+            location,
+            options.format.muxer(),
+            max_size_bytes,
+            max_size_time,
+            keyframe_requests
+        );
+        if audio_tee.is_some() {
+            description.push_str(
+                " queue name=recaudio max-size-buffers=0 max-size-bytes=0 max-size-time=3000000000 ! \
+                 opusparse ! recmux.audio_0",
+            );
+        }
+
+        info!("Starting recording {} to {}", id, location);
+        let bin = gst::parse::bin_from_description_with_name(&description, false, &format!("recording-{}", id))?;
+
+        // Own the file sink so the final EOS can be observed after the muxer has finished
+        let filesink = gst::ElementFactory::make("filesink").property("async", false).build()?;
+        bin.by_name("recmux")
+            .ok_or_else(|| anyhow!("Recording bin has no muxer"))?
+            .set_property("sink", &filesink);
+
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (finished_tx, finished) = oneshot::channel();
+        let finished_tx = Mutex::new(Some(finished_tx));
+        let stopping_probe = stopping.clone();
+        filesink
+            .static_pad("sink")
+            .ok_or_else(|| anyhow!("filesink has no sink pad"))?
+            .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                // Fragment rotation also sends EOS to the sink, only the one after stop() counts
+                if let Some(gst::PadProbeData::Event(ref event)) = info.data
+                    && event.type_() == gst::EventType::Eos
+                    && stopping_probe.load(Ordering::SeqCst)
+                    && let Some(tx) = finished_tx.lock().unwrap().take()
+                {
+                    let _ = tx.send(());
+                }
+                gst::PadProbeReturn::Ok
+            });
+
+        pipeline.add(&bin)?;
+        let mut recording = Self {
+            info: RecordingInfo {
+                id,
+                format: options.format,
+                location,
+                started_at,
+            },
+            pipeline: pipeline.clone(),
+            bin: bin.clone(),
+            branches: Vec::new(),
+            stopping,
+            finished,
+        };
+
+        let mut targets = vec![(video_tee, "recvideo")];
+        if let Some(audio_tee) = audio_tee {
+            targets.push((audio_tee, "recaudio"));
+        }
+        for (tee, queue_name) in targets {
+            if let Err(e) = recording.link_branch(tee, queue_name) {
+                recording.discard();
+                return Err(e);
+            }
+        }
+        if let Err(e) = bin.sync_state_with_parent() {
+            recording.discard();
+            return Err(anyhow!("Failed to start recording branch: {}", e));
+        }
+
+        // Recording has to begin on a keyframe
+        if let Some((_, video_pad, _)) = recording.branches.first() {
+            video_pad.send_event(gst_video::UpstreamForceKeyUnitEvent::builder().all_headers(true).build());
+        }
+
+        Ok(recording)
+    }
+
+    fn link_branch(&mut self, tee: gst::Element, queue_name: &str) -> Result<()> {
+        let queue_pad = self
+            .bin
+            .by_name(queue_name)
+            .and_then(|queue| queue.static_pad("sink"))
+            .ok_or_else(|| anyhow!("Recording bin has no {} queue", queue_name))?;
+        let bin_pad = gst::GhostPad::builder_with_target(&queue_pad)?.name(queue_name).build();
+        self.bin.add_pad(&bin_pad)?;
+
+        let tee_pad = tee
+            .request_pad_simple("src_%u")
+            .ok_or_else(|| anyhow!("Failed to request a pad from {}", tee.name()))?;
+        let bin_pad = bin_pad.upcast::<gst::Pad>();
+        self.branches.push((tee, tee_pad.clone(), bin_pad.clone()));
+        tee_pad.link(&bin_pad)?;
+        Ok(())
+    }
+
+    /// Detaches the branch, lets the muxer finalize the current file and
+    /// removes the recording elements from the pipeline.
+    pub async fn stop(self) -> RecordingInfo {
+        info!("Stopping recording {}", self.info.id);
+        self.stopping.store(true, Ordering::SeqCst);
+
+        for (_, tee_pad, bin_pad) in &self.branches {
+            let bin_pad = bin_pad.clone();
+            tee_pad.add_probe(gst::PadProbeType::IDLE, move |pad, _| {
+                let _ = pad.unlink(&bin_pad);
+                bin_pad.send_event(gst::event::Eos::new());
+                gst::PadProbeReturn::Remove
+            });
+        }
+
+        if tokio::time::timeout(FINALIZE_TIMEOUT, self.finished).await.is_err() {
+            warn!("Recording {} did not finalize in time, file may be incomplete", self.info.id);
+        }
+
+        let _ = self.bin.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&self.bin);
+        for (tee, tee_pad, _) in &self.branches {
+            tee.release_request_pad(tee_pad);
+        }
+        info!("Recording {} finalized", self.info.id);
+        self.info
+    }
+
+    // Tears down a branch that failed to start
+    fn discard(&self) {
+        for (tee, tee_pad, bin_pad) in &self.branches {
+            let _ = tee_pad.unlink(bin_pad);
+            tee.release_request_pad(tee_pad);
+        }
+        let _ = self.bin.set_state(gst::State::Null);
+        let _ = self.pipeline.remove(&self.bin);
+    }
+}
+
+/// Starts recording the peer's pipeline, failing if one is already running.
+pub async fn start_peer_recording(
+    peer: &PeerState,
+    options: &RecordingOptions,
+    settings: &ServerSettings,
+) -> Result<RecordingInfo> {
+    let mut recording = peer.recording.lock().await;
+    if recording.is_some() {
+        return Err(anyhow!("A recording is already running for this peer"));
+    }
+    let started = Recording::start(&peer.pipeline, &peer.config.source_type, options, settings)?;
+    let info = started.info.clone();
+    *recording = Some(started);
+    Ok(info)
+}
+
+/// Stops the peer's recording, returning None if nothing was being recorded.
+pub async fn stop_peer_recording(peer: &PeerState) -> Option<RecordingInfo> {
+    let recording = peer.recording.lock().await.take()?;
+    Some(recording.stop().await)
+}
+
+pub fn sanitize_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() { "recording".to_string() } else { name }
+}
+
+/// Default directory for recordings: $XDG_VIDEOS_DIR or ~/Videos, under desktop_streamer/
+pub fn default_recordings_dir() -> PathBuf {
+    let base = std::env::var("XDG_VIDEOS_DIR")
+        .ok()
+        .filter(|dir| Path::new(dir).is_absolute())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var("HOME").ok().map(|home| Path::new(&home).join("Videos")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("desktop_streamer")
+}
@@ -0,0 +1,407 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, tcp::OwnedReadHalf},
+    sync::mpsc,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::broadcast::{SourceLease, acquire_source};
+use crate::config::AppState;
+use crate::media::{TeeBranch, tee_media_type};
+
+// Interleaved frames queued per client; a slow client loses packets instead
+// of back-pressuring the shared encoder
+const CLIENT_QUEUE_FRAMES: usize = 2048;
+// How often a session checks that the pipeline it taps is still running
+const PIPELINE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// One media section of the SDP, fed from a tee of the source pipeline
+struct TrackDescription {
+    tee_name: &'static str,
+    media: &'static str,
+    payload_type: u8,
+    rtpmap: &'static str,
+    fmtp: Option<&'static str>,
+    payloader: &'static str,
+}
+
+impl TrackDescription {
+    fn for_media_type(tee_name: &'static str, media_type: &str) -> Option<Self> {
+        let (media, payload_type, rtpmap, fmtp, payloader) = match media_type {
+            "video/x-h264" => (
+                "video",
+                96,
+                "H264/90000",
+                Some("packetization-mode=1"),
+                "rtph264pay pt=96 config-interval=-1 aggregate-mode=zero-latency",
+            ),
+            "video/x-h265" => ("video", 96, "H265/90000", None, "rtph265pay pt=96 config-interval=-1"),
+            "video/x-vp8" => ("video", 96, "VP8/90000", None, "rtpvp8pay pt=96"),
+            "video/x-vp9" => ("video", 96, "VP9/90000", None, "rtpvp9pay pt=96"),
+            "video/x-av1" => ("video", 96, "AV1/90000", None, "rtpav1pay pt=96"),
+            "audio/x-opus" => ("audio", 97, "opus/48000/2", None, "rtpopuspay pt=97"),
+            _ => return None,
+        };
+        Some(Self {
+            tee_name,
+            media,
+            payload_type,
+            rtpmap,
+            fmtp,
+            payloader,
+        })
+    }
+}
+
+struct RtspRequest {
+    method: String,
+    url: String,
+    headers: HashMap<String, String>,
+}
+
+impl RtspRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+}
+
+// State of one RTSP client connection (one session per connection)
+struct RtspSession {
+    session_id: String,
+    lease: Option<SourceLease>,
+    tracks: Vec<TrackDescription>,
+    // track index -> interleaved RTP channel
+    channels: HashMap<usize, u8>,
+    branches: Vec<TeeBranch>,
+}
+
+/// Serves every source as rtsp://host:port/<source-id> (RTP interleaved over TCP).
+pub async fn run_rtsp_server(state: Arc<AppState>, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    info!("RTSP server listening on rtsp://0.0.0.0:{}/<source-id>", port);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            debug!("RTSP client connected: {}", addr);
+            if let Err(e) = handle_rtsp_connection(stream, state).await {
+                debug!("RTSP connection {} ended: {}", addr, e);
+            }
+            debug!("RTSP client disconnected: {}", addr);
+        });
+    }
+}
+
+async fn handle_rtsp_connection(stream: TcpStream, state: Arc<AppState>) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    // Single writer for responses and interleaved RTP
+    let (frame_tx, mut frame_rx) = mpsc::channel::<Vec<u8>>(CLIENT_QUEUE_FRAMES);
+    tokio::spawn(async move {
+        while let Some(frame) = frame_rx.recv().await {
+            if write_half.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let (request_tx, mut request_rx) = mpsc::channel::<RtspRequest>(8);
+    tokio::spawn(read_requests(read_half, request_tx));
+
+    let mut session = RtspSession {
+        session_id: Uuid::new_v4().simple().to_string()[..16].to_string(),
+        lease: None,
+        tracks: Vec::new(),
+        channels: HashMap::new(),
+        branches: Vec::new(),
+    };
+    let mut check = tokio::time::interval(PIPELINE_CHECK_INTERVAL);
+
+    let result = loop {
+        tokio::select! {
+            request = request_rx.recv() => {
+                let Some(request) = request else {
+                    break Ok(());
+                };
+                let teardown = request.method == "TEARDOWN";
+                let response = handle_request(&state, &mut session, &request, &frame_tx).await;
+                if frame_tx.send(response.into_bytes()).await.is_err() || teardown {
+                    break Ok(());
+                }
+            }
+            _ = check.tick() => {
+                // The shared WebRTC pipeline may go away under us
+                if let Some(lease) = &session.lease
+                    && !session.branches.is_empty()
+                    && lease.pipeline.current_state() != gst::State::Playing
+                {
+                    break Err(anyhow!("Source pipeline of {} stopped", lease.source));
+                }
+            }
+        }
+    };
+
+    for branch in session.branches.drain(..) {
+        branch.detach().await;
+    }
+    if let Some(lease) = session.lease.take() {
+        lease.release(&state).await;
+    }
+    result
+}
+
+// Parses requests, skipping interleaved RTCP sent by the client
+async fn read_requests(read_half: OwnedReadHalf, request_tx: mpsc::Sender<RtspRequest>) -> Result<()> {
+    let mut reader = BufReader::new(read_half);
+    loop {
+        let first = reader.fill_buf().await?;
+        if first.is_empty() {
+            return Ok(());
+        }
+        if first[0] == b'$' {
+            let mut header = [0u8; 4];
+            reader.read_exact(&mut header).await?;
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let mut discard = vec![0u8; len];
+            reader.read_exact(&mut discard).await?;
+            continue;
+        }
+
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(url)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+            }
+        }
+        if let Some(len) = headers.get("content-length").and_then(|l| l.parse::<usize>().ok()) {
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body).await?;
+        }
+
+        let request = RtspRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers,
+        };
+        if request_tx.send(request).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn handle_request(
+    state: &Arc<AppState>,
+    session: &mut RtspSession,
+    request: &RtspRequest,
+    frame_tx: &mpsc::Sender<Vec<u8>>,
+) -> String {
+    let cseq = request.header("cseq").unwrap_or("0").to_string();
+    let (source, control) = split_url(&request.url);
+    debug!("RTSP {} {}", request.method, request.url);
+
+    match request.method.as_str() {
+        "OPTIONS" => response(
+            &cseq,
+            "200 OK",
+            "Public: OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER, SET_PARAMETER\r\n",
+            "",
+        ),
+        "DESCRIBE" => {
+            if source.is_empty() {
+                return response(&cseq, "404 Not Found", "", "");
+            }
+            if let Err(e) = open_source(state, session, &source).await {
+                warn!("RTSP DESCRIBE of {} failed: {}", source, e);
+                return response(&cseq, "503 Service Unavailable", "", "");
+            }
+            let sdp = build_sdp(&source, &session.tracks);
+            let base = format!("Content-Base: {}/\r\nContent-Type: application/sdp\r\n", request.url.trim_end_matches('/'));
+            response(&cseq, "200 OK", &base, &sdp)
+        }
+        "SETUP" => {
+            if session.lease.is_none()
+                && let Err(e) = open_source(state, session, &source).await
+            {
+                warn!("RTSP SETUP of {} failed: {}", source, e);
+                return response(&cseq, "503 Service Unavailable", "", "");
+            }
+            let Some(index) = control
+                .strip_prefix("trackID=")
+                .and_then(|id| id.parse::<usize>().ok())
+                .filter(|index| *index < session.tracks.len())
+            else {
+                return response(&cseq, "404 Not Found", "", "");
+            };
+
+            // Only RTP interleaved in the RTSP connection is supported; clients fall back to it
+            let transport = request.header("transport").unwrap_or_default();
+            if !transport.contains("RTP/AVP/TCP") {
+                return response(&cseq, "461 Unsupported Transport", "", "");
+            }
+            let channel = transport
+                .split(';')
+                .find_map(|part| part.trim().strip_prefix("interleaved="))
+                .and_then(|range| range.split('-').next())
+                .and_then(|start| start.parse::<u8>().ok())
+                .unwrap_or((index * 2) as u8);
+            session.channels.insert(index, channel);
+
+            let headers = format!(
+                "Transport: RTP/AVP/TCP;unicast;interleaved={}-{}\r\nSession: {};timeout=60\r\n",
+                channel,
+                channel.saturating_add(1),
+                session.session_id
+            );
+            response(&cseq, "200 OK", &headers, "")
+        }
+        "PLAY" => {
+            if session.branches.is_empty()
+                && let Err(e) = start_streaming(session, frame_tx)
+            {
+                warn!("RTSP PLAY of {} failed: {}", source, e);
+                return response(&cseq, "500 Internal Server Error", "", "");
+            }
+            let headers = format!("Session: {}\r\nRange: npt=0.000-\r\n", session.session_id);
+            response(&cseq, "200 OK", &headers, "")
+        }
+        "GET_PARAMETER" | "SET_PARAMETER" | "TEARDOWN" => {
+            let headers = format!("Session: {}\r\n", session.session_id);
+            response(&cseq, "200 OK", &headers, "")
+        }
+        _ => response(&cseq, "405 Method Not Allowed", "", ""),
+    }
+}
+
+async fn open_source(state: &Arc<AppState>, session: &mut RtspSession, source: &str) -> Result<()> {
+    if session.lease.is_some() {
+        return Ok(());
+    }
+    let lease = acquire_source(state, source).await?;
+    let mut tracks = Vec::new();
+    for tee_name in ["videotee", "audiotee"] {
+        if let Some(track) = tee_media_type(&lease.pipeline, tee_name)
+            .and_then(|media_type| TrackDescription::for_media_type(tee_name, &media_type))
+        {
+            tracks.push(track);
+        }
+    }
+    if tracks.is_empty() {
+        lease.release(state).await;
+        return Err(anyhow!("Source {} has no stream that can be sent over RTSP", source));
+    }
+    session.tracks = tracks;
+    session.lease = Some(lease);
+    Ok(())
+}
+
+// Attaches a payloader branch per set-up track
+fn start_streaming(session: &mut RtspSession, frame_tx: &mpsc::Sender<Vec<u8>>) -> Result<()> {
+    let lease = session.lease.as_ref().ok_or_else(|| anyhow!("PLAY before SETUP"))?;
+    for (index, channel) in &session.channels {
+        let track = &session.tracks[*index];
+        let bin = gst::parse::bin_from_description(
+            &format!(
+                "queue leaky=downstream max-size-buffers=200 ! {} mtu=1400 ! \
+                 appsink name=rtpsink sync=false drop=true max-buffers=100",
+                track.payloader
+            ),
+            true,
+        )?;
+        let appsink = bin
+            .by_name("rtpsink")
+            .and_then(|e| e.downcast::<AppSink>().ok())
+            .ok_or_else(|| anyhow!("RTSP branch has no appsink"))?;
+
+        let channel = *channel;
+        let frame_tx = frame_tx.clone();
+        appsink.set_callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let mut frame = Vec::with_capacity(map.len() + 4);
+                    frame.push(b'$');
+                    frame.push(channel);
+                    frame.extend_from_slice(&(map.len() as u16).to_be_bytes());
+                    frame.extend_from_slice(map.as_slice());
+                    // Full queue: the client is too slow, drop rather than block the encoder
+                    let _ = frame_tx.try_send(frame);
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        session.branches.push(TeeBranch::attach(&lease.pipeline, track.tee_name, bin)?);
+    }
+    info!("RTSP client started playing {}", lease.source);
+    Ok(())
+}
+
+fn build_sdp(source: &str, tracks: &[TrackDescription]) -> String {
+    let mut sdp = format!(
+        "v=0\r\no=- 0 0 IN IP4 0.0.0.0\r\ns={}\r\nc=IN IP4 0.0.0.0\r\nt=0 0\r\na=control:*\r\n",
+        source
+    );
+    for (index, track) in tracks.iter().enumerate() {
+        sdp.push_str(&format!(
+            "m={} 0 RTP/AVP {}\r\na=rtpmap:{} {}\r\n",
+            track.media, track.payload_type, track.payload_type, track.rtpmap
+        ));
+        if let Some(fmtp) = track.fmtp {
+            sdp.push_str(&format!("a=fmtp:{} {}\r\n", track.payload_type, fmtp));
+        }
+        sdp.push_str(&format!("a=control:trackID={}\r\n", index));
+    }
+    sdp
+}
+
+// "rtsp://host:8554/x11-0/trackID=1" -> ("x11-0", "trackID=1")
+fn split_url(url: &str) -> (String, String) {
+    let path = url
+        .strip_prefix("rtsp://")
+        .and_then(|rest| rest.split_once('/'))
+        .map(|(_, path)| path)
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    match path.split_once('/') {
+        Some((source, control)) => (source.to_string(), control.to_string()),
+        None => (path.to_string(), String::new()),
+    }
+}
+
+fn response(cseq: &str, status: &str, headers: &str, body: &str) -> String {
+    format!(
+        "RTSP/1.0 {}\r\nCSeq: {}\r\nServer: desktop_streamer\r\n{}Content-Length: {}\r\n\r\n{}",
+        status,
+        cseq,
+        headers,
+        body.len(),
+        body
+    )
+}
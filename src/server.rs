@@ -1,6 +1,7 @@
 use anyhow::Result;
 use axum::{
-    extract::{State, WebSocketUpgrade},
+    extract::{ConnectInfo, State, WebSocketUpgrade},
+    http::header,
     response::IntoResponse,
     routing::get,
     Router,
@@ -11,19 +12,46 @@ use tower_http::services::ServeDir;
 use tracing::info;
 use uuid::Uuid;
 
-use crate::{config::{AppState, MonitorInfo}, webrtc_handler, audio_detection::{detect_audio_capabilities, AudioDevice}};
+use crate::virtual_camera::{VIRTUAL_CAMERA_LABEL, device_label};
+use crate::{admin, api, health, hls, metrics, mjpeg, whep, whip, config::{AppState, MonitorInfo, ApplicationStreamInfo}, webrtc_handler, audio_detection::{detect_audio_capabilities, list_playback_streams, AudioDevice}};
 
 pub async fn run_server(app_state: Arc<AppState>, addr: SocketAddr) -> Result<()> {
-    let cors = CorsLayer::new().allow_origin(Any).allow_methods(Any).allow_headers(Any);
+    // WHEP clients read the resource URL from the Location header
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers([header::LOCATION]);
 
     let app = Router::new()
         .route("/", get(serve_index))
         .route("/ws", get(websocket_handler))
+        .merge(api::routes())
+        .merge(admin::routes())
+        .merge(health::routes())
+        .merge(whep::routes())
+        .merge(whip::routes())
+        .merge(hls::routes())
+        .merge(mjpeg::routes())
+        .merge(metrics::routes())
         .fallback_service(ServeDir::new("static"))
-        .with_state(app_state)
+        .with_state(app_state.clone())
         .layer(cors);
 
-    axum_server::bind(addr).serve(app.into_make_service()).await?;
+    // systemd only hears READY once clients can actually connect
+    let handle = axum_server::Handle::new();
+    let listening = handle.clone();
+    tokio::spawn(async move {
+        if listening.listening().await.is_some() {
+            health::run_systemd_notifier(app_state).await;
+        }
+    });
+
+    // Peers keep the client address for the admin API
+    axum_server::bind(addr)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
     Ok(())
 }
 
@@ -54,14 +82,15 @@ async fn serve_index() -> impl IntoResponse {
 
 async fn websocket_handler(
     ws: WebSocketUpgrade,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
     let peer_id = Uuid::new_v4();
-    info!("New WebSocket connection attempt with Peer ID: {}", peer_id);
+    info!("New WebSocket connection attempt with Peer ID: {} from {}", peer_id, remote_addr);
     
     ws.on_upgrade(move |socket| {
         info!("WebSocket upgrade successful for Peer ID: {}", peer_id);
-        webrtc_handler::handle_connection(socket, peer_id, state)
+        webrtc_handler::handle_connection(socket, peer_id, remote_addr, state)
     })
 }
 
@@ -112,7 +141,8 @@ pub async fn detect_monitors(session_type: &str, has_portal: bool) -> Vec<Monito
     // Detect cameras
     for i in 0..5 {
         let device = format!("/dev/video{}", i);
-        if std::path::Path::new(&device).exists() {
+        // Our own virtual camera is an output, never a source
+        if std::path::Path::new(&device).exists() && device_label(&device).as_deref() != Some(VIRTUAL_CAMERA_LABEL) {
             let name = if let Ok(output) = Command::new("v4l2-ctl")
                 .args(&["--device", &device, "--info"])
                 .output()
@@ -180,3 +210,17 @@ pub async fn detect_audio_devices() -> Vec<AudioDevice> {
     
     devices
 }
+
+// Detect running application playback streams
+pub async fn detect_application_streams() -> Vec<ApplicationStreamInfo> {
+    list_playback_streams()
+        .into_iter()
+        .map(|stream| ApplicationStreamInfo {
+            id: stream.index,
+            app_name: stream.app_name,
+            pid: stream.pid,
+            binary: stream.binary,
+            media_name: stream.media_name,
+        })
+        .collect()
+}
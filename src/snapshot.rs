@@ -0,0 +1,86 @@
+use anyhow::{Result, anyhow};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::broadcast::start_raw_video;
+use crate::config::AppState;
+
+// Time to wait for a frame before giving up
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl SnapshotFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+
+    fn encoder(self) -> &'static str {
+        match self {
+            Self::Png => "pngenc",
+            Self::Jpeg => "jpegenc quality=90",
+        }
+    }
+}
+
+/// Captures a single encoded still frame of `source`, tapping its running
+/// pipeline when there is one.
+pub async fn capture_snapshot(
+    state: &AppState,
+    source: &str,
+    format: SnapshotFormat,
+    width: Option<u32>,
+) -> Result<Vec<u8>> {
+    // Height follows the source aspect ratio when only the width is given
+    let scale_caps = match width {
+        Some(width) => format!("video/x-raw,width={},pixel-aspect-ratio=1/1", width),
+        None => "video/x-raw".to_string(),
+    };
+    let encode_str = format!(
+        "videoconvert ! videoscale ! {} ! {} ! appsink name=snapsink sync=false max-buffers=1 drop=true",
+        scale_caps,
+        format.encoder()
+    );
+
+    let mut appsink = None;
+    let tap = start_raw_video(state, source, &encode_str, |bin| {
+        appsink = Some(snapshot_sink(bin)?);
+        Ok(())
+    })
+    .await?;
+    let result = match appsink {
+        Some(appsink) => pull_frame(appsink).await,
+        None => Err(anyhow!("Failed to get snapshot appsink")),
+    };
+    tap.stop().await;
+    result
+}
+
+fn snapshot_sink(bin: &gst::Bin) -> Result<AppSink> {
+    bin.by_name("snapsink")
+        .and_then(|e| e.downcast::<AppSink>().ok())
+        .ok_or_else(|| anyhow!("Failed to get snapshot appsink"))
+}
+
+async fn pull_frame(appsink: AppSink) -> Result<Vec<u8>> {
+    let sample = tokio::task::spawn_blocking(move || {
+        appsink.try_pull_sample(gst::ClockTime::from_seconds(SNAPSHOT_TIMEOUT.as_secs()))
+    })
+    .await?
+    .ok_or_else(|| anyhow!("No frame received within {:?}", SNAPSHOT_TIMEOUT))?;
+    let buffer = sample.buffer().ok_or_else(|| anyhow!("Snapshot sample has no buffer"))?;
+    let map = buffer.map_readable()?;
+    Ok(map.as_slice().to_vec())
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use webrtc::rtcp::{receiver_report::ReceiverReport, sender_report::SenderReport};
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::stats::{ICECandidateStats, StatsReportType};
+
+use crate::config::{PeerState, SignalMessage};
+use crate::media::CapturePath;
+
+// How often get_stats is polled for every peer (and pushed to its client)
+const STATS_INTERVAL: Duration = Duration::from_secs(2);
+
+// Statistics of one outgoing track, as seen by the remote peer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamStats {
+    pub bitrate_bps: u64,
+    pub packets_sent: u64,
+    pub packets_lost: u32, // cumulative, from RTCP receiver reports
+    pub fraction_lost: f64, // 0..1 over the last report interval
+    pub jitter_ms: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub encoder: String, // pipeline variant in use
+    pub capture_path: String, // "dmabuf" (zero-copy) or "system-memory"
+    pub video: StreamStats,
+    pub audio: StreamStats,
+    pub rtt_ms: Option<f64>,
+    pub candidate_pair: Option<CandidatePair>,
+}
+
+// Nominated ICE candidate pair, e.g. "udp 192.168.0.2:50000 (host)"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CandidatePair {
+    pub local: String,
+    pub remote: String,
+}
+
+// Latest statistics of a peer, fed by its RTCP readers and the stats poller
+#[derive(Default)]
+pub struct StatsTracker {
+    latest: Mutex<PeerStats>,
+    // (time, video bytes sent, audio bytes sent) of the previous poll
+    previous_poll: Mutex<Option<(Instant, u64, u64)>>,
+}
+
+impl StatsTracker {
+    pub fn snapshot(&self) -> PeerStats {
+        self.latest.lock().unwrap().clone()
+    }
+
+    fn record_reception(&self, kind: &str, report: &webrtc::rtcp::reception_report::ReceptionReport) {
+        let clock_rate = if kind == "audio" { 48_000.0 } else { 90_000.0 };
+        let mut latest = self.latest.lock().unwrap();
+        let stream = if kind == "audio" { &mut latest.audio } else { &mut latest.video };
+        stream.packets_lost = report.total_lost;
+        stream.fraction_lost = report.fraction_lost as f64 / 256.0;
+        stream.jitter_ms = report.jitter as f64 / clock_rate * 1000.0;
+    }
+
+    async fn poll(&self, peer: &PeerState) {
+        let report = peer.peer_connection.get_stats().await;
+        let (mut video_bytes, mut audio_bytes) = (0u64, 0u64);
+        let (mut video_packets, mut audio_packets) = (0u64, 0u64);
+        let mut remote_rtt = None;
+        let mut pair_rtt = None;
+        let mut pair_ids = None;
+        for stats in report.reports.values() {
+            match stats {
+                StatsReportType::OutboundRTP(outbound) if outbound.kind == "audio" => {
+                    audio_bytes += outbound.bytes_sent;
+                    audio_packets += outbound.packets_sent;
+                }
+                StatsReportType::OutboundRTP(outbound) => {
+                    video_bytes += outbound.bytes_sent;
+                    video_packets += outbound.packets_sent;
+                }
+                StatsReportType::RemoteInboundRTP(remote) => {
+                    remote_rtt = remote.round_trip_time.or(remote_rtt);
+                }
+                StatsReportType::CandidatePair(pair) if pair.nominated => {
+                    pair_rtt = Some(pair.current_round_trip_time);
+                    pair_ids = Some((pair.local_candidate_id.clone(), pair.remote_candidate_id.clone()));
+                }
+                _ => {}
+            }
+        }
+
+        let candidate = |id: &str| match report.reports.get(id) {
+            Some(StatsReportType::LocalCandidate(c) | StatsReportType::RemoteCandidate(c)) => describe_candidate(c),
+            _ => "-".to_string(),
+        };
+        let candidate_pair = pair_ids.map(|(local, remote)| CandidatePair {
+            local: candidate(&local),
+            remote: candidate(&remote),
+        });
+
+        let now = Instant::now();
+        let previous = self.previous_poll.lock().unwrap().replace((now, video_bytes, audio_bytes));
+        let mut latest = self.latest.lock().unwrap();
+        if let Some((at, previous_video, previous_audio)) = previous {
+            let elapsed = now.duration_since(at).as_secs_f64().max(0.001);
+            latest.video.bitrate_bps = (video_bytes.saturating_sub(previous_video) as f64 * 8.0 / elapsed) as u64;
+            latest.audio.bitrate_bps = (audio_bytes.saturating_sub(previous_audio) as f64 * 8.0 / elapsed) as u64;
+        }
+        latest.video.packets_sent = video_packets;
+        latest.audio.packets_sent = audio_packets;
+        // RTCP based RTT when the remote reports it, ICE consent RTT otherwise
+        latest.rtt_ms = remote_rtt.or(pair_rtt).filter(|rtt| *rtt > 0.0).map(|rtt| rtt * 1000.0);
+        latest.candidate_pair = candidate_pair;
+        latest.encoder.clone_from(&peer.pipeline_variant);
+        latest.capture_path = CapturePath::of_variant(&peer.pipeline_variant).as_str().to_string();
+    }
+}
+
+fn describe_candidate(candidate: &ICECandidateStats) -> String {
+    format!(
+        "{} {}:{} ({})",
+        candidate.network_type, candidate.ip, candidate.port, candidate.candidate_type
+    )
+}
+
+/// Reads the RTCP sent back for one of our tracks. Reading is also what lets
+/// webrtc-rs process the reports at all.
+pub fn spawn_rtcp_reader(sender: Arc<RTCRtpSender>, kind: &'static str, tracker: Arc<StatsTracker>) {
+    tokio::spawn(async move {
+        while let Ok((packets, _)) = sender.read_rtcp().await {
+            for packet in packets {
+                let reports = if let Some(rr) = packet.as_any().downcast_ref::<ReceiverReport>() {
+                    &rr.reports
+                } else if let Some(sr) = packet.as_any().downcast_ref::<SenderReport>() {
+                    &sr.reports
+                } else {
+                    continue;
+                };
+                for report in reports {
+                    tracker.record_reception(kind, report);
+                }
+            }
+        }
+        debug!("Stopped reading RTCP of {} track", kind);
+    });
+}
+
+/// Refreshes the peer statistics and sends them to its client until the peer is gone.
+pub fn spawn_stats_poller(peer: Weak<PeerState>) {
+    tokio::spawn(async move {
+        // First poll once the answer has gone out and ICE had a chance to connect
+        let start = tokio::time::Instant::now() + STATS_INTERVAL;
+        let mut interval = tokio::time::interval_at(start, STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(peer) = peer.upgrade() else {
+                break;
+            };
+            peer.stats.poll(&peer).await;
+            let stats = peer.stats.snapshot();
+            if peer.signal_sender.send(SignalMessage::Stats { stats }).is_err() {
+                break;
+            }
+        }
+    });
+}
@@ -1,6 +1,44 @@
-use anyhow::Result;
-use tracing::{info, warn};
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Prefixo dos null sinks criados para captura de áudio por aplicação
+const APP_ROUTE_SINK_PREFIX: &str = "desktop_streamer_app_";
+
+/// Como o áudio do sistema é levado ao sink virtual enquanto um peer transmite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemAudioRouting {
+    /// Nada é roteado automaticamente (comportamento padrão)
+    Off,
+    /// Move os streams existentes e novos para o sink virtual; a reprodução
+    /// local continua através de um loopback para o sink padrão anterior
+    MoveStreams,
+    /// Faz loopback do monitor do sink padrão para o sink virtual
+    LoopDefault,
+}
+
+impl SystemAudioRouting {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" | "" => Some(Self::Off),
+            "move" | "move-streams" => Some(Self::MoveStreams),
+            "loopback" | "loop-default" => Some(Self::LoopDefault),
+            _ => None,
+        }
+    }
+}
+
+/// Estado do roteamento automático, necessário para restaurar os padrões
+#[derive(Debug, Default)]
+struct RoutingState {
+    previous_default_sink: Option<String>,
+    loopback_module_id: Option<u32>,
+}
 
 /// Gerenciador de dispositivos de áudio virtuais
 #[derive(Debug)]
@@ -11,6 +49,9 @@ pub struct VirtualAudioDevice {
     pub source_description: String,
     pub sink_module_id: Option<u32>,
     pub source_module_id: Option<u32>,
+    pub routing_mode: SystemAudioRouting,
+    routing_users: usize,
+    routing_state: Option<RoutingState>,
 }
 
 impl VirtualAudioDevice {
@@ -22,6 +63,9 @@ impl VirtualAudioDevice {
             source_description: "Desktop Streamer Input (Mobile Microphone)".to_string(),
             sink_module_id: None,
             source_module_id: None,
+            routing_mode: SystemAudioRouting::Off,
+            routing_users: 0,
+            routing_state: None,
         }
     }
 
@@ -39,33 +83,14 @@ impl VirtualAudioDevice {
     }
 
     fn create_virtual_sink(&mut self) -> Result<()> {
-        let sink_result = Command::new("pactl")
-            .args([
-                "load-module",
-                "module-null-sink",
-                &format!("sink_name={}", self.sink_name),
-                &format!("sink_properties=device.description='{}'", self.sink_description),
-                "rate=48000",
-                "channels=2"
-            ])
-            .output();
-
-        match sink_result {
-            Ok(output) if output.status.success() => {
-                let module_id_output = String::from_utf8_lossy(&output.stdout);
-                let module_id_str = module_id_output.trim();
-                if let Ok(module_id) = module_id_str.parse::<u32>() {
-                    self.sink_module_id = Some(module_id);
-                    info!("Created virtual sink '{}' with module ID: {}", self.sink_name, module_id);
-                } else {
-                    warn!("Could not parse sink module ID: {}", module_id_str);
-                }
-            }
-            Ok(output) => {
-                warn!("Failed to create virtual sink: {}", String::from_utf8_lossy(&output.stderr));
+        let args = null_sink_args(&self.sink_name, &self.sink_description);
+        match load_module("module-null-sink", &args) {
+            Ok(module_id) => {
+                self.sink_module_id = Some(module_id);
+                info!("Created virtual sink '{}' with module ID: {}", self.sink_name, module_id);
             }
             Err(e) => {
-                warn!("Error creating virtual sink: {}", e);
+                warn!("Failed to create virtual sink: {}", e);
             }
         }
 
@@ -74,48 +99,133 @@ impl VirtualAudioDevice {
 
     fn create_virtual_source(&mut self) -> Result<()> {
         // Criar um sink para o loopback que funcionará como source
-        let source_result = Command::new("pactl")
-            .args([
-                "load-module",
-                "module-null-sink",
-                &format!("sink_name={}_loopback", self.source_name),
-                &format!("sink_properties=device.description='{} Loopback'", self.source_description),
-                "rate=48000",
-                "channels=2"
-            ])
-            .output();
-
-        match source_result {
-            Ok(output) if output.status.success() => {
-                let module_id_output = String::from_utf8_lossy(&output.stdout);
-                let module_id_str = module_id_output.trim();
-                if let Ok(module_id) = module_id_str.parse::<u32>() {
-                    self.source_module_id = Some(module_id);
-                    info!("Created virtual source '{}' with module ID: {}", self.source_name, module_id);
-                } else {
-                    warn!("Could not parse source module ID: {}", module_id_str);
-                }
-            }
-            Ok(output) => {
-                warn!("Failed to create virtual source: {}", String::from_utf8_lossy(&output.stderr));
+        let args = null_sink_args(
+            &format!("{}_loopback", self.source_name),
+            &format!("{} Loopback", self.source_description),
+        );
+        match load_module("module-null-sink", &args) {
+            Ok(module_id) => {
+                self.source_module_id = Some(module_id);
+                info!("Created virtual source '{}' with module ID: {}", self.source_name, module_id);
             }
             Err(e) => {
-                warn!("Error creating virtual source: {}", e);
+                warn!("Failed to create virtual source: {}", e);
             }
         }
 
         Ok(())
     }
 
-    /// Remove os dispositivos virtuais do sistema
-    pub fn remove_virtual_devices(&self) -> Result<()> {
+    /// Registra um peer que transmite o sink virtual; o primeiro ativa o roteamento automático
+    pub fn acquire_system_audio(&mut self) {
+        if self.routing_mode == SystemAudioRouting::Off {
+            return;
+        }
+
+        self.routing_users += 1;
+        if self.routing_users == 1
+            && let Err(e) = self.start_routing()
+        {
+            warn!("Failed to route system audio to '{}': {}", self.sink_name, e);
+        }
+    }
+
+    /// Libera um peer; o último restaura o roteamento original
+    pub fn release_system_audio(&mut self) {
+        if self.routing_users == 0 {
+            return;
+        }
+
+        self.routing_users -= 1;
+        if self.routing_users == 0 {
+            self.stop_routing();
+        }
+    }
+
+    fn start_routing(&mut self) -> Result<()> {
+        if self.sink_module_id.is_none() {
+            return Err(anyhow!("virtual sink was not created"));
+        }
+
+        let default_sink = get_default_sink()?;
+        if default_sink == self.sink_name {
+            return Err(anyhow!("virtual sink is already the default sink"));
+        }
+
+        let mut state = RoutingState::default();
+
+        match self.routing_mode {
+            SystemAudioRouting::Off => return Ok(()),
+            SystemAudioRouting::MoveStreams => {
+                // Manter a reprodução local: monitor do sink virtual -> sink padrão anterior
+                let loopback_id = load_module("module-loopback", &loopback_args(
+                    &self.get_monitor_source_name(),
+                    &default_sink,
+                ))?;
+                state.loopback_module_id = Some(loopback_id);
+                state.previous_default_sink = Some(default_sink.clone());
+                // Guardar o estado antes de mexer nos streams, para que falhas ainda sejam revertidas
+                self.routing_state = Some(state);
+
+                // Streams novos vão para o sink virtual
+                set_default_sink(&self.sink_name)?;
+                let moved = move_streams_between(&default_sink, &self.sink_name, Some(loopback_id));
+                info!(
+                    "Routing system audio to '{}': {} streams moved, local playback via loopback to '{}'",
+                    self.sink_name, moved, default_sink
+                );
+            }
+            SystemAudioRouting::LoopDefault => {
+                let loopback_id = load_module("module-loopback", &loopback_args(
+                    &format!("{}.monitor", default_sink),
+                    &self.sink_name,
+                ))?;
+                state.loopback_module_id = Some(loopback_id);
+                self.routing_state = Some(state);
+                info!("Looping default sink '{}' into '{}'", default_sink, self.sink_name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restaura o sink padrão e os streams; seguro para chamar mais de uma vez
+    fn stop_routing(&mut self) {
+        let Some(state) = self.routing_state.take() else {
+            return;
+        };
+
+        if let Some(previous_default) = &state.previous_default_sink {
+            if let Err(e) = set_default_sink(previous_default) {
+                warn!("Failed to restore default sink '{}': {}", previous_default, e);
+            }
+            let moved = move_streams_between(&self.sink_name, previous_default, state.loopback_module_id);
+            info!("Restored default sink '{}' ({} streams moved back)", previous_default, moved);
+        }
+
+        if let Some(loopback_id) = state.loopback_module_id
+            && let Err(e) = unload_module(loopback_id)
+        {
+            warn!("Failed to remove loopback module {}: {}", loopback_id, e);
+        }
+    }
+
+    /// Remove os dispositivos virtuais do sistema. Caminho único de teardown,
+    /// usado tanto no encerramento quanto no `Drop`; chamadas repetidas não fazem nada.
+    pub fn remove_virtual_devices(&mut self) -> Result<()> {
+        if self.sink_module_id.is_none() && self.source_module_id.is_none() && self.routing_state.is_none() {
+            return Ok(());
+        }
         info!("Removing virtual audio devices...");
 
-        if let Some(sink_id) = self.sink_module_id {
+        self.routing_users = 0;
+        self.stop_routing();
+
+        if let Some(sink_id) = self.sink_module_id.take() {
             self.remove_module(sink_id, "sink")?;
         }
 
-        if let Some(source_id) = self.source_module_id {
+        if let Some(source_id) = self.source_module_id.take() {
             self.remove_module(source_id, "source")?;
         }
 
@@ -123,73 +233,70 @@ impl VirtualAudioDevice {
     }
 
     fn remove_module(&self, module_id: u32, device_type: &str) -> Result<()> {
-        let result = Command::new("pactl")
-            .args(["unload-module", &module_id.to_string()])
-            .output();
-        
-        match result {
-            Ok(output) if output.status.success() => {
-                info!("Removed virtual {} module {}", device_type, module_id);
-            }
-            Ok(output) => {
-                warn!("Failed to remove {} module {}: {}", 
-                     device_type, module_id, String::from_utf8_lossy(&output.stderr));
-            }
-            Err(e) => {
-                warn!("Error removing {} module {}: {}", device_type, module_id, e);
-            }
+        match unload_module(module_id) {
+            Ok(()) => info!("Removed virtual {} module {}", device_type, module_id),
+            Err(e) => warn!("Failed to remove {} module {}: {}", device_type, module_id, e),
         }
-        
+
         Ok(())
     }
 
-    /// Remove todos os dispositivos virtuais desktop_streamer órfãos do sistema
+    /// Remove dispositivos desktop_streamer deixados por instâncias que morreram
+    /// (p.ex. SIGKILL). Módulos de instâncias ainda em execução são preservados.
     pub fn remove_all_orphan_devices() -> Result<()> {
-        info!("Removing all orphan desktop_streamer audio devices...");
-        
-        // Obter lista de módulos carregados
-        let output = Command::new("pactl")
-            .args(["list", "modules", "short"])
-            .output()?;
-            
-        let modules_output = String::from_utf8_lossy(&output.stdout);
+        info!("Reclaiming orphan desktop_streamer audio devices...");
+
+        let loaded_modules = list_loaded_modules()?;
         let mut removed_count = 0;
-        
-        for line in modules_output.lines() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 3 {
-                let module_id = parts[0];
-                let module_type = parts[1];
-                let module_args = parts[2..].join(" ");
-                
-                // Verificar se é um módulo null-sink com nome desktop_streamer
-                if module_type == "module-null-sink" && 
-                   (module_args.contains("desktop_streamer_output") || 
-                    module_args.contains("desktop_streamer_input")) {
-                    
-                    if let Ok(id) = module_id.parse::<u32>() {
-                        let result = Command::new("pactl")
-                            .args(["unload-module", &id.to_string()])
-                            .output();
-                            
-                        match result {
-                            Ok(output) if output.status.success() => {
-                                info!("Removed orphan module {} ({})", id, module_args);
-                                removed_count += 1;
-                            }
-                            Ok(output) => {
-                                warn!("Failed to remove module {}: {}", 
-                                     id, String::from_utf8_lossy(&output.stderr));
-                            }
-                            Err(e) => {
-                                warn!("Error removing module {}: {}", id, e);
-                            }
-                        }
+
+        // 1. Módulos registrados nos arquivos de estado de instâncias mortas
+        for (path, record) in read_ownership_records() {
+            if record.pid == std::process::id() || is_streamer_process_alive(record.pid) {
+                continue;
+            }
+            for module_id in &record.modules {
+                let still_owned = loaded_modules.iter().any(|m| {
+                    m.id == *module_id && m.property("desktop_streamer.instance") == Some(record.instance_id.as_str())
+                });
+                if !still_owned {
+                    continue;
+                }
+                match unload_module(*module_id) {
+                    Ok(()) => {
+                        info!("Removed orphan module {} (state file)", module_id);
+                        removed_count += 1;
                     }
+                    Err(e) => warn!("Failed to remove orphan module {}: {}", module_id, e),
                 }
             }
+            if let Err(e) = std::fs::remove_file(&path) {
+                debug!("Could not remove state file {}: {}", path.display(), e);
+            }
         }
-        
+
+        // 2. Módulos marcados cujo processo dono não existe mais (arquivo de estado perdido)
+        // 3. Módulos sem marcação (versões antigas) só quando nenhuma outra instância está rodando
+        let other_instances_running = other_streamer_processes_running();
+        for module in list_loaded_modules()? {
+            if !module.is_streamer_module() {
+                continue;
+            }
+            let orphan = match module.owner_pid() {
+                Some(pid) => pid != std::process::id() && !is_streamer_process_alive(pid),
+                None => !other_instances_running,
+            };
+            if !orphan {
+                continue;
+            }
+            match unload_module(module.id) {
+                Ok(()) => {
+                    info!("Removed orphan module {} ({})", module.id, module.args);
+                    removed_count += 1;
+                }
+                Err(e) => warn!("Failed to remove orphan module {}: {}", module.id, e),
+            }
+        }
+
         info!("Removed {} orphan desktop_streamer audio devices", removed_count);
         Ok(())
     }
@@ -222,3 +329,365 @@ impl Drop for VirtualAudioDevice {
         }
     }
 }
+
+/// Null sink dedicado que recebe os streams de aplicações escolhidas pelo cliente.
+/// O roteamento original é restaurado em `restore` (ou no `Drop`).
+#[derive(Debug)]
+pub struct ApplicationAudioRoute {
+    pub sink_name: String,
+    pub module_id: Option<u32>,
+    // (sink-input, sink original)
+    moved_streams: Vec<(u32, u32)>,
+}
+
+impl ApplicationAudioRoute {
+    /// Cria o null sink da rota e move os sink-inputs indicados para ele
+    pub fn create(route_id: &str, stream_indices: &[u32]) -> Result<Self> {
+        let sink_name = format!("{}{}", APP_ROUTE_SINK_PREFIX, route_id);
+        let args = null_sink_args(&sink_name, &format!("Desktop Streamer App Capture {}", route_id));
+        let module_id = load_module("module-null-sink", &args)?;
+        info!("Created application capture sink '{}' with module ID: {}", sink_name, module_id);
+
+        let mut route = Self {
+            sink_name,
+            module_id: Some(module_id),
+            moved_streams: Vec::new(),
+        };
+
+        let streams = crate::audio_detection::list_playback_streams();
+        for index in stream_indices {
+            let Some(stream) = streams.iter().find(|s| s.index == *index) else {
+                warn!("Playback stream {} not found, skipping", index);
+                continue;
+            };
+            let Some(original_sink) = stream.sink else {
+                warn!("Playback stream {} has no sink, skipping", index);
+                continue;
+            };
+
+            match move_sink_input(*index, &route.sink_name) {
+                Ok(()) => {
+                    info!("Routed '{}' (stream {}) to '{}'", stream.app_name, index, route.sink_name);
+                    route.moved_streams.push((*index, original_sink));
+                }
+                Err(e) => warn!("Failed to route stream {}: {}", index, e),
+            }
+        }
+
+        if route.moved_streams.is_empty() {
+            return Err(anyhow!("None of the requested playback streams could be routed"));
+        }
+
+        Ok(route)
+    }
+
+    /// Retorna o monitor do sink da rota (usado para captura de áudio)
+    pub fn get_monitor_source_name(&self) -> String {
+        format!("{}.monitor", self.sink_name)
+    }
+
+    /// Devolve os streams aos sinks originais e remove o null sink
+    pub fn restore(&mut self) {
+        for (index, original_sink) in self.moved_streams.drain(..) {
+            // O stream pode já ter terminado; isso não é um erro
+            if let Err(e) = move_sink_input(index, &original_sink.to_string()) {
+                debug!("Could not restore stream {} to sink {}: {}", index, original_sink, e);
+            }
+        }
+
+        if let Some(module_id) = self.module_id.take() {
+            match unload_module(module_id) {
+                Ok(()) => info!("Removed application capture sink '{}'", self.sink_name),
+                Err(e) => warn!("Failed to remove application capture sink '{}': {}", self.sink_name, e),
+            }
+        }
+    }
+}
+
+impl Drop for ApplicationAudioRoute {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+fn loopback_args(source: &str, sink: &str) -> Vec<String> {
+    vec![
+        format!("source={}", source),
+        format!("sink={}", sink),
+        // O proplist inteiro entre aspas é um único argumento do módulo
+        format!("sink_input_properties=\"{}\"", ownership_properties()),
+        "latency_msec=20".to_string(),
+        "source_dont_move=true".to_string(),
+        "sink_dont_move=true".to_string(),
+    ]
+}
+
+fn get_default_sink() -> Result<String> {
+    let output = Command::new("pactl").arg("info").output()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("Default Sink:"))
+        .map(|sink| sink.trim().to_string())
+        .ok_or_else(|| anyhow!("Could not determine the default sink"))
+}
+
+fn set_default_sink(sink: &str) -> Result<()> {
+    let output = Command::new("pactl")
+        .args(["set-default-sink", sink])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+fn get_sink_index(sink_name: &str) -> Option<u32> {
+    let output = Command::new("pactl")
+        .args(["list", "short", "sinks"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout).lines().find_map(|line| {
+        let mut parts = line.split('\t');
+        let index = parts.next()?;
+        if parts.next()? == sink_name {
+            index.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Move todos os sink-inputs de um sink para outro, exceto os do módulo indicado.
+/// Retorna quantos streams foram movidos.
+fn move_streams_between(from_sink: &str, to_sink: &str, skip_module: Option<u32>) -> usize {
+    let Some(from_index) = get_sink_index(from_sink) else {
+        warn!("Sink '{}' not found, no streams moved", from_sink);
+        return 0;
+    };
+
+    let mut moved = 0;
+    for stream in crate::audio_detection::list_playback_streams() {
+        if stream.sink != Some(from_index) || (skip_module.is_some() && stream.owner_module == skip_module) {
+            continue;
+        }
+        match move_sink_input(stream.index, to_sink) {
+            Ok(()) => moved += 1,
+            Err(e) => debug!("Could not move stream {} to '{}': {}", stream.index, to_sink, e),
+        }
+    }
+    moved
+}
+
+fn null_sink_args(sink_name: &str, description: &str) -> Vec<String> {
+    vec![
+        format!("sink_name={}", sink_name),
+        format!(
+            "sink_properties=\"device.description='{}' {}\"",
+            description,
+            ownership_properties()
+        ),
+        "rate=48000".to_string(),
+        "channels=2".to_string(),
+    ]
+}
+
+/// Carrega um módulo no servidor de áudio e retorna o seu ID
+fn load_module(module: &str, args: &[String]) -> Result<u32> {
+    let output = Command::new("pactl")
+        .arg("load-module")
+        .arg(module)
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    let module_id_output = String::from_utf8_lossy(&output.stdout);
+    let module_id_str = module_id_output.trim();
+    let module_id = module_id_str
+        .parse::<u32>()
+        .map_err(|_| anyhow!("Could not parse module ID: {}", module_id_str))?;
+
+    let mut ownership = lock_ownership();
+    ownership.modules.push(module_id);
+    ownership.persist();
+    Ok(module_id)
+}
+
+fn unload_module(module_id: u32) -> Result<()> {
+    let output = Command::new("pactl")
+        .args(["unload-module", &module_id.to_string()])
+        .output()?;
+
+    // Mesmo em caso de falha o módulo não é mais nosso (p.ex. já foi removido)
+    let mut ownership = lock_ownership();
+    ownership.modules.retain(|id| *id != module_id);
+    ownership.persist();
+
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Módulos carregados por esta instância, espelhados num arquivo de estado para
+/// que uma próxima execução possa recuperá-los se o processo for morto.
+#[derive(Debug, Serialize, Deserialize)]
+struct OwnershipRecord {
+    instance_id: String,
+    pid: u32,
+    modules: Vec<u32>,
+}
+
+impl OwnershipRecord {
+    fn state_file(&self) -> PathBuf {
+        ownership_state_dir().join(format!("{}.json", self.instance_id))
+    }
+
+    fn persist(&self) {
+        let path = self.state_file();
+        if self.modules.is_empty() {
+            let _ = std::fs::remove_file(&path);
+            return;
+        }
+
+        let result = std::fs::create_dir_all(ownership_state_dir())
+            .and_then(|_| {
+                let tmp_path = path.with_extension("json.tmp");
+                std::fs::write(&tmp_path, serde_json::to_vec(self).unwrap_or_default())?;
+                std::fs::rename(&tmp_path, &path)
+            });
+        if let Err(e) = result {
+            warn!("Failed to write audio module state file {}: {}", path.display(), e);
+        }
+    }
+}
+
+static OWNERSHIP: Lazy<Mutex<OwnershipRecord>> = Lazy::new(|| {
+    Mutex::new(OwnershipRecord {
+        instance_id: Uuid::new_v4().simple().to_string(),
+        pid: std::process::id(),
+        modules: Vec::new(),
+    })
+});
+
+fn lock_ownership() -> MutexGuard<'static, OwnershipRecord> {
+    OWNERSHIP.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Propriedades que marcam os módulos com a instância e o PID donos
+fn ownership_properties() -> String {
+    let ownership = lock_ownership();
+    format!(
+        "desktop_streamer.instance={} desktop_streamer.pid={}",
+        ownership.instance_id, ownership.pid
+    )
+}
+
+fn ownership_state_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("desktop_streamer")
+}
+
+fn read_ownership_records() -> Vec<(PathBuf, OwnershipRecord)> {
+    let Ok(entries) = std::fs::read_dir(ownership_state_dir()) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| {
+            let record = std::fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<OwnershipRecord>(&data).ok())?;
+            Some((path, record))
+        })
+        .collect()
+}
+
+/// Verifica se o PID pertence a um processo desktop_streamer vivo
+/// (evita confundir PIDs reutilizados por outros programas)
+pub fn is_streamer_process_alive(pid: u32) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|comm| comm.trim().starts_with("desktop_stream"))
+        .unwrap_or(false)
+}
+
+fn other_streamer_processes_running() -> bool {
+    let own_pid = std::process::id();
+    std::fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+                .any(|pid| pid != own_pid && is_streamer_process_alive(pid))
+        })
+        .unwrap_or(false)
+}
+
+#[derive(Debug)]
+struct LoadedModule {
+    id: u32,
+    name: String,
+    args: String,
+}
+
+impl LoadedModule {
+    fn is_streamer_module(&self) -> bool {
+        match self.name.as_str() {
+            "module-null-sink" => {
+                self.args.contains("desktop_streamer_output")
+                    || self.args.contains("desktop_streamer_input")
+                    || self.args.contains(APP_ROUTE_SINK_PREFIX)
+            }
+            "module-loopback" => {
+                self.args.contains("desktop_streamer_output") || self.args.contains("desktop_streamer.pid=")
+            }
+            _ => false,
+        }
+    }
+
+    fn owner_pid(&self) -> Option<u32> {
+        self.property("desktop_streamer.pid")?.parse().ok()
+    }
+
+    /// Valor de uma propriedade de marcação nos argumentos do módulo, que vem
+    /// dentro do proplist entre aspas, p.ex. `sink_properties="... desktop_streamer.pid=42"`
+    fn property(&self, key: &str) -> Option<&str> {
+        let rest = self.args.split(&format!("{}=", key)).nth(1)?;
+        let value = rest.split(|c: char| c.is_whitespace() || c == '"' || c == '\'').next()?;
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+fn list_loaded_modules() -> Result<Vec<LoadedModule>> {
+    let output = Command::new("pactl")
+        .args(["list", "modules", "short"])
+        .output()?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split('\t');
+            let id = parts.next()?.trim().parse().ok()?;
+            let name = parts.next()?.trim().to_string();
+            let args = parts.next().unwrap_or("").trim().to_string();
+            Some(LoadedModule { id, name, args })
+        })
+        .collect())
+}
+
+fn move_sink_input(index: u32, sink: &str) -> Result<()> {
+    let output = Command::new("pactl")
+        .args(["move-sink-input", &index.to_string(), sink])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use anyhow::{anyhow, Result};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::broadcast::{SourceLease, acquire_source, attach_raw_branch, start_raw_pipeline};
+use crate::config::{AppState, SignalMessage};
+use crate::media::{TeeBranch, watch_pipeline_bus};
+use crate::virtual_audio::is_streamer_process_alive;
+
+/// Nome do dispositivo v4l2loopback visto pelos aplicativos de videochamada
+pub const VIRTUAL_CAMERA_LABEL: &str = "Desktop Streamer Camera";
+
+/// Formato escrito na câmera virtual; fixo para que os aplicativos vejam sempre o mesmo modo
+const CAMERA_CAPS: &str = "video/x-raw,format=YUY2,width=1280,height=720,framerate=30/1,pixel-aspect-ratio=1/1";
+
+/// Estado da câmera virtual reportado pela API
+#[derive(Debug, Clone, Serialize)]
+pub struct VirtualCameraStatus {
+    pub device: Option<String>,
+    pub source: Option<String>,
+}
+
+/// Intervalo em que o monitor verifica se a fonte da câmera continua rodando
+const FEED_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Fonte sendo escrita na câmera virtual
+struct CameraFeed {
+    source: String,
+    output: FeedOutput,
+    // Marcado pelo monitor do bus quando o pipeline próprio falha
+    failed: Arc<AtomicBool>,
+}
+
+/// De onde vem o vídeo escrito na câmera
+enum FeedOutput {
+    // Ramo no pipeline que codifica a fonte, mantido vivo pela referência
+    Branch { lease: SourceLease, branch: TeeBranch },
+    // Captura própria, quando a fonte não pôde ser codificada
+    Pipeline(gst::Pipeline),
+}
+
+impl CameraFeed {
+    /// Passa a escrever `source` em `device`
+    async fn open(state: &Arc<AppState>, device: &str, source: &str) -> Result<Self> {
+        let consumer = format!(
+            "videoconvert ! videoscale ! videorate ! {} ! v4l2sink device={} sync=false",
+            CAMERA_CAPS, device
+        );
+        let failed = Arc::new(AtomicBool::new(false));
+        // A referência mantém o pipeline da fonte rodando enquanto a câmera estiver em uso,
+        // mesmo que o visualizador ou cliente que o iniciou saia
+        let output = match acquire_source(state, source).await {
+            Ok(lease) => match attach_raw_branch(&lease.pipeline, &consumer, |_| Ok(())) {
+                Ok(branch) => FeedOutput::Branch { lease, branch },
+                Err(e) => {
+                    lease.release(state).await;
+                    return Err(e);
+                }
+            },
+            Err(e) => {
+                warn!("Could not encode {} for the virtual camera ({}), capturing it directly", source, e);
+                let pipeline = start_raw_pipeline(state, source, &consumer, |_| Ok(()))?;
+                watch_feed_pipeline(&pipeline, source, failed.clone());
+                FeedOutput::Pipeline(pipeline)
+            }
+        };
+        info!("Feeding virtual camera {} with {}", device, source);
+        Ok(Self {
+            source: source.to_string(),
+            output,
+            failed,
+        })
+    }
+
+    fn pipeline(&self) -> &gst::Pipeline {
+        match &self.output {
+            FeedOutput::Branch { lease, .. } => &lease.pipeline,
+            FeedOutput::Pipeline(pipeline) => pipeline,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        let pipeline = self.pipeline();
+        !self.failed.load(Ordering::Relaxed)
+            && (pipeline.current_state() == gst::State::Playing || pipeline.pending_state() == gst::State::Playing)
+    }
+
+    async fn stop(self, state: &AppState) {
+        info!("Stopping virtual camera feed of {}", self.source);
+        match self.output {
+            FeedOutput::Branch { lease, branch } => {
+                branch.detach().await;
+                lease.release(state).await;
+            }
+            FeedOutput::Pipeline(pipeline) => {
+                let _ = pipeline.set_state(gst::State::Null);
+            }
+        }
+    }
+}
+
+/// Câmera virtual (v4l2loopback) alimentada por uma fonte do servidor,
+/// com o mesmo ciclo de vida dos dispositivos de áudio virtuais
+pub struct VirtualCamera {
+    pub device: Option<String>, // /dev/videoN
+    // Dispositivo criado por esta instância (e removido no encerramento)
+    created: bool,
+    feed: Option<CameraFeed>,
+    // Fonte pedida pela API; o monitor refaz o feed enquanto ela estiver definida
+    wanted_source: Option<String>,
+}
+
+impl VirtualCamera {
+    pub fn new() -> Self {
+        Self {
+            device: None,
+            created: false,
+            feed: None,
+            wanted_source: None,
+        }
+    }
+
+    /// Cria o dispositivo v4l2loopback, reaproveitando um já existente com o mesmo nome
+    /// (p.ex. `modprobe v4l2loopback card_label="Desktop Streamer Camera"`)
+    pub fn create_virtual_device(&mut self) -> Result<()> {
+        if !Path::new("/sys/module/v4l2loopback").exists() {
+            return Err(anyhow!(
+                "módulo v4l2loopback não carregado (sudo modprobe v4l2loopback exclusive_caps=1)"
+            ));
+        }
+
+        if let Some(device) = find_device_by_label(VIRTUAL_CAMERA_LABEL) {
+            info!("Using existing virtual camera {}", device);
+            self.device = Some(device);
+            return Ok(());
+        }
+
+        // exclusive_caps: o dispositivo só aparece como câmera enquanto recebe vídeo
+        let output = Command::new("v4l2loopback-ctl")
+            .args(["add", "-n", VIRTUAL_CAMERA_LABEL, "-x", "1"])
+            .output()
+            .map_err(|e| anyhow!("v4l2loopback-ctl indisponível: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+
+        let device = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .find(|word| word.starts_with("/dev/video"))
+            .map(str::to_string)
+            .or_else(|| find_device_by_label(VIRTUAL_CAMERA_LABEL))
+            .ok_or_else(|| anyhow!("v4l2loopback-ctl did not report the created device"))?;
+
+        info!("Created virtual camera {}", device);
+        self.device = Some(device.clone());
+        self.created = true;
+        persist_owned_device(Some(&device));
+        Ok(())
+    }
+
+    /// Passa a escrever `source` (fonte local ou stream WHIP) na câmera virtual
+    pub async fn start_feed(&mut self, state: &Arc<AppState>, source: &str) -> Result<()> {
+        let device = self
+            .device
+            .clone()
+            .ok_or_else(|| anyhow!("Virtual camera is not available"))?;
+        self.stop_feed(state).await;
+
+        self.feed = Some(CameraFeed::open(state, &device, source).await?);
+        self.wanted_source = Some(source.to_string());
+        Ok(())
+    }
+
+    pub async fn stop_feed(&mut self, state: &AppState) {
+        self.wanted_source = None;
+        if let Some(feed) = self.feed.take() {
+            feed.stop(state).await;
+        }
+    }
+
+    /// A fonte só é reportada enquanto o vídeo realmente chega à câmera
+    pub fn status(&self) -> VirtualCameraStatus {
+        VirtualCameraStatus {
+            device: self.device.clone(),
+            source: self
+                .feed
+                .as_ref()
+                .filter(|feed| feed.is_running())
+                .map(|feed| feed.source.clone()),
+        }
+    }
+
+    /// Remove o dispositivo criado por esta instância; chamadas repetidas não fazem nada.
+    /// O feed deve ter sido parado antes (`stop_feed`), senão o dispositivo continua aberto.
+    pub fn remove_virtual_device(&mut self) -> Result<()> {
+        self.wanted_source = None;
+        if self.feed.take().is_some() {
+            warn!("Virtual camera removed while still being fed");
+        }
+        let Some(device) = self.device.take() else {
+            return Ok(());
+        };
+        if !std::mem::take(&mut self.created) {
+            return Ok(());
+        }
+
+        info!("Removing virtual camera {}...", device);
+        delete_device(&device)?;
+        persist_owned_device(None);
+        Ok(())
+    }
+
+    /// Remove câmeras criadas por instâncias que morreram (p.ex. SIGKILL)
+    pub fn remove_all_orphan_devices() -> Result<()> {
+        let Ok(entries) = std::fs::read_dir(state_dir()) else {
+            return Ok(());
+        };
+
+        for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            let Some(record) = std::fs::read(&path)
+                .ok()
+                .and_then(|data| serde_json::from_slice::<CameraOwnership>(&data).ok())
+            else {
+                continue;
+            };
+            if record.pid == std::process::id() || is_streamer_process_alive(record.pid) {
+                continue;
+            }
+            // O número pode ter sido reaproveitado por outra câmera desde então
+            if device_label(&record.device).as_deref() == Some(VIRTUAL_CAMERA_LABEL) {
+                match delete_device(&record.device) {
+                    Ok(()) => info!("Removed orphan virtual camera {}", record.device),
+                    Err(e) => warn!("Failed to remove orphan virtual camera {}: {}", record.device, e),
+                }
+            }
+            if let Err(e) = std::fs::remove_file(&path) {
+                debug!("Could not remove state file {}: {}", path.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reinicia o feed da câmera quando o pipeline compartilhado que ele usava parou
+/// (p.ex. era o de um visualizador WebRTC que saiu), tentando de novo a cada
+/// verificação enquanto a fonte continuar pedida; falhas da captura própria
+/// só são reportadas pelo status
+pub async fn run_feed_monitor(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(FEED_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // O feed é refeito fora do lock, já que iniciar a fonte pode esperar o diálogo do portal
+        let (device, source, stopped) = {
+            let mut camera = state.virtual_camera.lock().await;
+            let Some(camera) = camera.as_mut() else {
+                continue;
+            };
+            let (Some(device), Some(source)) = (camera.device.clone(), camera.wanted_source.clone()) else {
+                continue;
+            };
+            match &camera.feed {
+                Some(feed) if matches!(feed.output, FeedOutput::Pipeline(_)) || feed.is_running() => continue,
+                _ => (device, source, camera.feed.take()),
+            }
+        };
+        if let Some(feed) = stopped {
+            info!("Pipeline feeding the virtual camera stopped, restarting the feed of {}", source);
+            feed.stop(&state).await;
+        }
+        let result = CameraFeed::open(&state, &device, &source).await;
+
+        let discarded = {
+            let mut camera = state.virtual_camera.lock().await;
+            match (camera.as_mut(), result) {
+                (Some(camera), Ok(feed))
+                    if camera.feed.is_none() && camera.wanted_source.as_deref() == Some(source.as_str()) =>
+                {
+                    camera.feed = Some(feed);
+                    None
+                }
+                // A API trocou ou parou o feed enquanto ele era refeito
+                (_, Ok(feed)) => Some(feed),
+                (_, Err(e)) => {
+                    warn!("Failed to restart the virtual camera feed of {}, retrying: {}", source, e);
+                    None
+                }
+            }
+        };
+        if let Some(feed) = discarded {
+            feed.stop(&state).await;
+        }
+    }
+}
+
+// Sem cliente para avisar: erros são registrados e o feed marcado como parado
+fn watch_feed_pipeline(pipeline: &gst::Pipeline, source: &str, failed: Arc<AtomicBool>) {
+    let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<SignalMessage>();
+    watch_pipeline_bus(pipeline, signal_tx);
+    let pipeline = pipeline.downgrade();
+    let source = source.to_string();
+    tokio::spawn(async move {
+        while let Some(msg) = signal_rx.recv().await {
+            if let SignalMessage::Error { message } = msg {
+                warn!("Virtual camera pipeline of {} error: {}", source, message);
+                failed.store(true, Ordering::Relaxed);
+                if let Some(pipeline) = pipeline.upgrade() {
+                    let _ = pipeline.set_state(gst::State::Null);
+                }
+                break;
+            }
+        }
+    });
+}
+
+impl Drop for VirtualCamera {
+    fn drop(&mut self) {
+        if let Err(e) = self.remove_virtual_device() {
+            warn!("Failed to cleanup virtual camera: {}", e);
+        }
+    }
+}
+
+/// Dispositivo criado por uma instância, guardado para ser recuperado se ela for morta
+#[derive(Debug, Serialize, Deserialize)]
+struct CameraOwnership {
+    pid: u32,
+    device: String,
+}
+
+fn state_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("desktop_streamer")
+        .join("cameras")
+}
+
+fn persist_owned_device(device: Option<&str>) {
+    let path = state_dir().join(format!("{}.json", std::process::id()));
+    let Some(device) = device else {
+        let _ = std::fs::remove_file(&path);
+        return;
+    };
+
+    let record = CameraOwnership {
+        pid: std::process::id(),
+        device: device.to_string(),
+    };
+    let result = std::fs::create_dir_all(state_dir())
+        .and_then(|_| std::fs::write(&path, serde_json::to_vec(&record).unwrap_or_default()));
+    if let Err(e) = result {
+        warn!("Failed to write virtual camera state file {}: {}", path.display(), e);
+    }
+}
+
+fn delete_device(device: &str) -> Result<()> {
+    let output = Command::new("v4l2loopback-ctl")
+        .args(["delete", device])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Nome (card label) de um dispositivo /dev/videoN
+pub fn device_label(device: &str) -> Option<String> {
+    let name = Path::new(device).file_name()?.to_str()?;
+    std::fs::read_to_string(format!("/sys/class/video4linux/{}/name", name))
+        .ok()
+        .map(|label| label.trim().to_string())
+}
+
+fn find_device_by_label(label: &str) -> Option<String> {
+    std::fs::read_dir("/sys/class/video4linux")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str().map(str::to_string))
+        .map(|name| format!("/dev/{}", name))
+        .find(|device| device_label(device).as_deref() == Some(label))
+}
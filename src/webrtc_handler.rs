@@ -2,35 +2,61 @@
 use anyhow::{anyhow, Result};
 use axum::extract::ws::{Message, WebSocket};
 use futures::{stream::StreamExt, SinkExt};
-use gstreamer::prelude::*;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 use webrtc::{
     api::{media_engine::MediaEngine, APIBuilder},
+    data_channel::RTCDataChannel,
     ice_transport::{ice_candidate::{RTCIceCandidate, RTCIceCandidateInit}, ice_server::RTCIceServer},
     peer_connection::{
         configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
     },
-    track::track_local::TrackLocal,
-    rtp_transceiver::rtp_receiver::RTCRtpReceiver,
+    rtp_transceiver::rtp_codec::RTPCodecType,
+    track::{track_local::TrackLocal, track_remote::TrackRemote},
+    util::Marshal,
 };
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
 use tokio::sync::mpsc;
 use ashpd::desktop::screencast::{Screencast, SourceType, CursorMode};
 use ashpd::enumflags2::BitFlags;
 
 use crate::{
-    config::{AppState, PeerState, SignalMessage, PipelineConfig, AudioDeviceInfo},
-    media::PipelineFactory,
-    server::{detect_monitors, detect_audio_devices},
+    cursor::{CURSOR_CHANNEL, CURSOR_SHAPE_CHANNEL, CursorChannels, spawn_x11_cursor_stream},
+    config::{AppState, PeerState, SignalMessage, PipelineConfig, AudioDeviceInfo, AudioSourceConfig, AudioProcessingConfig},
+    media::{PipelineFactory, EchoProbe, VideoSource, TEST_SOURCE, build_voice_processing_str, echo_reference_device, set_audio_source_gain, set_pip_layout, watch_pipeline_bus},
+    server::{detect_monitors, detect_audio_devices, detect_application_streams},
+    recording::{RecordingOptions, start_peer_recording, stop_peer_recording},
+    stats::{StatsTracker, spawn_rtcp_reader, spawn_stats_poller},
+    virtual_audio::ApplicationAudioRoute,
+    whip::ingest_monitors,
 };
 
-pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppState>) {
+pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, remote_addr: SocketAddr, state: Arc<AppState>) {
     let (ws_sender, mut ws_receiver) = socket.split();
     let ws_sender = Arc::new(tokio::sync::Mutex::new(ws_sender));
 
-    // Send initial monitor list
-    let monitors = detect_monitors(&state.session_type, state.has_portal).await;
+    // Channel for signaling messages produced outside this loop (pipeline bus, audio levels,
+    // source list changes)
+    let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<SignalMessage>();
+    state.signal_senders.lock().await.insert(peer_id, signal_tx.clone());
+    let ws_sender_signal = ws_sender.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = signal_rx.recv().await {
+            if let Ok(json) = serde_json::to_string(&msg) {
+                let mut sender = ws_sender_signal.lock().await;
+                if sender.send(Message::Text(json.into())).await.is_err() {
+                    warn!(%peer_id, "Failed to send signal message, client disconnected.");
+                    break;
+                }
+            }
+        }
+    });
+
+    // Send initial monitor list, including streams published over WHIP
+    let mut monitors = detect_monitors(&state.session_type, state.has_portal).await;
+    monitors.extend(ingest_monitors(&state).await);
     let monitors_msg = SignalMessage::Monitors { monitors };
     if let Ok(msg_json) = serde_json::to_string(&monitors_msg) {
         let mut sender = ws_sender.lock().await;
@@ -58,6 +84,13 @@ pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppS
         let _ = sender.send(Message::Text(msg_json.into())).await;
     }
 
+    // Send running application streams for per-application capture
+    let streams_msg = SignalMessage::ApplicationStreams { streams: detect_application_streams().await };
+    if let Ok(msg_json) = serde_json::to_string(&streams_msg) {
+        let mut sender = ws_sender.lock().await;
+        let _ = sender.send(Message::Text(msg_json.into())).await;
+    }
+
     while let Some(Ok(msg)) = ws_receiver.next().await {
         if let Message::Text(text) = msg {
             let signal: SignalMessage = match serde_json::from_str(&text) {
@@ -82,9 +115,9 @@ pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppS
                           config.enable_audio, config.audio_source, config.audio_bitrate);
 
                     // Clean up any existing peer state
-                    if let Some(existing_peer) = state.peers.lock().await.remove(&peer_id) {
-                        let _ = existing_peer.pipeline.set_state(gstreamer::State::Null);
-                        let _ = existing_peer.peer_connection.close().await;
+                    let existing_peer = state.peers.lock().await.remove(&peer_id);
+                    if let Some(existing_peer) = existing_peer {
+                        existing_peer.close(&state).await;
                     }
 
                     // Create ICE candidate channel
@@ -120,7 +153,9 @@ pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppS
                         &state,
                         config,
                         ice_tx,
+                        signal_tx.clone(),
                         offer,
+                        Some(remote_addr),
                     ).await {
                         Ok(answer_sdp) => {
                             // Send answer back to client
@@ -164,6 +199,57 @@ pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppS
                         warn!(%peer_id, "Received ICE candidate for non-existent peer.");
                     }
                 }
+                SignalMessage::SetAudioGain { index, gain, muted } => {
+                    if let Some(peer_state) = state.peers.lock().await.get(&peer_id) {
+                        match set_audio_source_gain(&peer_state.pipeline, index, gain, muted) {
+                            Ok(()) => debug!(%peer_id, "Audio source {} gain set to {} (muted: {})", index, gain, muted),
+                            Err(e) => warn!(%peer_id, "Failed to set audio gain: {}", e),
+                        }
+                    } else {
+                        warn!(%peer_id, "Received audio gain change for non-existent peer.");
+                    }
+                }
+                SignalMessage::SetPipLayout { layout } => {
+                    if let Some(peer_state) = state.peers.lock().await.get(&peer_id) {
+                        match set_pip_layout(&peer_state.pipeline, &layout) {
+                            Ok(()) => debug!(%peer_id, "Picture-in-picture layout set to {:?}", layout),
+                            Err(e) => warn!(%peer_id, "Failed to set picture-in-picture layout: {}", e),
+                        }
+                    } else {
+                        warn!(%peer_id, "Received picture-in-picture layout for non-existent peer.");
+                    }
+                }
+                SignalMessage::StartRecording { format, max_size_mb, max_duration_secs } => {
+                    let peer_state = state.peers.lock().await.get(&peer_id).cloned();
+                    let reply = match peer_state {
+                        Some(peer_state) => {
+                            let options = RecordingOptions { format, max_size_mb, max_duration_secs };
+                            match start_peer_recording(&peer_state, &options, &state.settings).await {
+                                Ok(recording) => SignalMessage::RecordingStatus { recording: Some(recording) },
+                                Err(e) => {
+                                    warn!(%peer_id, "Failed to start recording: {}", e);
+                                    SignalMessage::Error { message: format!("Erro ao iniciar gravação: {}", e) }
+                                }
+                            }
+                        }
+                        None => SignalMessage::Error { message: "Nenhuma transmissão ativa para gravar".to_string() },
+                    };
+                    let _ = signal_tx.send(reply);
+                }
+                SignalMessage::StopRecording => {
+                    let peer_state = state.peers.lock().await.get(&peer_id).cloned();
+                    if let Some(peer_state) = peer_state {
+                        stop_peer_recording(&peer_state).await;
+                    }
+                    let _ = signal_tx.send(SignalMessage::RecordingStatus { recording: None });
+                }
+                SignalMessage::ListApplicationStreams => {
+                    let streams_msg = SignalMessage::ApplicationStreams { streams: detect_application_streams().await };
+                    if let Ok(json) = serde_json::to_string(&streams_msg) {
+                        let mut sender = ws_sender.lock().await;
+                        let _ = sender.send(Message::Text(json.into())).await;
+                    }
+                }
                 _ => {
                     debug!(%peer_id, "Received unhandled message type");
                 }
@@ -173,22 +259,28 @@ pub async fn handle_connection(socket: WebSocket, peer_id: Uuid, state: Arc<AppS
 
     // Cleanup when WebSocket closes
     info!("WebSocket connection closed for Peer ID: {}", peer_id);
-    if let Some(peer_state) = state.peers.lock().await.remove(&peer_id) {
-        let _ = peer_state.pipeline.set_state(gstreamer::State::Null);
-        let _ = peer_state.peer_connection.close().await;
+    state.signal_senders.lock().await.remove(&peer_id);
+    let peer_state = state.peers.lock().await.remove(&peer_id);
+    if let Some(peer_state) = peer_state {
+        peer_state.close(&state).await;
         info!("Cleaned up resources for Peer ID: {}", peer_id);
     }
 }
 
-async fn create_peer_connection_and_pipeline(
+pub async fn create_peer_connection_and_pipeline(
     peer_id: Uuid,
     state: &Arc<AppState>,
-    config: PipelineConfig,
+    mut config: PipelineConfig,
     ice_sender: mpsc::UnboundedSender<String>,
+    signal_sender: mpsc::UnboundedSender<SignalMessage>,
     offer: RTCSessionDescription,
+    remote_addr: Option<SocketAddr>,
 ) -> Result<String> {
+    // Only encode video the client can decode
+    config.negotiate_video_codecs(&offer.sdp);
+
     // Get PipeWire node ID if needed for Wayland portal
-    let pipewire_node_id = if config.source_type == "wayland-portal" 
+    let pipewire_node_id = if VideoSource::uses_portal(&config.source_type)
         && state.session_type == "wayland" 
         && state.has_portal {
         match get_wayland_pipewire_node_id().await {
@@ -205,6 +297,29 @@ async fn create_peer_connection_and_pipeline(
         None
     };
 
+    // Viewers always get a picture: unknown or unavailable sources stream the test pattern
+    if VideoSource::select(&config.source_type, pipewire_node_id, &state.session_type, state.has_portal).is_none() {
+        warn!(%peer_id, "Video source {} is not available, streaming the test pattern", config.source_type);
+        config.source_type = TEST_SOURCE.to_string();
+    }
+
+    // Route the selected applications into a dedicated sink and capture its monitor
+    let app_audio_route = if config.enable_audio && !config.audio_applications.is_empty() {
+        let route = ApplicationAudioRoute::create(&peer_id.simple().to_string(), &config.audio_applications)?;
+        info!(%peer_id, "Capturing audio from applications {:?} via '{}'", config.audio_applications, route.sink_name);
+        let monitor = route.get_monitor_source_name();
+        if config.audio_sources.is_empty() {
+            config.audio_source = Some(monitor);
+        } else if let Some(source) = config.audio_sources.iter_mut().find(|s| s.device == "applications") {
+            source.device = monitor;
+        } else {
+            config.audio_sources.push(AudioSourceConfig { device: monitor, gain: 1.0, muted: false });
+        }
+        Some(route)
+    } else {
+        None
+    };
+
     // Create WebRTC peer connection
     let mut m = MediaEngine::default();
     m.register_default_codecs()?;
@@ -224,30 +339,67 @@ async fn create_peer_connection_and_pipeline(
 
     let pc = Arc::new(api.new_peer_connection(rtc_config).await?);
 
+    // The client opens the cursor data channels in its offer when it draws the pointer itself
+    if config.cursor_metadata {
+        match config.source_type.strip_prefix("x11-").and_then(|n| n.parse::<i32>().ok()) {
+            Some(screen_num) => {
+                let cursor_channels = Arc::new(std::sync::Mutex::new(CursorChannels::default()));
+                pc.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+                    if [CURSOR_CHANNEL, CURSOR_SHAPE_CHANNEL].contains(&channel.label()) {
+                        let weak_channel = Arc::downgrade(&channel);
+                        let cursor_channels = cursor_channels.clone();
+                        channel.on_open(Box::new(move || {
+                            if let Some(channel) = weak_channel.upgrade()
+                                && let Some((positions, shapes)) = cursor_channels.lock().unwrap().opened(channel)
+                            {
+                                spawn_x11_cursor_stream(screen_num, positions, shapes);
+                            }
+                            Box::pin(async {})
+                        }));
+                    }
+                    Box::pin(async {})
+                }));
+            }
+            None => {
+                // No cursor stream to replace it (e.g. a picture-in-picture canvas), so the pointer is not hidden
+                warn!(%peer_id, "Cursor metadata is only available for X11 sources, {} keeps the pointer in the video", config.source_type);
+                config.cursor_metadata = false;
+            }
+        }
+    }
+
     // Create media pipeline and tracks
-    let mut pipeline_factory = PipelineFactory::new();
+    let mut pipeline_factory = PipelineFactory::new().with_pipeline_name(format!("peer-{}", peer_id));
     
-    let (pipeline, tracks) = pipeline_factory.create_pipeline(
+    let (pipeline, tracks, variant) = pipeline_factory.create_pipeline(
         &config, 
-        &state.hw_info, 
+        &state.current_hw_info(), 
         pipewire_node_id,
         &state.session_type,
         state.has_portal,
         &state
     ).await?;
 
-    // Add tracks to peer connection
+    info!(%peer_id, "Streaming {} with pipeline variant: {}", config.source_type, variant);
+    watch_pipeline_bus(&pipeline, signal_sender.clone());
+
+    // Add tracks to peer connection, reading back the RTCP of each one
+    let stats = Arc::new(StatsTracker::default());
     for track in &tracks {
-        pc.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+        let kind = if track.kind() == RTPCodecType::Audio { "audio" } else { "video" };
+        let sender = pc.add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>).await?;
+        spawn_rtcp_reader(sender, kind, stats.clone());
     }
     
     // Add incoming audio track handler if microphone input is enabled
     if config.enable_microphone_input {
         let app_state_clone = state.clone();
-        pc.on_track(Box::new(move |track, receiver, _transceiver| {
+        let audio_processing = config.audio_processing.clone();
+        pc.on_track(Box::new(move |track, _receiver, _transceiver| {
             let track_kind = track.kind();
             let track_id = track.id();
             let app_state = app_state_clone.clone();
+            let audio_processing = audio_processing.clone();
             
             info!("Received track from client: {} ({})", track_id, track_kind);
             
@@ -256,7 +408,7 @@ async fn create_peer_connection_and_pipeline(
                 tokio::spawn(async move {
                     info!("Setting up audio input pipeline for client microphone");
                     
-                    if let Err(e) = setup_microphone_playback_pipeline(receiver, &app_state).await {
+                    if let Err(e) = setup_microphone_playback_pipeline(track, &audio_processing, &app_state).await {
                         error!("Failed to setup microphone playback pipeline: {}", e);
                     }
                 });
@@ -290,17 +442,34 @@ async fn create_peer_connection_and_pipeline(
     pc.set_local_description(answer).await?;
 
     // Store peer state
+    let routes_system_audio = config.uses_virtual_output();
     let peer_state = Arc::new(PeerState {
         peer_connection: pc,
         pipeline,
-        ws_sender: tokio::sync::Mutex::new(None), // Will be set later if needed
+        pipeline_variant: variant,
+        config,
+        signal_sender,
+        app_audio_route: tokio::sync::Mutex::new(app_audio_route),
+        routes_system_audio,
+        recording: tokio::sync::Mutex::new(None),
+        stats,
+        remote_addr,
+        connected_at: std::time::Instant::now(),
     });
+    spawn_stats_poller(Arc::downgrade(&peer_state));
+
+    // Route system audio into the virtual sink while this peer streams it (opt-in)
+    if routes_system_audio
+        && let Some(virtual_device) = state.virtual_audio.lock().await.as_mut()
+    {
+        virtual_device.acquire_system_audio();
+    }
     state.peers.lock().await.insert(peer_id, peer_state);
 
     Ok(answer_sdp)
 }
 
-async fn get_wayland_pipewire_node_id() -> Result<u32> {
+pub async fn get_wayland_pipewire_node_id() -> Result<u32> {
     use ashpd::desktop::PersistMode;
     
     let screencast = Screencast::new().await
@@ -346,8 +515,10 @@ async fn get_wayland_pipewire_node_id() -> Result<u32> {
 }
 
 /// Configura pipeline para reproduzir áudio do microfone do cliente
-async fn setup_microphone_playback_pipeline(
-    _receiver: Arc<RTCRtpReceiver>,
+/// (também usado para o áudio publicado via WHIP)
+pub async fn setup_microphone_playback_pipeline(
+    track: Arc<TrackRemote>,
+    audio_processing: &AudioProcessingConfig,
     app_state: &Arc<AppState>,
 ) -> Result<()> {
     
@@ -366,20 +537,54 @@ async fn setup_microphone_playback_pipeline(
     
     info!("Will play client microphone audio to sink: {}", sink_name);
     
+    // Cancelamento de eco usa como referência o áudio enviado ao cliente
+    let echo_probe = EchoProbe::new();
+    let voice_processing = build_voice_processing_str(audio_processing, &echo_probe.name);
+    let echo_probe_branch = if voice_processing.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", echo_probe.build_str(&echo_reference_device(app_state)))
+    };
+    
     // Criar pipeline GStreamer para reproduzir o áudio recebido
     let pipeline_str = format!(
-        "udpsrc port=0 ! application/x-rtp,media=audio,payload=111,encoding-name=OPUS ! \
-         rtpopusdepay ! opusdec ! audioconvert ! audioresample ! \
-         audio/x-raw,rate=48000,channels=2,format=S16LE ! \
-         volume volume=1.0 ! pulsesink device={} sync=false",
-        sink_name
+        "appsrc name=micsrc is-live=true format=time do-timestamp=true \
+         caps=application/x-rtp,media=audio,clock-rate=48000,encoding-name=OPUS,payload={} ! \
+         rtpjitterbuffer latency=50 ! rtpopusdepay ! opusdec ! audioconvert ! audioresample ! \
+         audio/x-raw,rate=48000,channels=2,format=S16LE ! {}\
+         volume volume=1.0 ! pulsesink device={} sync=false{}",
+        track.payload_type(),
+        voice_processing,
+        sink_name,
+        echo_probe_branch
     );
     
     info!("Microphone playback pipeline: {}", pipeline_str);
     
-    // TODO: Integrar com o receiver do WebRTC
-    // Por enquanto, apenas logamos que o pipeline foi configurado
-    info!("Microphone playback pipeline configured (integration pending)");
+    let pipeline = gstreamer::parse::launch(&pipeline_str)?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| anyhow!("Microphone playback pipeline is not a pipeline"))?;
+    let appsrc = pipeline
+        .by_name("micsrc")
+        .and_then(|e| e.downcast::<AppSrc>().ok())
+        .ok_or_else(|| anyhow!("Failed to get microphone appsrc"))?;
+    
+    pipeline.set_state(gstreamer::State::Playing)?;
+    info!("Microphone playback pipeline started");
+    
+    // Encaminhar os pacotes RTP recebidos até o track terminar
+    tokio::spawn(async move {
+        while let Ok((packet, _)) = track.read_rtp().await {
+            let Ok(data) = packet.marshal() else {
+                continue;
+            };
+            if appsrc.push_buffer(gstreamer::Buffer::from_slice(data)).is_err() {
+                break;
+            }
+        }
+        info!("Client microphone track ended, stopping playback pipeline");
+        let _ = pipeline.set_state(gstreamer::State::Null);
+    });
     
     Ok(())
 }
@@ -0,0 +1,210 @@
+use axum::{
+    Router,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+    routing::{patch, post},
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::{
+        peer_connection_state::RTCPeerConnectionState, sdp::session_description::RTCSessionDescription,
+    },
+};
+
+use crate::{
+    config::{AppState, PipelineConfig, SignalMessage},
+    webrtc_handler::create_peer_connection_and_pipeline,
+};
+
+// WHEP answers carry all server candidates, the server side does not trickle
+const ICE_GATHERING_TIMEOUT: Duration = Duration::from_secs(3);
+
+type WhepError = (StatusCode, String);
+
+// WebRTC-HTTP Egress Protocol (draft-ietf-wish-whep) endpoints
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/whep/{source}", post(create_session))
+        .route("/whep/resource/{id}", patch(trickle_ice).delete(delete_session))
+}
+
+// Optional overrides, e.g. /whep/x11-0?audio=alsa_input.usb-mic&hw=false
+#[derive(Debug, Deserialize)]
+struct WhepQuery {
+    audio: Option<String>,
+    hw: Option<bool>,
+    adaptive: Option<bool>,
+}
+
+async fn create_session(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    Path(source): Path<String>,
+    Query(query): Query<WhepQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<impl IntoResponse, WhepError> {
+    require_content_type(&headers, "application/sdp")?;
+    let offer = RTCSessionDescription::offer(body.clone())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid SDP offer: {}", e)))?;
+
+    let peer_id = Uuid::new_v4();
+    let config = PipelineConfig {
+        source_type: source.clone(),
+        enable_audio: body.contains("m=audio"),
+        audio_source: query.audio,
+        use_hardware_encoding: query.hw.unwrap_or(true),
+        adaptive_framerate: query.adaptive.unwrap_or(false),
+        ..Default::default()
+    };
+    info!(%peer_id, "WHEP session requested for source {}", source);
+
+    // No signaling channel: candidates go into the answer and bus messages are only logged
+    let (ice_tx, _) = mpsc::unbounded_channel::<String>();
+    let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<SignalMessage>();
+    tokio::spawn(async move {
+        while let Some(msg) = signal_rx.recv().await {
+            if let SignalMessage::Error { message } = msg {
+                warn!(%peer_id, "WHEP pipeline error: {}", message);
+            }
+        }
+    });
+
+    create_peer_connection_and_pipeline(peer_id, &state, config, ice_tx, signal_tx, offer, Some(remote_addr))
+        .await
+        .map_err(|e| {
+            error!(%peer_id, "Failed to create WHEP session: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create session: {}", e))
+        })?;
+
+    let peer_connection = state
+        .peers
+        .lock()
+        .await
+        .get(&peer_id)
+        .map(|peer| peer.peer_connection.clone())
+        .ok_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR, "Session vanished".to_string()))?;
+
+    // Without a WebSocket the session ends with the peer connection
+    let cleanup_state = state.clone();
+    peer_connection.on_peer_connection_state_change(Box::new(move |connection_state| {
+        let state = cleanup_state.clone();
+        Box::pin(async move {
+            if matches!(connection_state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                close_session(&state, peer_id).await;
+            }
+        })
+    }));
+
+    let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+    if tokio::time::timeout(ICE_GATHERING_TIMEOUT, gathering_complete.recv()).await.is_err() {
+        warn!(%peer_id, "ICE gathering did not complete, answering with the candidates found so far");
+    }
+    let answer = peer_connection
+        .local_description()
+        .await
+        .ok_or_else(|| (StatusCode::INTERNAL_SERVER_ERROR, "Missing local description".to_string()))?;
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, "application/sdp".to_string()),
+            (header::LOCATION, format!("/whep/resource/{}", peer_id)),
+        ],
+        answer.sdp,
+    ))
+}
+
+async fn trickle_ice(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<StatusCode, WhepError> {
+    require_content_type(&headers, "application/trickle-ice-sdpfrag")?;
+    let peer_id = parse_resource_id(&id)?;
+    let peer_connection = state
+        .peers
+        .lock()
+        .await
+        .get(&peer_id)
+        .map(|peer| peer.peer_connection.clone())
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Unknown WHEP resource".to_string()))?;
+
+    for candidate in parse_ice_fragment(&body) {
+        if let Err(e) = peer_connection.add_ice_candidate(candidate).await {
+            warn!(%peer_id, "Failed to add trickled ICE candidate: {}", e);
+            return Err((StatusCode::BAD_REQUEST, format!("Invalid candidate: {}", e)));
+        }
+        debug!(%peer_id, "Trickled ICE candidate added");
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, WhepError> {
+    let peer_id = parse_resource_id(&id)?;
+    if !close_session(&state, peer_id).await {
+        return Err((StatusCode::NOT_FOUND, "Unknown WHEP resource".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn close_session(state: &AppState, peer_id: Uuid) -> bool {
+    let peer = state.peers.lock().await.remove(&peer_id);
+    match peer {
+        Some(peer) => {
+            info!(%peer_id, "Closing WHEP session");
+            peer.close(state).await;
+            true
+        }
+        None => false,
+    }
+}
+
+fn parse_resource_id(id: &str) -> Result<Uuid, WhepError> {
+    Uuid::parse_str(id).map_err(|_| (StatusCode::NOT_FOUND, "Unknown WHEP resource".to_string()))
+}
+
+pub fn require_content_type(headers: &HeaderMap, expected: &str) -> Result<(), (StatusCode, String)> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.split(';').next().map(str::trim) == Some(expected) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("Expected Content-Type {}", expected)))
+    }
+}
+
+/// Candidates of a trickle-ice-sdpfrag body (RFC 8840), shared with WHIP
+pub fn parse_ice_fragment(body: &str) -> Vec<RTCIceCandidateInit> {
+    let mut candidates = Vec::new();
+    let mut mid = None;
+    let mut ufrag = None;
+    for line in body.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("a=mid:") {
+            mid = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag = Some(value.to_string());
+        } else if let Some(candidate) = line.strip_prefix("a=").filter(|c| c.starts_with("candidate:")) {
+            candidates.push(RTCIceCandidateInit {
+                candidate: candidate.to_string(),
+                sdp_mid: mid.clone(),
+                sdp_mline_index: if mid.is_none() { Some(0) } else { None },
+                username_fragment: ufrag.clone(),
+            });
+        }
+    }
+    candidates
+}
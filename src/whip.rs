@@ -0,0 +1,340 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::IntoResponse,
+    routing::{patch, post},
+};
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSrc;
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+use webrtc::{
+    api::{APIBuilder, media_engine::MediaEngine},
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::{
+        RTCPeerConnection, configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState, sdp::session_description::RTCSessionDescription,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp_transceiver::rtp_codec::RTPCodecType,
+    track::track_remote::TrackRemote,
+    util::Marshal,
+};
+
+use crate::{
+    config::{AppState, AudioProcessingConfig, MonitorInfo, SignalMessage},
+    server::detect_monitors,
+    webrtc_handler::setup_microphone_playback_pipeline,
+    whep::{parse_ice_fragment, require_content_type},
+};
+
+// Source ids of published streams: "whip-<name>"
+pub const WHIP_SOURCE_PREFIX: &str = "whip-";
+
+const ICE_GATHERING_TIMEOUT: Duration = Duration::from_secs(3);
+// Keyframe requests to the publisher so decoding can start (and recover) quickly
+const PLI_INTERVAL: Duration = Duration::from_secs(3);
+
+type WhipError = (StatusCode, String);
+
+// A stream published over WHIP
+pub struct IngestSession {
+    pub id: Uuid,
+    pub name: String,
+    pub peer_connection: Arc<RTCPeerConnection>,
+}
+
+impl IngestSession {
+    pub fn source_id(&self) -> String {
+        format!("{}{}", WHIP_SOURCE_PREFIX, self.name)
+    }
+
+    pub fn monitor_info(&self) -> MonitorInfo {
+        MonitorInfo {
+            id: self.source_id(),
+            name: format!("{} (WHIP)", self.name),
+            primary: false,
+            resolution: "Dinâmica".to_string(),
+            source_type: "remote".to_string(),
+        }
+    }
+}
+
+// WebRTC-HTTP Ingestion Protocol (RFC 9725) endpoints
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/whip/{name}", post(create_session))
+        .route("/whip/resource/{id}", patch(trickle_ice).delete(delete_session))
+}
+
+/// Sources published over WHIP, listed next to the local screens and cameras
+pub async fn ingest_monitors(state: &AppState) -> Vec<MonitorInfo> {
+    state.ingests.lock().await.values().map(|ingest| ingest.monitor_info()).collect()
+}
+
+async fn create_session(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<impl IntoResponse, WhipError> {
+    require_content_type(&headers, "application/sdp")?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err((StatusCode::BAD_REQUEST, "Stream name may only contain letters, digits, '-' and '_'".to_string()));
+    }
+    if state.ingests.lock().await.contains_key(&name) {
+        return Err((StatusCode::CONFLICT, format!("Stream '{}' is already being published", name)));
+    }
+    let offer = RTCSessionDescription::offer(body)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid SDP offer: {}", e)))?;
+
+    let id = Uuid::new_v4();
+    info!(%id, "WHIP publish requested for stream '{}'", name);
+    let (peer_connection, answer_sdp) = create_ingest_peer_connection(&state, &name, offer)
+        .await
+        .map_err(|e| {
+            error!(%id, "Failed to accept WHIP publish: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create session: {}", e))
+        })?;
+
+    let session = Arc::new(IngestSession {
+        id,
+        name: name.clone(),
+        peer_connection: peer_connection.clone(),
+    });
+    {
+        let mut ingests = state.ingests.lock().await;
+        if ingests.contains_key(&name) {
+            let _ = peer_connection.close().await;
+            return Err((StatusCode::CONFLICT, format!("Stream '{}' is already being published", name)));
+        }
+        ingests.insert(name.clone(), session);
+    }
+    notify_monitors_changed(&state).await;
+
+    // The publisher going away ends the session like a DELETE
+    let cleanup_state = Arc::downgrade(&state);
+    peer_connection.on_peer_connection_state_change(Box::new(move |connection_state| {
+        let state = cleanup_state.clone();
+        Box::pin(async move {
+            if matches!(connection_state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed)
+                && let Some(state) = state.upgrade()
+            {
+                close_session(&state, id).await;
+            }
+        })
+    }));
+
+    Ok((
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, "application/sdp".to_string()),
+            (header::LOCATION, format!("/whip/resource/{}", id)),
+        ],
+        answer_sdp,
+    ))
+}
+
+async fn create_ingest_peer_connection(
+    state: &Arc<AppState>,
+    name: &str,
+    offer: RTCSessionDescription,
+) -> Result<(Arc<RTCPeerConnection>, String)> {
+    let mut m = MediaEngine::default();
+    m.register_default_codecs()?;
+    let api = APIBuilder::new().with_media_engine(m).build();
+
+    let rtc_config = RTCConfiguration {
+        ice_servers: vec![RTCIceServer {
+            urls: vec!["stun:stun.l.google.com:19302".to_string()],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let pc = Arc::new(api.new_peer_connection(rtc_config).await?);
+
+    let weak_pc = Arc::downgrade(&pc);
+    let app_state = state.clone();
+    let channel = format!("{}{}", WHIP_SOURCE_PREFIX, name);
+    pc.on_track(Box::new(move |track, _receiver, _transceiver| {
+        let weak_pc = weak_pc.clone();
+        let app_state = app_state.clone();
+        let channel = channel.clone();
+        tokio::spawn(async move {
+            info!("WHIP track received for {}: {} ({})", channel, track.id(), track.codec().capability.mime_type);
+            let result = match track.kind() {
+                RTPCodecType::Video => setup_video_ingest_pipeline(track, &channel, weak_pc),
+                // Published audio becomes the virtual microphone
+                RTPCodecType::Audio => {
+                    setup_microphone_playback_pipeline(track, &AudioProcessingConfig::default(), &app_state).await
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                error!("Failed to set up WHIP ingest pipeline for {}: {}", channel, e);
+            }
+        });
+        Box::pin(async {})
+    }));
+
+    pc.set_remote_description(offer).await?;
+    let answer = pc.create_answer(None).await?;
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(answer).await?;
+    if tokio::time::timeout(ICE_GATHERING_TIMEOUT, gathering_complete.recv()).await.is_err() {
+        warn!("ICE gathering for WHIP stream '{}' did not complete in time", name);
+    }
+    let answer_sdp = pc
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow!("Missing local description"))?
+        .sdp;
+
+    Ok((pc, answer_sdp))
+}
+
+// Decodes a published video track into an inter-pipeline channel read by
+// `intervideosrc` when a viewer selects the "whip-<name>" source
+fn setup_video_ingest_pipeline(
+    track: Arc<TrackRemote>,
+    channel: &str,
+    peer_connection: Weak<RTCPeerConnection>,
+) -> Result<()> {
+    let codec = track.codec();
+    let (encoding_name, depay) = match codec.capability.mime_type.to_lowercase().as_str() {
+        "video/vp8" => ("VP8", "rtpvp8depay"),
+        "video/vp9" => ("VP9", "rtpvp9depay"),
+        "video/h264" => ("H264", "rtph264depay"),
+        "video/av1" => ("AV1", "rtpav1depay"),
+        other => return Err(anyhow!("Unsupported WHIP video codec {}", other)),
+    };
+
+    let pipeline_str = format!(
+        "appsrc name=whipsrc is-live=true format=time do-timestamp=true \
+         caps=application/x-rtp,media=video,clock-rate=90000,encoding-name={},payload={} ! \
+         rtpjitterbuffer latency=100 ! {} ! decodebin ! videoconvert ! \
+         intervideosink channel={} sync=false",
+        encoding_name,
+        track.payload_type(),
+        depay,
+        channel
+    );
+    info!("WHIP video ingest pipeline: {}", pipeline_str);
+
+    let pipeline = gst::parse::launch(&pipeline_str)?
+        .downcast::<gst::Pipeline>()
+        .map_err(|_| anyhow!("WHIP ingest pipeline is not a pipeline"))?;
+    let appsrc = pipeline
+        .by_name("whipsrc")
+        .and_then(|e| e.downcast::<AppSrc>().ok())
+        .ok_or_else(|| anyhow!("Failed to get WHIP appsrc"))?;
+    pipeline.set_state(gst::State::Playing)?;
+
+    let media_ssrc = track.ssrc();
+    let pli_pc = peer_connection.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PLI_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(pc) = pli_pc.upgrade() else { break };
+            let pli = PictureLossIndication { sender_ssrc: 0, media_ssrc };
+            if pc.write_rtcp(&[Box::new(pli)]).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Feed RTP packets until the publisher stops
+    let channel = channel.to_string();
+    tokio::spawn(async move {
+        while let Ok((packet, _)) = track.read_rtp().await {
+            let Ok(data) = packet.marshal() else {
+                continue;
+            };
+            if appsrc.push_buffer(gst::Buffer::from_slice(data)).is_err() {
+                break;
+            }
+        }
+        info!("WHIP video track for {} ended", channel);
+        let _ = pipeline.set_state(gst::State::Null);
+    });
+
+    Ok(())
+}
+
+async fn trickle_ice(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<StatusCode, WhipError> {
+    require_content_type(&headers, "application/trickle-ice-sdpfrag")?;
+    let id = parse_resource_id(&id)?;
+    let peer_connection = state
+        .ingests
+        .lock()
+        .await
+        .values()
+        .find(|ingest| ingest.id == id)
+        .map(|ingest| ingest.peer_connection.clone())
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Unknown WHIP resource".to_string()))?;
+
+    for candidate in parse_ice_fragment(&body) {
+        if let Err(e) = peer_connection.add_ice_candidate(candidate).await {
+            warn!(%id, "Failed to add trickled ICE candidate: {}", e);
+            return Err((StatusCode::BAD_REQUEST, format!("Invalid candidate: {}", e)));
+        }
+        debug!(%id, "Trickled ICE candidate added");
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, WhipError> {
+    let id = parse_resource_id(&id)?;
+    if !close_session(&state, id).await {
+        return Err((StatusCode::NOT_FOUND, "Unknown WHIP resource".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn close_session(state: &AppState, id: Uuid) -> bool {
+    let session = {
+        let mut ingests = state.ingests.lock().await;
+        let name = ingests.values().find(|ingest| ingest.id == id).map(|ingest| ingest.name.clone());
+        name.and_then(|name| ingests.remove(&name))
+    };
+    let Some(session) = session else {
+        return false;
+    };
+
+    info!(%id, "Closing WHIP stream '{}'", session.name);
+    // Ends the tracks, which stops their ingest pipelines
+    let _ = session.peer_connection.close().await;
+    notify_monitors_changed(state).await;
+    true
+}
+
+// Pushes the updated source list to every connected client, including those
+// still picking a source
+async fn notify_monitors_changed(state: &AppState) {
+    let mut monitors = detect_monitors(&state.session_type, state.has_portal).await;
+    monitors.extend(ingest_monitors(state).await);
+    for signal_sender in state.signal_senders.lock().await.values() {
+        let _ = signal_sender.send(SignalMessage::Monitors { monitors: monitors.clone() });
+    }
+}
+
+fn parse_resource_id(id: &str) -> Result<Uuid, WhipError> {
+    Uuid::parse_str(id).map_err(|_| (StatusCode::NOT_FOUND, "Unknown WHIP resource".to_string()))
+}
@@ -13,7 +13,11 @@ const placeholder = document.getElementById('placeholder');
 const connectBtn = document.getElementById('connectBtn');
 const disconnectBtn = document.getElementById('disconnectBtn');
 const vrBtn = document.getElementById('vrBtn');
+const recordBtn = document.getElementById('recordBtn');
+const recordBtnText = document.getElementById('recordBtnText');
+const recordFormat = document.getElementById('recordFormat');
 const sourceSelect = document.getElementById('sourceSelect');
+const pipCameraSelect = document.getElementById('pipCameraSelect');
 const vrMode = document.getElementById('vrMode');
 
 // New UI elements
@@ -37,6 +41,15 @@ const enableMicrophone = document.getElementById('enableMicrophone');
 const audioSourceSelect = document.getElementById('audioSourceSelect');
 const audioBitrate = document.getElementById('audioBitrate');
 const audioSourceGroup = document.getElementById('audioSourceGroup');
+const appStreamsGroup = document.getElementById('appStreamsGroup');
+const appStreamsList = document.getElementById('appStreamsList');
+const appStreamsRefresh = document.getElementById('appStreamsRefresh');
+const enableMixer = document.getElementById('enableMixer');
+const mixerMicSelect = document.getElementById('mixerMicSelect');
+const systemGain = document.getElementById('systemGain');
+const micGain = document.getElementById('micGain');
+const vuMeters = document.getElementById('vuMeters');
+const enableVoiceProcessing = document.getElementById('enableVoiceProcessing');
 
 // Statistics tracking
 let stats = {
@@ -57,10 +70,19 @@ let currentAudioConfig = {
     enabled: false,
     microphoneEnabled: false,
     source: null,
-    bitrate: 128000
+    bitrate: 128000,
+    applications: [],
+    voiceProcessing: false,
+    mixer: {
+        enabled: false,
+        microphone: null,
+        systemGain: 1.0,
+        micGain: 1.0
+    }
 };
 
 let microphoneStream = null;
+let activeRecording = null;
 
 // Initialize event listeners
 document.addEventListener('DOMContentLoaded', function() {
@@ -93,6 +115,17 @@ function initializeEventListeners() {
     if (enableMicrophone) enableMicrophone.addEventListener('change', toggleMicrophone);
     if (audioSourceSelect) audioSourceSelect.addEventListener('change', updateAudioSource);
     if (audioBitrate) audioBitrate.addEventListener('change', updateAudioBitrate);
+    if (appStreamsRefresh) appStreamsRefresh.addEventListener('click', requestApplicationStreams);
+    if (enableVoiceProcessing) enableVoiceProcessing.addEventListener('change', toggleVoiceProcessing);
+    if (pipCameraSelect) pipCameraSelect.addEventListener('change', updatePipControls);
+    ['pipPosition', 'pipSize', 'pipOpacity'].forEach(id => {
+        const control = document.getElementById(id);
+        if (control) control.addEventListener('input', sendPipLayout);
+    });
+    if (enableMixer) enableMixer.addEventListener('change', updateMixerConfig);
+    if (mixerMicSelect) mixerMicSelect.addEventListener('change', updateMixerConfig);
+    if (systemGain) systemGain.addEventListener('input', () => updateMixerGain(0, systemGain));
+    if (micGain) micGain.addEventListener('input', () => updateMixerGain(1, micGain));
     
     // Keyboard shortcuts
     document.addEventListener('keydown', handleKeyboardShortcuts);
@@ -311,7 +344,8 @@ function log(message, type = 'info') {
 function updateSourceList(monitors) {
     log(`Recebido lista de ${monitors.length} fontes disponíveis`, 'info');
     
-    // Clear existing options
+    // Clear existing options, keeping the current selection when it still exists
+    const previousSource = sourceSelect.value;
     sourceSelect.innerHTML = '';
     
     // Create groups
@@ -321,6 +355,9 @@ function updateSourceList(monitors) {
     const cameraGroup = document.createElement('optgroup');
     cameraGroup.label = 'Câmeras';
     
+    const remoteGroup = document.createElement('optgroup');
+    remoteGroup.label = 'Remotas (WHIP)';
+    
     // Add monitors and cameras
     monitors.forEach(monitor => {
         const option = document.createElement('option');
@@ -329,6 +366,9 @@ function updateSourceList(monitors) {
         if (monitor.source_type === 'camera') {
             option.textContent = `📹 ${monitor.name}`;
             cameraGroup.appendChild(option);
+        } else if (monitor.source_type === 'remote') {
+            option.textContent = `📡 ${monitor.name}`;
+            remoteGroup.appendChild(option);
         } else {
             const icon = monitor.primary ? '🖥️ ' : '📺 ';
             option.textContent = `${icon}${monitor.name} (${monitor.resolution})`;
@@ -339,6 +379,17 @@ function updateSourceList(monitors) {
         }
     });
     
+    // Cameras that can be composited over a screen
+    if (pipCameraSelect) {
+        const previousCamera = pipCameraSelect.value;
+        pipCameraSelect.innerHTML = '<option value="">Sem câmera sobreposta</option>';
+        Array.from(cameraGroup.children).forEach(option => pipCameraSelect.appendChild(option.cloneNode(true)));
+        if (Array.from(pipCameraSelect.options).some(o => o.value === previousCamera)) {
+            pipCameraSelect.value = previousCamera;
+        }
+        updatePipControls();
+    }
+    
     // Add groups to select
     if (screenGroup.children.length > 0) {
         sourceSelect.appendChild(screenGroup);
@@ -348,6 +399,10 @@ function updateSourceList(monitors) {
         sourceSelect.appendChild(cameraGroup);
     }
     
+    if (remoteGroup.children.length > 0) {
+        sourceSelect.appendChild(remoteGroup);
+    }
+    
     // Fallback if no sources
     if (sourceSelect.options.length === 0) {
         sourceSelect.innerHTML = `
@@ -357,6 +412,10 @@ function updateSourceList(monitors) {
         `;
     }
     
+    if (previousSource && Array.from(sourceSelect.options).some(o => o.value === previousSource)) {
+        sourceSelect.value = previousSource;
+    }
+    
     log(`Lista de fontes atualizada: ${sourceSelect.options.length} opções disponíveis`, 'success');
 }
 
@@ -546,6 +605,7 @@ async function setupWebRTC(sourceType) {
                 case 'connected':
                     updateStatus('connected', 'Conectado');
                     disconnectBtn.disabled = false;
+                    if (recordBtn) recordBtn.disabled = false;
                     log('🎉 Streaming ativo!', 'success');
                     break;
                 case 'connecting':
@@ -572,6 +632,16 @@ async function setupWebRTC(sourceType) {
             });
         }
         
+        // The server only sends the cursor as metadata over channels opened in the offer:
+        // positions unreliable (a lost one is superseded by the next), shapes reliable
+        const cursorMetadata = document.getElementById('enableCursorMetadata')?.checked || false;
+        if (cursorMetadata) {
+            setupCursorChannels(
+                pc.createDataChannel('cursor', { ordered: false, maxRetransmits: 0 }),
+                pc.createDataChannel('cursor-shape')
+            );
+        }
+        
         // Create offer to receive video and audio from server
         const offer = await pc.createOffer({
             offerToReceiveVideo: true,
@@ -590,10 +660,20 @@ async function setupWebRTC(sourceType) {
             enable_audio: currentAudioConfig.enabled,
             enable_microphone_input: currentAudioConfig.microphoneEnabled,
             audio_bitrate: currentAudioConfig.bitrate,
+            audio_applications: currentAudioConfig.enabled ? currentAudioConfig.applications : [],
+            audio_sources: buildMixerSources(),
+            audio_processing: {
+                echo_cancellation: currentAudioConfig.voiceProcessing,
+                noise_suppression: currentAudioConfig.voiceProcessing,
+                auto_gain_control: currentAudioConfig.voiceProcessing
+            },
             audio_sample_rate: 48000,
             resolution: [1920, 1080],
             framerate: 30,
-            use_hardware_encoding: true
+            use_hardware_encoding: true,
+            adaptive_framerate: document.getElementById('enableAdaptiveFramerate')?.checked || false,
+            cursor_metadata: cursorMetadata,
+            pip_layout: currentPipLayout()
         };
         
         log(`Configuração enviada: ${JSON.stringify(config)}`, 'debug');
@@ -653,7 +733,7 @@ async function handleSignalingMessage(event) {
                         // Launch as a separate async task to not block the switch case
                         (async () => {
                             try {
-                                await setupWebRTC(sourceType);
+                                await setupWebRTC(withPictureInPicture(sourceType));
                                 log('setupWebRTC chamado com sucesso a partir do case "monitors".', 'success');
                             } catch (e) {
                                 log(`ERRO ao chamar setupWebRTC a partir do case "monitors": ${e.message}`, 'error');
@@ -671,6 +751,22 @@ async function handleSignalingMessage(event) {
                 populateAudioDevices(signal.devices);
                 break;
                 
+            case 'application-streams':
+                populateApplicationStreams(signal.streams);
+                break;
+                
+            case 'audio-level':
+                updateVuMeter(signal.source, signal.peak, signal.rms);
+                break;
+                
+            case 'recording-status':
+                updateRecordingState(signal.recording);
+                break;
+                
+            case 'stats':
+                updateServerStats(signal.stats);
+                break;
+                
             case 'answer':
                 if (pc) {
                     log('Resposta recebida do servidor', 'info');
@@ -919,6 +1015,7 @@ function getAudioSourceDescription(sourceId) {
 
 function populateAudioDevices(devices) {
     audioDevices = devices;
+    populateMixerMicrophones(devices);
     log(`Recebidos ${devices.length} dispositivos de áudio`, 'info');
     
     // Clear existing options except the first one and virtual option
@@ -994,6 +1091,251 @@ function populateAudioDevices(devices) {
     log(`${devices.length} dispositivos de áudio detectados`, 'success');
 }
 
+function populateApplicationStreams(streams) {
+    if (!appStreamsList) return;
+    
+    // Keep only selections that still exist
+    const ids = streams.map(stream => stream.id);
+    currentAudioConfig.applications = currentAudioConfig.applications.filter(id => ids.includes(id));
+    
+    appStreamsList.innerHTML = '';
+    streams.forEach(stream => {
+        const label = document.createElement('label');
+        const checkbox = document.createElement('input');
+        checkbox.type = 'checkbox';
+        checkbox.value = stream.id;
+        checkbox.checked = currentAudioConfig.applications.includes(stream.id);
+        checkbox.addEventListener('change', updateApplicationSelection);
+        
+        label.appendChild(checkbox);
+        label.appendChild(document.createTextNode(stream.pid ? `${stream.app_name} (${stream.pid})` : stream.app_name));
+        label.title = stream.media_name || '';
+        appStreamsList.appendChild(label);
+    });
+    
+    appStreamsGroup.style.display = streams.length > 0 ? 'flex' : 'none';
+    log(`${streams.length} aplicações com áudio detectadas`, 'info');
+}
+
+function updateApplicationSelection() {
+    currentAudioConfig.applications = Array.from(appStreamsList.querySelectorAll('input:checked'))
+        .map(checkbox => parseInt(checkbox.value));
+    
+    if (currentAudioConfig.applications.length > 0) {
+        log(`Capturando áudio apenas das aplicações: ${currentAudioConfig.applications.join(', ')}`, 'info');
+    } else {
+        log(`Captura por aplicação desativada`, 'info');
+    }
+    
+    updateConnectionConfig();
+}
+
+function populateMixerMicrophones(devices) {
+    if (!mixerMicSelect) return;
+    
+    mixerMicSelect.innerHTML = '';
+    devices
+        .filter(device => device.device_type === 'microphone')
+        .forEach(device => {
+            const option = document.createElement('option');
+            option.value = device.id;
+            option.textContent = device.name;
+            mixerMicSelect.appendChild(option);
+        });
+    
+    currentAudioConfig.mixer.microphone = mixerMicSelect.value || null;
+}
+
+function updateMixerConfig() {
+    currentAudioConfig.mixer.enabled = enableMixer.checked && !!mixerMicSelect.value;
+    currentAudioConfig.mixer.microphone = mixerMicSelect.value || null;
+    
+    if (currentAudioConfig.mixer.enabled) {
+        log(`Mixer ativo: sistema + ${currentAudioConfig.mixer.microphone}`, 'info');
+    } else {
+        log('Mixer desativado', 'info');
+    }
+    
+    updateConnectionConfig();
+}
+
+function buildMixerSources() {
+    const mixer = currentAudioConfig.mixer;
+    if (!currentAudioConfig.enabled || !mixer.enabled || !mixer.microphone) {
+        return [];
+    }
+    
+    // Index 0 is the system source, index 1 the local microphone (see updateMixerGain)
+    return [
+        { device: currentAudioConfig.source || 'desktop_streamer_virtual', gain: mixer.systemGain, muted: false },
+        { device: mixer.microphone, gain: mixer.micGain, muted: false }
+    ];
+}
+
+function updateMixerGain(index, slider) {
+    const gain = parseFloat(slider.value);
+    if (index === 0) {
+        currentAudioConfig.mixer.systemGain = gain;
+    } else {
+        currentAudioConfig.mixer.micGain = gain;
+    }
+    
+    // Gains are applied live while streaming
+    if (currentAudioConfig.mixer.enabled && ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ type: 'set-audio-gain', index, gain, muted: gain === 0 }));
+    }
+}
+
+// Map a dBFS value (-60..0) to a 0..100% meter width
+function dbToPercent(db) {
+    if (!isFinite(db)) return 0;
+    return Math.max(0, Math.min(100, (db + 60) / 60 * 100));
+}
+
+function updateVuMeter(source, peak, rms) {
+    if (!vuMeters) return;
+    
+    let meter = document.getElementById(`vuMeter${source}`);
+    if (!meter) {
+        meter = document.createElement('div');
+        meter.id = `vuMeter${source}`;
+        meter.className = 'vu-meter';
+        meter.innerHTML = '<div class="vu-peak"></div><div class="vu-rms"></div>';
+        vuMeters.appendChild(meter);
+    }
+    
+    // Show the loudest channel
+    const maxPeak = Math.max(...peak);
+    const maxRms = Math.max(...rms);
+    meter.querySelector('.vu-peak').style.width = `${dbToPercent(maxPeak)}%`;
+    meter.querySelector('.vu-rms').style.width = `${dbToPercent(maxRms)}%`;
+}
+
+function toggleRecording() {
+    if (!ws || ws.readyState !== WebSocket.OPEN) return;
+    
+    if (activeRecording) {
+        log('Finalizando gravação...', 'info');
+        ws.send(JSON.stringify({ type: 'stop-recording' }));
+    } else {
+        const format = recordFormat ? recordFormat.value : 'mkv';
+        log(`Iniciando gravação (${format})...`, 'info');
+        ws.send(JSON.stringify({ type: 'start-recording', format }));
+    }
+}
+
+function updateRecordingState(recording) {
+    if (recording && !activeRecording) {
+        log(`⏺ Gravando em ${recording.location}`, 'success');
+    } else if (!recording && activeRecording) {
+        log('⏹ Gravação finalizada', 'success');
+    }
+    activeRecording = recording;
+    
+    if (!recordBtn) return;
+    recordBtn.disabled = !pc;
+    recordBtn.classList.toggle('recording', !!recording);
+    if (recordBtnText) recordBtnText.textContent = recording ? 'Parar' : 'Gravar';
+    if (recordFormat) recordFormat.disabled = !!recording;
+}
+
+// Server-side view of the stream, pushed periodically by the server
+function updateServerStats(serverStats) {
+    const setText = (id, text) => {
+        const element = document.getElementById(id);
+        if (element) element.textContent = text;
+    };
+    const video = serverStats.video || {};
+    setText('serverEncoder', serverStats.encoder || '-');
+    setText('serverCapturePath', serverStats.capture_path === 'dmabuf' ? 'DMA-BUF (zero-copy)' : 'Memória do sistema');
+    setText('serverBitrate', `${Math.round((video.bitrate_bps || 0) / 1000)} Kbps`);
+    setText('serverRtt', serverStats.rtt_ms != null ? `${Math.round(serverStats.rtt_ms)} ms` : '-');
+    setText('serverLost', `${video.packets_lost || 0} (${((video.fraction_lost || 0) * 100).toFixed(1)}%)`);
+    const pair = serverStats.candidate_pair;
+    setText('serverCandidatePair', pair ? `${pair.local} ⇄ ${pair.remote}` : '-');
+}
+
+// Draws the remote pointer over the video: the positions channel carries JSON
+// (0..1 of the screen, with a sequence number since it is unordered), the
+// shapes channel binary messages (width, height, hotspot x/y as little-endian
+// u16, then RGBA pixels)
+function setupCursorChannels(positions, shapes) {
+    const cursorCanvas = document.getElementById('remoteCursor');
+    if (!cursorCanvas) return;
+    shapes.binaryType = 'arraybuffer';
+    let hotspot = { x: 0, y: 0 };
+    let lastSeq = 0;
+
+    positions.onmessage = (event) => {
+        const { seq, x, y } = JSON.parse(event.data);
+        if (seq <= lastSeq) return;
+        lastSeq = seq;
+        if (!remoteVideo.videoWidth) return;
+        // Content box of the letterboxed video (object-fit: contain)
+        const scale = Math.min(remoteVideo.clientWidth / remoteVideo.videoWidth,
+                               remoteVideo.clientHeight / remoteVideo.videoHeight);
+        const offsetX = remoteVideo.offsetLeft + (remoteVideo.clientWidth - remoteVideo.videoWidth * scale) / 2;
+        const offsetY = remoteVideo.offsetTop + (remoteVideo.clientHeight - remoteVideo.videoHeight * scale) / 2;
+        const left = offsetX + x * remoteVideo.videoWidth * scale - hotspot.x * scale;
+        const top = offsetY + y * remoteVideo.videoHeight * scale - hotspot.y * scale;
+        cursorCanvas.style.transform = `translate(${left}px, ${top}px) scale(${scale})`;
+        cursorCanvas.style.transformOrigin = 'top left';
+        cursorCanvas.style.display = 'block';
+    };
+    shapes.onmessage = (event) => {
+        const view = new DataView(event.data);
+        const width = view.getUint16(0, true);
+        const height = view.getUint16(2, true);
+        hotspot = { x: view.getUint16(4, true), y: view.getUint16(6, true) };
+        if (!width || !height) return;
+        cursorCanvas.width = width;
+        cursorCanvas.height = height;
+        const pixels = new Uint8ClampedArray(event.data, 8, width * height * 4);
+        cursorCanvas.getContext('2d').putImageData(new ImageData(pixels, width, height), 0, 0);
+    };
+    positions.onclose = shapes.onclose = () => {
+        cursorCanvas.style.display = 'none';
+    };
+    log('Cursor enviado como metadados pelos data channels', 'info');
+}
+
+// Screens with a camera selected are streamed as "pip-<screen>+<camera>"
+function withPictureInPicture(sourceType) {
+    const camera = pipCameraSelect ? pipCameraSelect.value : '';
+    const isScreen = !sourceType.startsWith('camera-') && !sourceType.startsWith('whip-');
+    return camera && isScreen ? `pip-${sourceType}+${camera}` : sourceType;
+}
+
+function updatePipControls() {
+    const pipControls = document.getElementById('pipControls');
+    if (pipControls) pipControls.classList.toggle('active', Boolean(pipCameraSelect.value));
+}
+
+function currentPipLayout() {
+    return {
+        position: document.getElementById('pipPosition')?.value || 'bottom-right',
+        size: (parseInt(document.getElementById('pipSize')?.value, 10) || 25) / 100,
+        opacity: (parseInt(document.getElementById('pipOpacity')?.value, 10) || 100) / 100,
+        margin: 24,
+        border: 4
+    };
+}
+
+// Layout changes are applied live to the running composition
+function sendPipLayout() {
+    if (ws && ws.readyState === WebSocket.OPEN && pc) {
+        ws.send(JSON.stringify({ type: 'set-pip-layout', layout: currentPipLayout() }));
+    }
+}
+
+function requestApplicationStreams() {
+    if (ws && ws.readyState === WebSocket.OPEN) {
+        ws.send(JSON.stringify({ type: 'list-application-streams' }));
+    } else {
+        initializeAudioDevices();
+    }
+}
+
 function getAudioEmoji(deviceType) {
     switch (deviceType) {
         case 'microphone': return '🎤';
@@ -1011,6 +1353,12 @@ function updateConnectionConfig() {
     }
 }
 
+function toggleVoiceProcessing() {
+    currentAudioConfig.voiceProcessing = enableVoiceProcessing.checked;
+    log(`Cancelamento de eco ${currentAudioConfig.voiceProcessing ? 'habilitado' : 'desabilitado'}`, 'info');
+    updateConnectionConfig();
+}
+
 function toggleMicrophone() {
     currentAudioConfig.microphoneEnabled = enableMicrophone.checked;
     
@@ -1135,12 +1483,15 @@ function cleanup() {
     
     remoteVideo.srcObject = null;
     vrVideo.srcObject = null;
+    if (vuMeters) vuMeters.innerHTML = '';
     remoteVideo.style.display = 'none';
     placeholder.style.display = 'block';
     
     connectBtn.disabled = false;
     disconnectBtn.disabled = true;
     vrBtn.disabled = true;
+    updateRecordingState(null);
+    if (recordBtn) recordBtn.disabled = true;
     
     updateStatus('', 'Desconectado');
     
@@ -1200,6 +1551,8 @@ async function initializeAudioDevices() {
                 const signal = JSON.parse(event.data);
                 if (signal.type === 'audio-devices') {
                     populateAudioDevices(signal.devices);
+                } else if (signal.type === 'application-streams') {
+                    populateApplicationStreams(signal.streams);
                     discoveryWs.close();
                 }
             } catch (error) {